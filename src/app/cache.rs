@@ -0,0 +1,181 @@
+use super::cmd_builder::format_size;
+use crate::util::cache::{
+    cache_root, http_cache_file, is_cacheable, list_entries, remove_entry, CacheEntry,
+};
+use crate::util::git;
+use crate::util::time::format_age;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use colored::*;
+use easy_error::{bail, Error};
+use std::fs;
+use std::path::Path;
+use walkdir::WalkDir;
+
+pub fn cache_app<'a, 'b>() -> App<'a, 'b> {
+    return SubCommand::with_name("cache")
+        .about("Inspects and cleans the cache of remote repositories")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::InferSubcommands)
+        .setting(AppSettings::DisableVersion)
+        .setting(AppSettings::ColoredHelp)
+        .setting(AppSettings::UnifiedHelpMessage)
+        .subcommand(
+            SubCommand::with_name("list")
+                .setting(AppSettings::ColoredHelp)
+                .about("Lists the cached repositories"),
+        )
+        .subcommand(
+            SubCommand::with_name("show")
+                .setting(AppSettings::ColoredHelp)
+                .about("Shows what is known about a cached repository")
+                .arg(
+                    Arg::with_name("URL")
+                        .help("URL of the repository")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("clear")
+                .setting(AppSettings::ColoredHelp)
+                .about("Removes the provided repositories from the cache, or all of them")
+                .arg(
+                    Arg::with_name("URL")
+                        .help("URL of the repositories to remove")
+                        .multiple(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("path")
+                .setting(AppSettings::ColoredHelp)
+                .about("Prints the path of the cache, or of the cached copy of a repository")
+                .arg(Arg::with_name("URL").help("URL of the repository").index(1)),
+        );
+}
+
+pub fn handle<'a>(app: &ArgMatches<'a>) -> Result<(), Error> {
+    let (subcommand, args) = app.subcommand();
+    let urls: Vec<&str> = args
+        .and_then(|args| args.values_of("URL"))
+        .map(|urls| urls.collect())
+        .unwrap_or_default();
+    match subcommand {
+        "list" => handle_list(),
+        "show" => handle_show(urls[0]),
+        "clear" => handle_clear(&urls),
+        "path" => handle_path(urls.first().copied()),
+        _ => bail!("Cache subcommand unrecognized: {}", subcommand),
+    }
+}
+
+fn handle_list() -> Result<(), Error> {
+    let entries = list_entries()?;
+    if entries.is_empty() {
+        println!("The cache is empty");
+        return Ok(());
+    }
+    println!("{: <6}{: <12}{: <14}URL", "KIND", "SIZE", "FETCHED");
+    for entry in &entries {
+        println!(
+            "{: <6}{: <12}{: <14}{}",
+            kind(entry),
+            format_size(size(&entry.path)),
+            format_age(entry.metadata.fetched_at),
+            entry.metadata.url
+        );
+    }
+    return Ok(());
+}
+
+fn handle_show(url: &str) -> Result<(), Error> {
+    let entry = find_entry(url)?;
+    let metadata = &entry.metadata;
+    println!("{: <15}{}", "URL", metadata.url.blue());
+    println!("{: <15}{}", "Kind", kind(&entry));
+    println!("{: <15}{}", "Path", entry.path);
+    println!("{: <15}{}", "Size", format_size(size(&entry.path)));
+    println!("{: <15}{}", "Fetched", format_age(metadata.fetched_at));
+    println!("{: <15}{}", "Content hash", metadata.content_hash);
+    if let Some(etag) = &metadata.etag {
+        println!("{: <15}{}", "ETag", etag);
+    }
+    if let Some(last_modified) = &metadata.last_modified {
+        println!("{: <15}{}", "Last-Modified", last_modified);
+    }
+    if let Some(content_type) = &metadata.content_type {
+        println!("{: <15}{}", "Content-Type", content_type);
+    }
+    return Ok(());
+}
+
+fn handle_clear(urls: &[&str]) -> Result<(), Error> {
+    let entries = if urls.is_empty() {
+        list_entries()?
+    } else {
+        urls.iter()
+            .map(|url| find_entry(url))
+            .collect::<Result<Vec<CacheEntry>, Error>>()?
+    };
+    let mut reclaimed = 0;
+    for entry in &entries {
+        reclaimed += size(&entry.path);
+        remove_entry(entry)?;
+        eprintln!("Removed {}", entry.metadata.url);
+    }
+    eprintln!(
+        "Removed {} cached repositories, {}",
+        entries.len(),
+        format_size(reclaimed).green()
+    );
+    return Ok(());
+}
+
+fn handle_path(url: Option<&str>) -> Result<(), Error> {
+    let path = match url {
+        None => cache_root(),
+        Some(url) if is_cacheable(url) => http_cache_file(url),
+        Some(url) => find_entry(url)?.path,
+    };
+    println!("{}", path);
+    return Ok(());
+}
+
+/// Finds a cached repository by URL. Git repositories are cached once per
+/// reference, so the path within the repository, if any, is ignored.
+fn find_entry(url: &str) -> Result<CacheEntry, Error> {
+    let url = if git::is_git_resource(url) {
+        git::parse_git_resource(url)?.checkout_url()
+    } else {
+        String::from(url)
+    };
+    match list_entries()?
+        .into_iter()
+        .find(|entry| entry.metadata.url == url)
+    {
+        Some(entry) => return Ok(entry),
+        None => bail!("{} is not cached", url.red()),
+    }
+}
+
+fn kind(entry: &CacheEntry) -> &'static str {
+    return if entry.metadata.url.starts_with("git+") {
+        "git"
+    } else {
+        "http"
+    };
+}
+
+/// Size of a file, or of all the files of a directory
+fn size(path: &str) -> u64 {
+    if !Path::new(path).is_dir() {
+        return fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    }
+    return WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum();
+}
@@ -1,12 +1,22 @@
 use crate::cmd;
+use crate::config;
+use crate::config::{MacondoConfig, Repository};
 use crate::docker;
+use crate::docker::builders::{find_builder, find_package_manager};
+use crate::docker::BuildOptions;
+use crate::util::cache::{get_from_cache, is_cacheable};
+use crate::util::paths::expand_path;
 use crate::util::paths;
+use crate::util::signature::signature_file;
+use crate::util::tasks::run_concurrently;
 use crate::exec;
 use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use cmd::{Cmd, Repo};
 use colored::*;
 use easy_error::{bail, Error, ResultExt};
+use indicatif::ProgressBar;
 use std::path::Path;
+use std::time::{Duration, Instant};
 
 pub fn update_app<'a, 'b>() -> App<'a, 'b> {
     SubCommand::with_name("build")
@@ -29,6 +39,60 @@ pub fn update_app<'a, 'b>() -> App<'a, 'b> {
                 .requires_all(&["publish"])
                 .help("Prints a YAML repository with all the built commands"),
         )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .requires_all(&["publish"])
+                .help("Writes the YAML repository to the provided file instead of printing it"),
+        )
+        .arg(
+            Arg::with_name("merge_into")
+                .long("merge-into")
+                .takes_value(true)
+                .requires_all(&["publish"])
+                .help("Merges the built commands into an existing YAML repository (file or URL). Unless --output is provided, a local repository file is updated in place"),
+        )
+        .arg(
+            Arg::with_name("sign_key")
+                .long("sign-key")
+                .takes_value(true)
+                .requires_all(&["publish"])
+                .help("Signs the written YAML repository with the provided minisign secret key. Requires minisign"),
+        )
+        .arg(
+            Arg::with_name("keep_versions")
+                .long("keep-versions")
+                .requires_all(&["merge_into"])
+                .help("When merging, keep the other versions of the built commands instead of replacing them"),
+        )
+        .arg(
+            Arg::with_name("cache_from")
+                .long("cache-from")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("External cache sources passed to docker build (e.g. type=registry,ref=user/app:cache)"),
+        )
+        .arg(
+            Arg::with_name("cache_to")
+                .long("cache-to")
+                .takes_value(true)
+                .help("Cache export destination passed to docker build. Requires docker buildx"),
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .long("jobs")
+                .short("j")
+                .takes_value(true)
+                .default_value("1")
+                .validator(|jobs| match jobs.parse::<usize>() {
+                    Ok(jobs) if jobs > 0 => Ok(()),
+                    _ => Err(String::from("must be a positive number")),
+                })
+                .help("Number of commands to build concurrently"),
+        )
         .arg(
             Arg::with_name("COMMAND_OR_DIR")
                 .help("The command file to build (or a directory, which is traversed in search of files with the .mcd extension)")
@@ -42,55 +106,264 @@ pub fn handle_build_command<'a>(
     verbose: bool,
 ) -> Result<(), Error> {
     let path = String::from(build_options.value_of("COMMAND_OR_DIR").unwrap());
-    let cmds = cmd::load_commands(vec![path])?;
-    return build_command(
-        cmds,
+    let merge_into = build_options.value_of("merge_into");
+    let output = build_options.value_of("output");
+    let sign_key = build_options.value_of("sign_key");
+    // a local repository that gets merged into is updated in place
+    let output = output.or(merge_into.filter(|repo| !is_cacheable(repo)));
+    if sign_key.is_some() {
+        if output.is_none() {
+            bail!("Only repositories written to a file can be signed. Provide --output");
+        }
+        if !exec::does_command_exist("minisign") {
+            bail!("{} command not found. It is needed to sign repositories", "minisign".blue());
+        }
+    }
+    // loaded once, as the builds run concurrently and loading it may ask
+    // whether to trust the project config
+    let config = config::load_config()?;
+    let cmds = cmd::load_commands(vec![Repository::from(path)])?;
+    let options = BuildOptions {
         verbose,
+        base_image_pins: config.base_image_pins.clone(),
+        cache_from: build_options
+            .values_of("cache_from")
+            .map(|values| values.map(String::from).collect())
+            .unwrap_or_default(),
+        cache_to: build_options.value_of("cache_to").map(String::from),
+        ..BuildOptions::default()
+    };
+    let (built_cmds, failed) = build_command(
+        cmds,
+        &options,
         build_options.value_of("publish"),
-        build_options.is_present("generate"),
+        build_options.value_of("jobs").unwrap().parse().unwrap(),
     );
+
+    if build_options.is_present("generate") || merge_into.is_some() || output.is_some() {
+        let repo = match merge_into {
+            Some(existing_repo) => {
+                let mut repo = load_existing_repo(existing_repo, &config)?;
+                repo.merge(built_cmds, build_options.is_present("keep_versions"));
+                repo
+            }
+            None => Repo {
+                commands: built_cmds,
+                ..Repo::default()
+            },
+        };
+        let yaml = serde_yaml::to_string(&repo).context("Failed to serialize repo as YAML")?;
+
+        match output {
+            Some(output) => {
+                let output = expand_path(output);
+                paths::write_atomically(&output, yaml.as_bytes())?;
+                eprintln!("Wrote repository to {}", output.green());
+                if let Some(sign_key) = sign_key {
+                    sign_repo(&output, &expand_path(sign_key))?;
+                }
+            }
+            None => println!("{}", yaml),
+        }
+    }
+
+    if failed > 0 {
+        bail!("{} commands failed to build", failed);
+    }
+    return Ok(());
 }
 
+/// Signs a repository file with minisign, which writes the signature next
+/// to it. minisign prompts for the password of the key, if it has one.
+fn sign_repo(repo: &str, secret_key: &str) -> Result<(), Error> {
+    let args = vec![
+        String::from("-S"),
+        String::from("-s"),
+        String::from(secret_key),
+        String::from("-m"),
+        String::from(repo),
+    ];
+    if exec::exec("minisign", args, None)? != 0 {
+        bail!("Failed to sign {}", repo);
+    }
+    eprintln!("Signed repository: {}", signature_file(repo).green());
+    return Ok(());
+}
+
+/// Loads the repository the built commands are merged into
+fn load_existing_repo(repo: &str, config: &MacondoConfig) -> Result<Repo, Error> {
+    if is_cacheable(repo) {
+        // a configured repository is fetched with its credentials, and must
+        // still be signed with its key
+        let repository = config
+            .repositories
+            .iter()
+            .find(|configured| configured.url == repo)
+            .cloned()
+            .unwrap_or_else(|| Repository::from(repo));
+        // always merge into the latest published version
+        return cmd::load_repo(&get_from_cache(&repository, true)?);
+    }
+    let path = expand_path(repo);
+    if !Path::new(&path).exists() {
+        // nothing to merge into yet; the repository will be created
+        return Ok(Repo::default());
+    }
+    return cmd::load_repo(&path);
+}
+
+/// Outcome of building (and possibly publishing) a single command
+pub struct BuildReport {
+    pub name: String,
+    pub result: Result<Cmd, Error>,
+    pub duration: Duration,
+    pub size: Option<u64>,
+}
+
+/// Builds all the provided commands, up to `jobs` of them concurrently.
+/// A failure does not stop the other builds; they are all reported in a
+/// summary at the end. Returns the built commands and how many failed.
 fn build_command(
     cmds: Vec<Cmd>,
-    verbose: bool,
+    options: &BuildOptions,
     publish: Option<&str>,
-    print_yaml: bool,
-) -> Result<(), Error> {
-    let mut built_cmds: Vec<Cmd> = Vec::new();
-    for cmd in cmds {
-        if let Some(_) = &cmd.command_path {
-            let built_cmd = build_on_the_fly_if_necessary(cmd, verbose)?;
-            if let Some(repo) = publish {
-                let repo_image_name =
-                    format!("{}:{}-{}", repo, &built_cmd.registry, &built_cmd.version);
-                publish_image(&built_cmd, &repo_image_name)?;
-                eprintln!(
-                    "Built {} and published to {}",
-                    &built_cmd.registry.blue(),
-                    repo_image_name.green()
-                );
-                built_cmds.push(Cmd {
-                    registry: String::from(&repo_image_name),
-                    ..built_cmd
-                });
-            } else {
-                eprintln!("Built {}", &built_cmd.registry.green());
-                built_cmds.push(built_cmd);
+    jobs: usize,
+) -> (Vec<Cmd>, usize) {
+    let items: Vec<(String, Cmd)> = cmds
+        .into_iter()
+        .filter(|cmd| cmd.command_path.is_some())
+        .map(|cmd| (cmd.name.clone(), cmd))
+        .collect();
+    let reports = run_concurrently(
+        items,
+        jobs,
+        |cmd, progress| {
+            let options = BuildOptions {
+                progress: Some(progress.clone()),
+                ..options.clone()
+            };
+            build_and_publish(cmd, &options, publish)
+        },
+        |report: &BuildReport| match &report.result {
+            Ok(built_cmd) => format!(
+                "{} {} in {}",
+                "✔".green(),
+                &built_cmd.registry,
+                format_duration(report.duration)
+            ),
+            Err(_) => format!("{} failed", "✘".red()),
+        },
+    );
+    print_build_summary(&reports, "Failed to build");
+
+    let failed = reports.iter().filter(|report| report.result.is_err()).count();
+    let built_cmds = reports
+        .into_iter()
+        .filter_map(|report| report.result.ok())
+        .collect();
+    return (built_cmds, failed);
+}
+
+fn build_and_publish(cmd: Cmd, options: &BuildOptions, publish: Option<&str>) -> BuildReport {
+    let name = cmd.name.clone();
+    let start = Instant::now();
+    let result = build_on_the_fly_if_necessary(cmd, options).and_then(|built_cmd| {
+        if let Some(repo) = publish {
+            let repo_image_name =
+                format!("{}:{}-{}", repo, &built_cmd.registry, &built_cmd.version);
+            publish_image(&built_cmd, &repo_image_name, options)?;
+            // tags can be overwritten, so the published command references
+            // the immutable digest of the image that was just pushed
+            return Ok(Cmd {
+                registry: docker::pushed_image_digest(&repo_image_name)?,
+                ..built_cmd
+            });
+        }
+        return Ok(built_cmd);
+    });
+    let size = match &result {
+        Ok(built_cmd) => docker::image_size(&built_cmd.registry).ok(),
+        Err(_) => None,
+    };
+    return BuildReport {
+        name,
+        result,
+        duration: start.elapsed(),
+        size,
+    };
+}
+
+/// Prints a table with the outcome of every build. `failure` describes
+/// what went wrong for the failed ones, e.g. "Failed to build"
+pub fn print_build_summary(reports: &[BuildReport], failure: &str) {
+    eprintln!(
+        "\n{: <30}{: <10}{: <12}{: <12}IMAGE",
+        "COMMAND", "STATUS", "DURATION", "SIZE"
+    );
+    for report in reports {
+        let size = report
+            .size
+            .map(format_size)
+            .unwrap_or_else(|| String::from("-"));
+        match &report.result {
+            Ok(built_cmd) => eprintln!(
+                "{: <30}{: <10}{: <12}{: <12}{}",
+                report.name,
+                "ok".green(),
+                format_duration(report.duration),
+                size,
+                built_cmd.registry
+            ),
+            Err(_) => eprintln!(
+                "{: <30}{: <10}{: <12}{: <12}-",
+                report.name,
+                "FAILED".red(),
+                format_duration(report.duration),
+                size
+            ),
+        }
+    }
+
+    for report in reports {
+        if let Err(e) = &report.result {
+            eprintln!("\n{} {}: {}", failure.red(), report.name.blue(), e);
+            let mut source = std::error::Error::source(e);
+            while let Some(cause) = source {
+                eprintln!("  caused by: {}", cause);
+                source = cause.source();
             }
         }
     }
-    if print_yaml {
-        let repo = Repo {
-            commands: built_cmds,
-        };
-        let yaml = serde_yaml::to_string(&repo).context("Failed to serialize repo as YAML")?;
-        println!("{}", yaml);
+    eprintln!();
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    return format!("{:.1}s", duration.as_secs_f64());
+}
+
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < units.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
     }
-    return Ok(());
+    return format!("{:.1} {}", size, units[unit]);
 }
 
-pub fn build_on_the_fly_if_necessary(cmd: Cmd, verbose: bool) -> Result<Cmd, Error> {
+pub fn build_on_the_fly_if_necessary(cmd: Cmd, options: &BuildOptions) -> Result<Cmd, Error> {
+    // build args and secrets are declared by the command itself, and the
+    // command is recorded in the image labels so the image describes itself
+    let mut labels = options.labels.clone();
+    labels.extend(docker::command_labels(&cmd)?);
+    labels.extend(docker::managed_labels(&cmd, "build")?);
+    let options = &BuildOptions {
+        build_args: cmd.build_args.clone(),
+        secrets: cmd.build_secrets.clone(),
+        labels,
+        ..options.clone()
+    };
     let registry = if let Some(command_path) = &cmd.command_path {
         let command_path = paths::canonalize_path(command_path);
         let command_path = Path::new(&command_path);
@@ -100,40 +373,68 @@ pub fn build_on_the_fly_if_necessary(cmd: Cmd, verbose: bool) -> Result<Cmd, Err
         if cmd.registry == "Dockerfile" {
             // This means the Dockerfile for this file is next to the command.
             // In this case we just run `docker build .` on the folder of the command
-            docker::build_image(&command_name, context_path, None, verbose)?
-        } else if cmd.registry.starts_with("AlpinePackages") {
-            // This means we should build an Alpine image on-the-fly that
-            // has the provided packages
-            let packages = cmd.registry.replace("AlpinePackages", "");
-            let extra_commands = format!("RUN apk --no-cache add bash {}", packages.trim());
-            docker::build_command_image_from_base(
-                &command_name,
-                context_path,
-                "alpine",
-                &extra_commands,
-                verbose,
-            )?
-        } else if cmd.registry.starts_with("UbuntuPackages") {
-            // This means we should build an Ubuntu image on-the-fly that
-            // has the provided packages
-            let packages = cmd.registry.replace("UbuntuPackages", "");
-            let extra_commands = format!("RUN apt-get update && apt-get install -y bash {} && rm -rf /var/lib/apt/lists/* && ln -sv /usr/games/* /usr/bin/ || exit 0", packages.trim());
+            if !cmd.packages.is_empty() {
+                bail!(
+                    "Command {} uses @packages along with a Dockerfile. Install the packages in the Dockerfile instead",
+                    cmd.name
+                );
+            }
+            docker::build_dockerfile_image(&command_name, context_path, &cmd.includes, options)?
+        } else if let Some(reference) = find_builder(&cmd.registry) {
+            // This means we should build an image on-the-fly, based on the
+            // builder's base image, that has the provided packages
+            let builder = reference.builder;
+            let pin = match reference.pin {
+                Some(pin) => Some(pin),
+                None => options.base_image_pins.get(builder.name).cloned(),
+            };
+            // Build from the digest so that what gets recorded in the
+            // image labels is exactly what the image was built from
+            let base_image =
+                docker::resolve_image_digest(&builder.base_image(pin.as_deref()), options)?;
+            let mut labels = options.labels.clone();
+            labels.push((String::from(docker::BASE_IMAGE_LABEL), base_image.clone()));
+            let options = &BuildOptions {
+                labels,
+                ..options.clone()
+            };
+            // packages from @packages are installed by the builder itself
+            let mut packages = reference.packages;
+            packages.extend(cmd.packages.iter().cloned());
             docker::build_command_image_from_base(
                 &command_name,
                 context_path,
-                "ubuntu",
-                &extra_commands,
-                verbose,
+                &builder.render(&base_image, &packages)?,
+                "",
+                &cmd.uses,
+                &cmd.includes,
+                options,
             )?
         } else {
             // This means the command references an existent base Docker image
             // In this case we build a new image based on it, but overwriting the entrypoint
+            // (and installing the packages from @packages, if any)
+            let extra_commands = if cmd.packages.is_empty() {
+                String::new()
+            } else {
+                let base_image = docker::inspect_base_image(&cmd.registry, options)?;
+                let package_manager = match &cmd.package_manager {
+                    Some(name) => match find_package_manager(name) {
+                        Some(package_manager) => package_manager,
+                        None => bail!("Unknown package manager {} for {}", name, cmd.name),
+                    },
+                    None => docker::detect_package_manager(&cmd.registry, &base_image, options)?,
+                };
+                package_manager.render(&cmd.packages, &base_image.user)?
+            };
             docker::build_command_image_from_base(
                 &command_name,
                 context_path,
-                &cmd.registry,
-                "",
-                verbose,
+                &format!("FROM {}", &cmd.registry),
+                &extra_commands,
+                &cmd.uses,
+                &cmd.includes,
+                options,
             )?
         }
     } else {
@@ -147,38 +448,43 @@ pub fn build_on_the_fly_if_necessary(cmd: Cmd, verbose: bool) -> Result<Cmd, Err
     });
 }
 
-fn publish_image(cmd: &Cmd, repo_image_name: &str) -> Result<(), Error> {
-    exec::exec_and_capture_output(
+fn publish_image(cmd: &Cmd, repo_image_name: &str, options: &BuildOptions) -> Result<(), Error> {
+    let progress = match &options.progress {
+        Some(progress) => progress.clone(),
+        None => ProgressBar::hidden(),
+    };
+    exec::exec_and_report_progress(
+        &progress,
         "docker",
         vec!["tag", &cmd.registry, &repo_image_name],
         Vec::new(),
         false,
         None,
-        "",
+        &format!("Tagging {}...", repo_image_name),
     )
     .context(format!(
         "Failed to build Docker image for {}",
         repo_image_name
     ))?;
 
-    let result = exec::exec_and_capture_output(
+    let result = exec::exec_and_report_progress(
+        &progress,
         "docker",
         vec!["push", &repo_image_name],
         Vec::new(),
         false,
         None,
-        &format!(
-            "Publishing {} as {}",
-            &cmd.name.blue(),
-            &repo_image_name.green()
-        ),
+        &format!("Publishing as {}...", &repo_image_name),
     )
     .context(format!("Failed to publish {}", repo_image_name))?;
 
     if result.status.code().unwrap_or(-1) != 0 {
-        println!("{}", String::from_utf8_lossy(&result.stdout));
-        println!("{}", String::from_utf8_lossy(&result.stderr));
-        bail!("Failed to publish image {}", repo_image_name);
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        bail!(
+            "Failed to publish image {}: {}",
+            repo_image_name,
+            stderr.trim()
+        );
     }
 
     return Ok(());
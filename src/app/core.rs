@@ -1,10 +1,13 @@
-use super::{cmd_builder, repo_management};
+use super::{cache, cmd_builder, export, gc, import, lock, new, pull, repo_management, test};
+use crate::config::MacondoConfig;
+use crate::docker::BuildOptions;
+use crate::util;
 use crate::{cmd, config, exec};
 use clap::{App, AppSettings, Arg, ArgMatches};
-use cmd::{executer, parser, Cmd};
+use cmd::{executer, parser, Cmd, LoadedRepository};
 use colored::*;
-use easy_error::{bail, Terminator};
-use std::{path::Path, process::exit};
+use easy_error::{bail, Error, Terminator};
+use std::{env, path::Path, process::exit};
 
 pub fn main_app<'a, 'b>() -> App<'a, 'b> {
     return App::new("macondo")
@@ -26,12 +29,42 @@ pub fn main_app<'a, 'b>() -> App<'a, 'b> {
                 .help("If set will disable the dynamic mounting of volumes"),
         )
         .arg(Arg::with_name("verbose").long("verbose").short("v"))
+        .arg(
+            Arg::with_name("offline")
+                .long("offline")
+                .help("Never fetches repositories, using the cached copies as they are"),
+        )
+        .arg(
+            Arg::with_name("locked")
+                .long("locked")
+                .help("Only loads and runs commands that match the lockfile (see macondo lock)"),
+        )
         .subcommand(cmd_builder::update_app())
-        .subcommand(repo_management::repo_management_app());
+        .subcommand(repo_management::repo_management_app())
+        .subcommand(import::import_app())
+        .subcommand(export::export_app())
+        .subcommand(new::new_app())
+        .subcommand(test::test_app())
+        .subcommand(gc::gc_app())
+        .subcommand(gc::du_app())
+        .subcommand(pull::pull_app())
+        .subcommand(lock::lock_app())
+        .subcommand(cache::cache_app());
 }
 
-/// Handles any cli subcommand or defaults to return a fully built `Cmd`
-pub fn handle_meta_commands_or_return_cmd(app: &ArgMatches) -> Result<Option<Cmd>, Terminator> {
+/// Handles any cli subcommand or defaults to return a fully built `Cmd`,
+/// along with the config it was found with
+pub fn handle_meta_commands_or_return_cmd(
+    app: &ArgMatches,
+) -> Result<Option<(Cmd, MacondoConfig)>, Terminator> {
+    // picked up wherever they matter, whichever subcommand runs
+    if app.is_present("offline") {
+        env::set_var(util::cache::OFFLINE_ENV, "1");
+    }
+    if app.is_present("locked") {
+        env::set_var(config::LOCKED_ENV, "1");
+    }
+
     if let Some(build_options) = app.subcommand_matches("build") {
         cmd_builder::handle_build_command(build_options, app.is_present("verbose"))?;
         return Ok(None);
@@ -42,26 +75,77 @@ pub fn handle_meta_commands_or_return_cmd(app: &ArgMatches) -> Result<Option<Cmd
         return Ok(None);
     }
 
+    if let Some(cache_options) = app.subcommand_matches("cache") {
+        cache::handle(cache_options)?;
+        return Ok(None);
+    }
+
+    if let Some(import_options) = app.subcommand_matches("import") {
+        import::handle_import(import_options)?;
+        return Ok(None);
+    }
+
+    if let Some(export_options) = app.subcommand_matches("export") {
+        export::handle_export(export_options)?;
+        return Ok(None);
+    }
+
+    if let Some(new_options) = app.subcommand_matches("new") {
+        new::handle_new(new_options)?;
+        return Ok(None);
+    }
+
+    if let Some(test_options) = app.subcommand_matches("test") {
+        test::handle_test(test_options, app.is_present("verbose"))?;
+        return Ok(None);
+    }
+
+    if let Some(gc_options) = app.subcommand_matches("gc") {
+        gc::handle_gc(gc_options)?;
+        return Ok(None);
+    }
+
+    if let Some(du_options) = app.subcommand_matches("du") {
+        gc::handle_du(du_options)?;
+        return Ok(None);
+    }
+
+    if let Some(pull_options) = app.subcommand_matches("pull") {
+        pull::handle_pull(pull_options, app.is_present("verbose"))?;
+        return Ok(None);
+    }
+
+    if let Some(lock_options) = app.subcommand_matches("lock") {
+        lock::handle_lock(lock_options, app.is_present("verbose"))?;
+        return Ok(None);
+    }
+
     let config = config::load_config()?;
-    let cmds = cmd::load_commands(config.repositories)?;
+    let loaded = cmd::lock::load_configured_repositories(&config)?;
 
     if app.subcommand().0 == "" {
         eprintln!("{}", "You forgot to provide a command...\n".red());
-        cmd::list_commands(&cmds, false);
+        cmd::list_commands(&cmd::resolve_commands(&loaded), false);
         return Ok(None);
     }
 
     ensure_necessary_programs_exist();
 
-    return Ok(Some(resolve_command(app, cmds)?));
+    let cmd = resolve_command(app, &loaded)?;
+    let cmd = cmd::lock::enforce_lock(cmd, &config)?;
+    return Ok(Some((cmd, config)));
 }
 
-fn resolve_command(app: &ArgMatches, cmds: Vec<Cmd>) -> Result<Cmd, Terminator> {
+fn resolve_command(app: &ArgMatches, loaded: &[LoadedRepository]) -> Result<Cmd, Terminator> {
     let (command_name, _) = app.subcommand();
-    return find_command_in(command_name, cmds);
+    return Ok(find_command(command_name, loaded)?);
 }
 
-pub fn execute_command(cmd: Cmd, app: &ArgMatches) -> Result<(), Terminator> {
+pub fn execute_command(
+    cmd: Cmd,
+    config: &MacondoConfig,
+    app: &ArgMatches,
+) -> Result<(), Terminator> {
     let (_, args) = app.subcommand();
 
     let dry_run: bool = app.is_present("dry_run");
@@ -74,10 +158,25 @@ pub fn execute_command(cmd: Cmd, app: &ArgMatches) -> Result<(), Terminator> {
         .unwrap_or(clap::Values::default())
         .collect();
 
-    return executer::execute_command(cmd, ext_args, dry_run, disable_dynamic_mounts, verbose);
+    let options = BuildOptions {
+        verbose,
+        base_image_pins: config.base_image_pins.clone(),
+        ..BuildOptions::default()
+    };
+    return executer::execute_command(cmd, ext_args, dry_run, disable_dynamic_mounts, &options);
+}
+
+/// Finds a command of the loaded repositories by name or by qualified name
+/// (ALIAS/COMMAND), or parses it if `command_name` is a path to a command file
+pub fn find_command(command_name: &str, loaded: &[LoadedRepository]) -> Result<Cmd, Error> {
+    if let Some(cmd) = cmd::find_qualified_command(command_name, loaded) {
+        return Ok(cmd);
+    }
+    return find_command_in(command_name, cmd::resolve_commands(loaded));
 }
 
-fn find_command_in(command_name: &str, cmds: Vec<Cmd>) -> Result<Cmd, Terminator> {
+/// Finds a command by name, or parses it if `command_name` is a path to a command file
+pub fn find_command_in(command_name: &str, cmds: Vec<Cmd>) -> Result<Cmd, Error> {
     for c in cmds {
         if c.name == command_name {
             return Ok(c);
@@ -0,0 +1,127 @@
+use super::cmd_builder::build_on_the_fly_if_necessary;
+use super::core::find_command;
+use crate::cmd;
+use crate::config;
+use crate::docker::BuildOptions;
+use crate::export;
+use crate::util::paths::expand_path;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use colored::*;
+use easy_error::{bail, Error, ResultExt};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+pub fn export_app<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("export")
+        .about("Exports a command so that it can be run without macondo")
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .setting(AppSettings::DisableVersion)
+        .setting(AppSettings::ColoredHelp)
+        .setting(AppSettings::UnifiedHelpMessage)
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .short("f")
+                .takes_value(true)
+                .possible_values(&["sh", "compose", "k8s-job"])
+                .default_value("sh")
+                .help("Export format. sh: a standalone POSIX shell script. compose: a docker compose file. k8s-job: a Kubernetes Job"),
+        )
+        .arg(
+            Arg::with_name("schedule")
+                .long("schedule")
+                .takes_value(true)
+                .value_name("CRON")
+                .help("With k8s-job, exports a CronJob that runs on the provided schedule"),
+        )
+        .arg(
+            Arg::with_name("host-path-volumes")
+                .long("host-path-volumes")
+                .help("With k8s-job, mounts volumes as hostPath volumes instead of PersistentVolumeClaims"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .help("Writes the export to the provided file instead of printing it"),
+        )
+        .arg(
+            Arg::with_name("COMMAND")
+                .help("Name of the command to export (or path to a command file)")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("ARGS")
+                .help("Arguments the command is run with (compose and k8s-job only)")
+                .multiple(true)
+                .last(true),
+        )
+}
+
+pub fn handle_export<'a>(export_options: &ArgMatches<'a>) -> Result<(), Error> {
+    let command_name = export_options.value_of("COMMAND").unwrap();
+    let config = config::load_config()?;
+    let loaded = cmd::lock::load_configured_repositories(&config)?;
+    let mut cmd = cmd::lock::enforce_lock(find_command(command_name, &loaded)?, &config)?;
+
+    if cmd.command_path.is_some() {
+        // the export references the image, so it must exist
+        let options = BuildOptions {
+            base_image_pins: config.base_image_pins.clone(),
+            ..BuildOptions::default()
+        };
+        cmd = build_on_the_fly_if_necessary(cmd, &options)?;
+        eprintln!(
+            "{}: {} is not published to any registry. The export will only work where its image ({}) is available",
+            "Warning".yellow(),
+            cmd.name.blue(),
+            cmd.registry
+        );
+    }
+
+    let format = export_options.value_of("format").unwrap();
+    let args: Vec<String> = match export_options.values_of("ARGS") {
+        Some(args) => args.map(String::from).collect(),
+        None => Vec::new(),
+    };
+    if format == "sh" && !args.is_empty() {
+        bail!("The sh format takes the arguments of the command when the script runs");
+    }
+    if format != "k8s-job"
+        && (export_options.is_present("schedule") || export_options.is_present("host-path-volumes"))
+    {
+        bail!("--schedule and --host-path-volumes are only supported by the k8s-job format");
+    }
+    let exported = match format {
+        "sh" => export::sh::export(&cmd)?,
+        "compose" => export::compose::export(&cmd, &args)?,
+        "k8s-job" => export::k8s::export(
+            &cmd,
+            &args,
+            &export::k8s::K8sOptions {
+                schedule: export_options.value_of("schedule"),
+                host_path_volumes: export_options.is_present("host-path-volumes"),
+            },
+        )?,
+        _ => unreachable!(),
+    };
+    for warning in &exported.warnings {
+        eprintln!("{}: {}", "Warning".yellow(), warning);
+    }
+
+    match export_options.value_of("output") {
+        Some(output) => {
+            let output = expand_path(output);
+            fs::write(&output, exported.contents).context(format!("Failed to write {}", output))?;
+            if format == "sh" {
+                fs::set_permissions(&output, fs::Permissions::from_mode(0o755))
+                    .context(format!("Failed to make {} executable", output))?;
+            }
+            eprintln!("Exported {} to {}", cmd.name.blue(), output.green());
+        }
+        None => print!("{}", exported.contents),
+    }
+    return Ok(());
+}
@@ -0,0 +1,346 @@
+use super::cmd_builder::format_size;
+use crate::cmd;
+use crate::config;
+use crate::docker;
+use crate::docker::images::{is_parent, list_managed_images, remove_image, ManagedImage};
+use crate::util::time::{now, parse_age};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use colored::*;
+use easy_error::{bail, Error};
+use std::collections::{HashMap, HashSet};
+
+pub fn gc_app<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("gc")
+        .about("Removes the images created by macondo for commands that changed or no longer exist")
+        .setting(AppSettings::DisableVersion)
+        .setting(AppSettings::ColoredHelp)
+        .setting(AppSettings::UnifiedHelpMessage)
+        .arg(
+            Arg::with_name("dry_run")
+                .long("dry-run")
+                .short("n")
+                .help("Only prints the images that would be removed"),
+        )
+        .arg(
+            Arg::with_name("keep_last")
+                .long("keep-last")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("0")
+                .validator(|n| match n.parse::<usize>() {
+                    Ok(_) => Ok(()),
+                    _ => Err(String::from("must be a number")),
+                })
+                .help("Keeps the N most recent stale images of each command"),
+        )
+        .arg(
+            Arg::with_name("older_than")
+                .long("older-than")
+                .takes_value(true)
+                .value_name("AGE")
+                .validator(|age| parse_age(&age).map(|_| ()))
+                .help("Only removes images older than AGE, e.g. 12h or 30d"),
+        )
+}
+
+pub fn du_app<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("du")
+        .about("Shows the disk space used by the images created by macondo, per command")
+        .setting(AppSettings::DisableVersion)
+        .setting(AppSettings::ColoredHelp)
+        .setting(AppSettings::UnifiedHelpMessage)
+}
+
+/// Why an image is (or is not) needed anymore
+#[derive(PartialEq)]
+enum ImageStatus {
+    /// Referenced by a command of the configured repositories
+    InUse,
+    /// Created for the current version of a command
+    Current,
+    /// Created for a previous version of a command
+    Outdated,
+    /// Created for a command that is not in the configured repositories
+    Orphaned,
+}
+
+impl ImageStatus {
+    fn is_stale(&self) -> bool {
+        return *self == ImageStatus::Outdated || *self == ImageStatus::Orphaned;
+    }
+
+    fn describe(&self) -> &'static str {
+        return match self {
+            ImageStatus::InUse => "in use",
+            ImageStatus::Current => "current",
+            ImageStatus::Outdated => "outdated",
+            ImageStatus::Orphaned => "orphaned",
+        };
+    }
+}
+
+pub fn handle_gc<'a>(gc_options: &ArgMatches<'a>) -> Result<(), Error> {
+    let dry_run = gc_options.is_present("dry_run");
+    let keep_last: usize = gc_options.value_of("keep_last").unwrap().parse().unwrap();
+    let min_age = match gc_options.value_of("older_than") {
+        Some(age) => parse_age(age).unwrap(),
+        None => 0,
+    };
+    let now = now();
+
+    let mut images = classify_images()?;
+    // newest first, so that the ones that are kept are the most recent
+    images.sort_by(|(a, _), (b, _)| b.created.cmp(&a.created));
+    let mut stale_seen: HashMap<(String, String), usize> = HashMap::new();
+    let mut remove: Vec<bool> = Vec::new();
+    for (image, status) in &images {
+        if !status.is_stale() {
+            remove.push(false);
+            continue;
+        }
+        let seen = stale_seen
+            .entry((image.command.clone(), image.kind.clone()))
+            .or_insert(0);
+        *seen += 1;
+        remove.push(*seen > keep_last && now.saturating_sub(image.created) >= min_age);
+    }
+    let managed: Vec<&ManagedImage> = images.iter().map(|(image, _)| image).collect();
+    keep_parents(&managed, &mut remove);
+    let mut to_remove: Vec<(ManagedImage, ImageStatus)> = images
+        .into_iter()
+        .zip(remove)
+        .filter(|(_, remove)| *remove)
+        .map(|(entry, _)| entry)
+        .collect();
+    // aligned images are children of command images, so they go first
+    to_remove.sort_by_key(|(image, _)| image.kind != "aligned");
+
+    if to_remove.is_empty() {
+        eprintln!("Nothing to remove");
+        return Ok(());
+    }
+    let mut reclaimed = 0;
+    let mut failed = 0;
+    for (image, status) in &to_remove {
+        let description = format!(
+            "{} {} ({}, {})",
+            display_command(image).blue(),
+            display_reference(image),
+            status.describe(),
+            format_size(image.size)
+        );
+        if dry_run {
+            eprintln!("Would remove {}", description);
+            reclaimed += image.size;
+            continue;
+        }
+        match remove_image(image) {
+            Ok(()) => {
+                eprintln!("Removed {}", description);
+                reclaimed += image.size;
+            }
+            Err(e) => {
+                eprintln!("{}: {}", "Warning".yellow(), e);
+                failed += 1;
+            }
+        }
+    }
+    // layers can be shared between images, so this is an upper bound
+    eprintln!(
+        "{} up to {}",
+        if dry_run {
+            "Would reclaim"
+        } else {
+            "Reclaimed"
+        },
+        format_size(reclaimed).green()
+    );
+    if failed > 0 {
+        bail!("{} images could not be removed", failed);
+    }
+    return Ok(());
+}
+
+pub fn handle_du<'a>(_du_options: &ArgMatches<'a>) -> Result<(), Error> {
+    // (images, size, stale size) per command
+    let mut usage: HashMap<String, (usize, u64, u64)> = HashMap::new();
+    for (image, status) in classify_images()? {
+        let entry = usage.entry(display_command(&image)).or_insert((0, 0, 0));
+        entry.0 += 1;
+        entry.1 += image.size;
+        if status.is_stale() {
+            entry.2 += image.size;
+        }
+    }
+    let mut usage: Vec<(String, (usize, u64, u64))> = usage.into_iter().collect();
+    usage.sort_by(|(_, a), (_, b)| b.1.cmp(&a.1));
+
+    println!(
+        "{: <30}{: <10}{: <12}RECLAIMABLE",
+        "COMMAND", "IMAGES", "SIZE"
+    );
+    let (mut total_images, mut total_size, mut total_stale) = (0, 0, 0);
+    for (command, (images, size, stale)) in &usage {
+        println!(
+            "{: <30}{: <10}{: <12}{}",
+            command,
+            images,
+            format_size(*size),
+            format_size(*stale)
+        );
+        total_images += images;
+        total_size += size;
+        total_stale += stale;
+    }
+    println!(
+        "{: <30}{: <10}{: <12}{}",
+        "TOTAL",
+        total_images,
+        format_size(total_size),
+        format_size(total_stale)
+    );
+    eprintln!("Sizes include the layers shared with other images. Run macondo gc to reclaim space");
+    return Ok(());
+}
+
+/// Unmarks the images that are to be removed but are the parents of images
+/// that are kept (e.g. the command image of a kept aligned image), and so on
+/// up to their own parents
+fn keep_parents(images: &[&ManagedImage], remove: &mut [bool]) {
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for parent in 0..images.len() {
+            let is_needed = (0..images.len())
+                .any(|child| !remove[child] && is_parent(images[parent], images[child]));
+            if remove[parent] && is_needed {
+                remove[parent] = false;
+                changed = true;
+            }
+        }
+    }
+}
+
+/// Lists the images created by macondo along with whether they are still needed
+fn classify_images() -> Result<Vec<(ManagedImage, ImageStatus)>, Error> {
+    let config = config::load_config()?;
+    // shadowed commands count too, as they can still be run by qualified name
+    let cmds: Vec<cmd::Cmd> = cmd::load_repositories(&config.repositories)?
+        .into_iter()
+        .flat_map(|repo| repo.cmds)
+        .collect();
+
+    // hashes of the current version of each command. None when it could
+    // not be computed, in which case all the images of the command are kept
+    let mut hashes: HashMap<String, Option<HashSet<String>>> = HashMap::new();
+    let mut referenced: HashSet<String> = HashSet::new();
+    for cmd in &cmds {
+        let hash = docker::command_hash(cmd).ok();
+        let entry = hashes
+            .entry(cmd.name.clone())
+            .or_insert_with(|| Some(HashSet::new()));
+        match (entry.as_mut(), hash) {
+            (Some(known), Some(hash)) => {
+                known.insert(hash);
+            }
+            _ => *entry = None,
+        }
+        if cmd.command_path.is_none() {
+            referenced.insert(normalize_reference(&cmd.registry));
+        }
+    }
+
+    let mut images = Vec::new();
+    for image in list_managed_images()? {
+        let status = if image
+            .tags
+            .iter()
+            .chain(image.digests.iter())
+            .any(|reference| referenced.contains(reference))
+        {
+            ImageStatus::InUse
+        } else {
+            match hashes.get(&image.command) {
+                None => ImageStatus::Orphaned,
+                Some(None) => ImageStatus::Current,
+                Some(Some(known)) if known.contains(&image.hash) => ImageStatus::Current,
+                Some(Some(_)) => ImageStatus::Outdated,
+            }
+        };
+        images.push((image, status));
+    }
+    return Ok(images);
+}
+
+/// Adds the implicit latest tag to image references without tag or digest,
+/// the way docker lists them
+fn normalize_reference(image: &str) -> String {
+    let (name, digest) = docker::split_digest(image);
+    if digest.is_some() || docker::strip_tag(name) != name {
+        return String::from(image);
+    }
+    return format!("{}:latest", image);
+}
+
+fn display_command(image: &ManagedImage) -> String {
+    return if image.command.is_empty() {
+        String::from("(unknown command)")
+    } else {
+        image.command.clone()
+    };
+}
+
+fn display_reference(image: &ManagedImage) -> String {
+    return match image.tags.first() {
+        Some(tag) => tag.clone(),
+        None => image.id.replace("sha256:", "").chars().take(12).collect(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(id: &str, kind: &str, layers: &[&str]) -> ManagedImage {
+        return ManagedImage {
+            id: String::from(id),
+            tags: Vec::new(),
+            digests: Vec::new(),
+            command: String::from("hello"),
+            hash: String::new(),
+            kind: String::from(kind),
+            created: 0,
+            size: 0,
+            layers: layers.iter().map(|layer| String::from(*layer)).collect(),
+        };
+    }
+
+    #[test]
+    fn keeps_the_parents_of_kept_images() {
+        let images = vec![
+            image("old-build", "build", &["base", "old"]),
+            image("old-aligned", "aligned", &["base", "old", "user"]),
+            image("older-build", "build", &["base", "older"]),
+            image("older-aligned", "aligned", &["base", "older", "user"]),
+        ];
+        let images: Vec<&ManagedImage> = images.iter().collect();
+        // e.g. --keep-last 1 kept the most recent aligned image only
+        let mut remove = vec![true, false, true, true];
+        keep_parents(&images, &mut remove);
+        assert_eq!(remove, vec![false, false, true, true]);
+    }
+
+    #[test]
+    fn keeps_ancestors_of_kept_images() {
+        let images = vec![
+            image("base-command", "build", &["base", "a"]),
+            image("command", "build", &["base", "a", "b"]),
+            image("aligned", "aligned", &["base", "a", "b", "user"]),
+            image("unrelated", "build", &["base", "c"]),
+        ];
+        let images: Vec<&ManagedImage> = images.iter().collect();
+        let mut remove = vec![true, true, false, true];
+        keep_parents(&images, &mut remove);
+        assert_eq!(remove, vec![false, false, false, true]);
+    }
+}
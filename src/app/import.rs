@@ -0,0 +1,60 @@
+use crate::cmd;
+use crate::docker;
+use crate::util::paths;
+use crate::util::paths::expand_path;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use cmd::Repo;
+use colored::*;
+use easy_error::{Error, ResultExt};
+use std::path::Path;
+
+pub fn import_app<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("import")
+        .about("Reconstructs the commands described by the labels of images built by macondo")
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .setting(AppSettings::DisableVersion)
+        .setting(AppSettings::ColoredHelp)
+        .setting(AppSettings::UnifiedHelpMessage)
+        .arg(
+            Arg::with_name("merge_into")
+                .long("merge-into")
+                .takes_value(true)
+                .help("Adds the commands to the provided YAML repository file instead of printing them"),
+        )
+        .arg(
+            Arg::with_name("IMAGE")
+                .help("Image reference, e.g. registry/commands:ls-0.1.0 or registry/commands@sha256:...")
+                .required(true)
+                .multiple(true)
+                .index(1),
+        )
+}
+
+pub fn handle_import<'a>(import_options: &ArgMatches<'a>) -> Result<(), Error> {
+    let mut cmds = Vec::new();
+    for image in import_options.values_of("IMAGE").unwrap() {
+        cmds.push(docker::import_command(image)?);
+    }
+
+    if let Some(repo_file) = import_options.value_of("merge_into") {
+        let repo_file = expand_path(repo_file);
+        let mut repo = if Path::new(&repo_file).exists() {
+            cmd::load_repo(&repo_file)?
+        } else {
+            Repo::default()
+        };
+        let names: Vec<String> = cmds.iter().map(|cmd| cmd.name.clone()).collect();
+        repo.merge(cmds, false);
+        let yaml = serde_yaml::to_string(&repo).context("Failed to serialize repo as YAML")?;
+        paths::write_atomically(&repo_file, yaml.as_bytes())?;
+        eprintln!("Imported {} into {}", names.join(", ").blue(), repo_file.green());
+    } else {
+        let repo = Repo {
+            commands: cmds,
+            ..Repo::default()
+        };
+        let yaml = serde_yaml::to_string(&repo).context("Failed to serialize repo as YAML")?;
+        println!("{}", yaml);
+    }
+    return Ok(());
+}
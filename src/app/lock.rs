@@ -0,0 +1,77 @@
+use crate::cmd;
+use crate::cmd::lock::{repository_hash, LockedCommand, LockedRepository, Lockfile};
+use crate::cmd::Cmd;
+use crate::config;
+use crate::docker;
+use crate::docker::BuildOptions;
+use crate::util::paths::write_atomically;
+use clap::{App, AppSettings, ArgMatches, SubCommand};
+use colored::*;
+use easy_error::{Error, ResultExt};
+
+pub fn lock_app<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("lock")
+        .about("Records the contents of the repositories and the image digests of their commands in a lockfile, to be enforced with --locked")
+        .setting(AppSettings::DisableVersion)
+        .setting(AppSettings::ColoredHelp)
+        .setting(AppSettings::UnifiedHelpMessage)
+}
+
+pub fn handle_lock<'a>(_lock_options: &ArgMatches<'a>, verbose: bool) -> Result<(), Error> {
+    let config = config::load_config()?;
+    let options = BuildOptions {
+        verbose,
+        ..BuildOptions::default()
+    };
+
+    let mut lockfile = Lockfile::default();
+    for repo in cmd::load_repositories(&config.repositories)? {
+        eprintln!("Locking {}", repo.repository.url.blue());
+        let mut commands = Vec::new();
+        for cmd in &repo.cmds {
+            commands.push(lock_command(cmd, &options)?);
+        }
+        lockfile.repositories.push(LockedRepository {
+            hash: repository_hash(&repo.repository)?,
+            url: repo.repository.url,
+            commands,
+        });
+    }
+
+    let path = config.lockfile_path();
+    let doc = serde_yaml::to_string(&lockfile).context("Failed to serialize the lockfile")?;
+    write_atomically(&path, doc.as_bytes())?;
+    let locked_cmds: usize = lockfile
+        .repositories
+        .iter()
+        .map(|repo| repo.commands.len())
+        .sum();
+    eprintln!(
+        "Locked {} commands of {} repositories in {}",
+        locked_cmds,
+        lockfile.repositories.len(),
+        path.green()
+    );
+    return Ok(());
+}
+
+/// Commands that run a published image get the digest its tag currently
+/// points to, which requires pulling it. Commands built on the fly are
+/// locked by their hash only, as they are rebuilt from their sources.
+fn lock_command(cmd: &Cmd, options: &BuildOptions) -> Result<LockedCommand, Error> {
+    let image = if cmd.command_path.is_some() {
+        None
+    } else if let (_, Some(_)) = docker::split_digest(&cmd.registry) {
+        Some(cmd.registry.clone())
+    } else {
+        docker::pull_image(&cmd.registry, options)?;
+        let digest = docker::resolve_image_digest(&cmd.registry, options)?;
+        Some(digest).filter(|digest| digest != &cmd.registry)
+    };
+    return Ok(LockedCommand {
+        name: cmd.name.clone(),
+        version: cmd.version.clone(),
+        hash: docker::command_hash(cmd)?,
+        image,
+    });
+}
@@ -1,3 +1,11 @@
 pub mod core;
 pub mod repo_management;
-pub mod cmd_builder;
\ No newline at end of file
+pub mod cache;
+pub mod cmd_builder;
+pub mod export;
+pub mod gc;
+pub mod import;
+pub mod lock;
+pub mod new;
+pub mod pull;
+pub mod test;
\ No newline at end of file
@@ -0,0 +1,273 @@
+use crate::cmd::parser;
+use crate::docker::builders::find_builder;
+use crate::util::prompt::{confirm, prompt};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use colored::*;
+use easy_error::{bail, Error, ResultExt};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+pub fn new_app<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("new")
+        .about("Creates a new command file, ready to be run")
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .setting(AppSettings::DisableVersion)
+        .setting(AppSettings::ColoredHelp)
+        .setting(AppSettings::UnifiedHelpMessage)
+        .arg(
+            Arg::with_name("lang")
+                .long("lang")
+                .short("l")
+                .takes_value(true)
+                .possible_values(&["bash", "python", "node", "go"])
+                .default_value("bash")
+                .help("Language the command is written in"),
+        )
+        .arg(
+            Arg::with_name("from")
+                .long("from")
+                .takes_value(true)
+                .help("Builder or base image of the command (value of @from). Go commands are compiled, so it is the image the binary runs on"),
+        )
+        .arg(
+            Arg::with_name("dir")
+                .long("dir")
+                .short("d")
+                .takes_value(true)
+                .default_value(".")
+                .help("Directory the command is created in"),
+        )
+        .arg(
+            Arg::with_name("yes")
+                .long("yes")
+                .short("y")
+                .help("Does not prompt for anything, using the defaults instead"),
+        )
+        .arg(
+            Arg::with_name("NAME")
+                .help("Name of the command")
+                .required(true)
+                .index(1),
+        )
+}
+
+/// What the user told us about the command
+struct Answers {
+    description: String,
+    group: String,
+    volumes: Vec<String>,
+    enable_dynamic_volume_mounts: bool,
+    align_with_host_user: bool,
+}
+
+pub fn handle_new<'a>(new_options: &ArgMatches<'a>) -> Result<(), Error> {
+    let name = new_options.value_of("NAME").unwrap();
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!(
+            "Invalid command name {}. Use letters, digits, - and _",
+            name
+        );
+    }
+    let lang = new_options.value_of("lang").unwrap();
+    let from = new_options.value_of("from");
+    if lang == "go" {
+        if let Some(from) = from.and_then(find_builder) {
+            bail!(
+                "Go commands are compiled with a Dockerfile, so they can't use the {} builder. Use a base image instead",
+                from.builder.name
+            );
+        }
+    }
+
+    let dir = Path::new(new_options.value_of("dir").unwrap());
+    let command_file = dir.join(format!("{}.mcd", name));
+    let dockerfile = dir.join("Dockerfile");
+    if command_file.exists() {
+        bail!("{} already exists", command_file.display());
+    }
+    if lang == "go" && dockerfile.exists() {
+        bail!(
+            "{} already exists. Go commands need their own Dockerfile, create the command in another directory",
+            dockerfile.display()
+        );
+    }
+
+    let answers = if new_options.is_present("yes") {
+        Answers {
+            description: String::new(),
+            group: String::new(),
+            volumes: Vec::new(),
+            enable_dynamic_volume_mounts: false,
+            align_with_host_user: true,
+        }
+    } else {
+        ask_questions()?
+    };
+
+    fs::create_dir_all(dir).context(format!("Failed to create {}", dir.display()))?;
+    let contents = render_command(name, lang, from, &answers);
+    fs::write(&command_file, contents)
+        .context(format!("Failed to write {}", command_file.display()))?;
+    fs::set_permissions(&command_file, fs::Permissions::from_mode(0o755)).context(format!(
+        "Failed to make {} executable",
+        command_file.display()
+    ))?;
+    if lang == "go" {
+        let contents = render_go_dockerfile(name, from.unwrap_or("alpine:3.20"));
+        fs::write(&dockerfile, contents)
+            .context(format!("Failed to write {}", dockerfile.display()))?;
+    }
+
+    // make sure what we generated is a valid command
+    parser::parse_command_file(&command_file.to_string_lossy())?;
+
+    eprintln!("Created {}", command_file.display().to_string().green());
+    if lang == "go" {
+        eprintln!("Created {}", dockerfile.display().to_string().green());
+    }
+    eprintln!(
+        "Run it with: {}",
+        format!("macondo {}", command_file.display()).blue()
+    );
+    return Ok(());
+}
+
+fn ask_questions() -> Result<Answers, Error> {
+    let description = prompt("Description", "")?;
+    let group = prompt("Group", "")?;
+    let mut volumes = Vec::new();
+    eprintln!("Volumes to mount, as FROM:TO[:OPTIONS] (e.g. ~:~). Leave empty to finish");
+    loop {
+        let volume = prompt("Volume", "")?;
+        if volume.is_empty() {
+            break;
+        }
+        if volume.split(':').filter(|part| !part.is_empty()).count() < 2 {
+            eprintln!("{}", "Expected format: FROM:TO[:OPTIONS]".red());
+            continue;
+        }
+        volumes.push(volume);
+    }
+    let enable_dynamic_volume_mounts = confirm("Mount the paths passed as arguments?", false)?;
+    let align_with_host_user = confirm(
+        "Run as your host user, so that written files belong to you?",
+        true,
+    )?;
+    return Ok(Answers {
+        description,
+        group,
+        volumes,
+        enable_dynamic_volume_mounts,
+        align_with_host_user,
+    });
+}
+
+/// Renders the command file: the interpreter line, the annotations as
+/// comments of the language, and a program that prints its arguments
+fn render_command(name: &str, lang: &str, from: Option<&str>, answers: &Answers) -> String {
+    let (shebang, comment, default_from, body) = match lang {
+        "python" => (
+            Some("#!/usr/bin/env python3"),
+            "#",
+            "python:3.12-slim",
+            PYTHON_BODY,
+        ),
+        "node" => (Some("#!/usr/bin/env node"), "//", "node:20-slim", NODE_BODY),
+        // compiled by the Dockerfile created next to the command
+        "go" => (None, "//", "Dockerfile", GO_BODY),
+        _ => (
+            Some("#!/usr/bin/env bash"),
+            "#",
+            "AlpinePackages",
+            BASH_BODY,
+        ),
+    };
+    let from = if lang == "go" {
+        default_from
+    } else {
+        from.unwrap_or(default_from)
+    };
+
+    let mut annotations = vec![format!("from {}", from), String::from("version 0.1.0")];
+    if !answers.description.is_empty() {
+        annotations.push(format!("description {}", answers.description));
+    }
+    if !answers.group.is_empty() {
+        annotations.push(format!("group {}", answers.group));
+    }
+    for volume in &answers.volumes {
+        annotations.push(format!("vol {}", volume));
+    }
+    if answers.enable_dynamic_volume_mounts {
+        annotations.push(String::from("enable_dynamic_volume_mounts true"));
+    }
+    annotations.push(format!(
+        "align_with_host_user {}",
+        answers.align_with_host_user
+    ));
+
+    let mut contents = String::new();
+    if let Some(shebang) = shebang {
+        contents.push_str(shebang);
+        contents.push('\n');
+    }
+    for annotation in annotations {
+        contents.push_str(&format!("{} @{}\n", comment, annotation));
+    }
+    contents.push_str(&body.replace("{name}", name));
+    return contents;
+}
+
+fn render_go_dockerfile(name: &str, base_image: &str) -> String {
+    return GO_DOCKERFILE
+        .replace("{name}", name)
+        .replace("{base_image}", base_image);
+}
+
+const BASH_BODY: &str = "set -euo pipefail
+
+echo \"Hello from {name}! Arguments: $*\"
+";
+
+const PYTHON_BODY: &str = "import sys
+
+
+def main(args):
+    print(f\"Hello from {name}! Arguments: {' '.join(args)}\")
+
+
+if __name__ == \"__main__\":
+    main(sys.argv[1:])
+";
+
+const NODE_BODY: &str = "const args = process.argv.slice(2);
+console.log(`Hello from {name}! Arguments: ${args.join(\" \")}`);
+";
+
+const GO_BODY: &str = "package main
+
+import (
+	\"fmt\"
+	\"os\"
+	\"strings\"
+)
+
+func main() {
+	fmt.Printf(\"Hello from {name}! Arguments: %s\\n\", strings.Join(os.Args[1:], \" \"))
+}
+";
+
+const GO_DOCKERFILE: &str = "FROM golang:1.22-alpine AS build
+WORKDIR /src
+COPY {name}.mcd main.go
+RUN go mod init {name} && CGO_ENABLED=0 go build -o /{name} .
+
+FROM {base_image}
+COPY --from=build /{name} /{name}
+ENTRYPOINT [ \"/{name}\" ]
+";
@@ -0,0 +1,186 @@
+use super::cmd_builder::{
+    build_on_the_fly_if_necessary, format_duration, print_build_summary, BuildReport,
+};
+use crate::cmd::{self, Cmd, LoadedRepository};
+use crate::config;
+use crate::docker;
+use crate::docker::BuildOptions;
+use crate::exec;
+use crate::util::tasks::run_concurrently;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use colored::*;
+use easy_error::{bail, Error};
+use std::time::Instant;
+
+pub fn pull_app<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("pull")
+        .about(
+            "Pulls (or builds) the images of commands ahead of time, so that they are ready to run",
+        )
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .setting(AppSettings::DisableVersion)
+        .setting(AppSettings::ColoredHelp)
+        .setting(AppSettings::UnifiedHelpMessage)
+        .arg(
+            Arg::with_name("all")
+                .long("all")
+                .short("a")
+                .conflicts_with_all(&["group", "COMMAND"])
+                .help("Pulls the images of all commands"),
+        )
+        .arg(
+            Arg::with_name("group")
+                .long("group")
+                .short("g")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Pulls the images of all commands in the provided group"),
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .long("jobs")
+                .short("j")
+                .takes_value(true)
+                .default_value("4")
+                .validator(|jobs| match jobs.parse::<usize>() {
+                    Ok(jobs) if jobs > 0 => Ok(()),
+                    _ => Err(String::from("must be a positive number")),
+                })
+                .help("Number of images to pull concurrently"),
+        )
+        .arg(
+            Arg::with_name("COMMAND")
+                .help("Name of the commands whose images to pull")
+                .multiple(true)
+                .index(1),
+        )
+}
+
+pub fn handle_pull<'a>(pull_options: &ArgMatches<'a>, verbose: bool) -> Result<(), Error> {
+    let config = config::load_config()?;
+    let loaded = cmd::lock::load_configured_repositories(&config)?;
+    let cmds = select_commands(&loaded, pull_options)?
+        .into_iter()
+        .map(|cmd| cmd::lock::enforce_lock(cmd, &config))
+        .collect::<Result<Vec<Cmd>, Error>>()?;
+    let options = BuildOptions {
+        verbose,
+        base_image_pins: config.base_image_pins.clone(),
+        ..BuildOptions::default()
+    };
+
+    let items: Vec<(String, Cmd)> = cmds
+        .into_iter()
+        .map(|cmd| (cmd.name.clone(), cmd))
+        .collect();
+    let reports = run_concurrently(
+        items,
+        pull_options.value_of("jobs").unwrap().parse().unwrap(),
+        |cmd, progress| {
+            let options = BuildOptions {
+                progress: Some(progress.clone()),
+                ..options.clone()
+            };
+            warm_up(cmd, &options)
+        },
+        |report: &BuildReport| match &report.result {
+            Ok(ready_cmd) => format!(
+                "{} {} in {}",
+                "✔".green(),
+                &ready_cmd.registry,
+                format_duration(report.duration)
+            ),
+            Err(_) => format!("{} failed", "✘".red()),
+        },
+    );
+    print_build_summary(&reports, "Failed to pull");
+
+    let failed = reports
+        .iter()
+        .filter(|report| report.result.is_err())
+        .count();
+    if failed > 0 {
+        bail!("{} of {} commands failed to pull", failed, reports.len());
+    }
+    return Ok(());
+}
+
+/// Commands selected by (qualified) name, group or --all
+fn select_commands(
+    loaded: &[LoadedRepository],
+    pull_options: &ArgMatches,
+) -> Result<Vec<Cmd>, Error> {
+    let cmds = cmd::resolve_commands(loaded);
+    if pull_options.is_present("all") {
+        return Ok(cmds);
+    }
+    let names: Vec<&str> = pull_options
+        .values_of("COMMAND")
+        .map(|names| names.collect())
+        .unwrap_or_default();
+    let groups: Vec<&str> = pull_options
+        .values_of("group")
+        .map(|groups| groups.collect())
+        .unwrap_or_default();
+    let mut selected: Vec<Cmd> = Vec::new();
+    for name in &names {
+        let found = cmd::find_qualified_command(name, loaded)
+            .or_else(|| cmds.iter().find(|cmd| &cmd.name == name).cloned());
+        match found {
+            Some(cmd) => selected.push(cmd),
+            None => bail!("{} command not found", name.red()),
+        }
+    }
+    selected.extend(
+        cmds.into_iter().filter(|cmd| {
+            groups.contains(&cmd.group.as_str()) && !names.contains(&cmd.name.as_str())
+        }),
+    );
+    if selected.is_empty() {
+        bail!("No commands in the provided groups");
+    }
+    return Ok(selected);
+}
+
+/// Makes the image a command runs on available locally: registry images are
+/// pulled, local commands are built, and both get their user-aligned version
+fn warm_up(cmd: Cmd, options: &BuildOptions) -> BuildReport {
+    let name = cmd.name.clone();
+    let start = Instant::now();
+    let result = docker::managed_labels(&cmd, "aligned").and_then(|labels| {
+        let cmd = if cmd.command_path.is_some() {
+            build_on_the_fly_if_necessary(cmd, options)?
+        } else {
+            // digest references are immutable, so they are only pulled once
+            let (_, digest) = docker::split_digest(&cmd.registry);
+            let is_present =
+                || exec::does_command_succeed("docker", vec!["image", "inspect", &cmd.registry]);
+            if digest.is_none() || !is_present() {
+                docker::pull_image(&cmd.registry, options)?;
+            }
+            cmd
+        };
+        if !cmd.align_with_host_user {
+            return Ok(cmd);
+        }
+        let options = BuildOptions {
+            labels,
+            ..options.clone()
+        };
+        return Ok(Cmd {
+            registry: docker::align_with_host_user(&cmd.registry, &options)?,
+            ..cmd
+        });
+    });
+    let size = match &result {
+        Ok(ready_cmd) => docker::image_size(&ready_cmd.registry).ok(),
+        Err(_) => None,
+    };
+    return BuildReport {
+        name,
+        result,
+        duration: start.elapsed(),
+        size,
+    };
+}
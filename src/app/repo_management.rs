@@ -1,9 +1,13 @@
-use crate::util::cache::{get_from_cache, is_cacheable};
 use crate::cmd;
+use crate::cmd::Cmd;
 use crate::config;
+use crate::util::cache::{get_from_cache, is_cacheable};
+use crate::util::git;
+use crate::util::http;
+use crate::util::signature;
 use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use colored::*;
-use config::MacondoConfig;
+use config::{MacondoConfig, Repository, RepositoryAuth};
 use easy_error::{bail, Error};
 
 pub fn repo_management_app<'a, 'b>() -> App<'a, 'b> {
@@ -18,9 +22,63 @@ pub fn repo_management_app<'a, 'b>() -> App<'a, 'b> {
             SubCommand::with_name("add")
             .setting(AppSettings::ColoredHelp)
             .about("Adds a repository")
+            .arg(Arg::with_name("alias")
+            .long("alias")
+            .takes_value(true)
+            .validator(|alias| validate_alias(&alias))
+            .help("Name used to reach its commands as ALIAS/COMMAND. Defaults to the last segment of the URL"))
+            .arg(Arg::with_name("priority")
+            .long("priority")
+            .takes_value(true)
+            .allow_hyphen_values(true)
+            .validator(|priority| priority.parse::<i32>().map(|_| ()).map_err(|_| String::from("must be an integer")))
+            .help("When several repositories define a command with the same name, the one with the highest priority wins [default: 0]"))
+            .arg(Arg::with_name("key")
+            .long("key")
+            .takes_value(true)
+            .validator(|key| signature::parse_public_key(&key).map(|_| ()).map_err(|e| e.to_string()))
+            .help("Minisign public key (or path to a .pub file) the repository must be signed with"))
+            .arg(Arg::with_name("token_env")
+            .long("token-env")
+            .takes_value(true)
+            .conflicts_with_all(&["token_file", "username"])
+            .help("Environment variable with a bearer token for the repository"))
+            .arg(Arg::with_name("token_file")
+            .long("token-file")
+            .takes_value(true)
+            .conflicts_with_all(&["username"])
+            .help("File with a bearer token for the repository"))
+            .arg(Arg::with_name("username")
+            .long("username")
+            .takes_value(true)
+            .help("Username for basic auth. The password is read from --password-env or --password-file"))
+            .arg(Arg::with_name("password_env")
+            .long("password-env")
+            .takes_value(true)
+            .requires("username")
+            .conflicts_with("password_file")
+            .help("Environment variable with the basic auth password"))
+            .arg(Arg::with_name("password_file")
+            .long("password-file")
+            .takes_value(true)
+            .requires("username")
+            .help("File with the basic auth password"))
+            .arg(Arg::with_name("client_cert")
+            .long("client-cert")
+            .takes_value(true)
+            .help("PKCS#12 bundle with a client certificate and its key, for mutual TLS"))
+            .arg(Arg::with_name("client_cert_password_env")
+            .long("client-cert-password-env")
+            .takes_value(true)
+            .requires("client_cert")
+            .help("Environment variable with the password of the client certificate bundle"))
+            .arg(Arg::with_name("insecure")
+            .long("insecure")
+            .help("Does not verify the TLS certificate of the repository. Anyone on the network path can then tamper with its commands"))
             .arg(Arg::with_name("REPO")
-            .help("Repository URL (either http, https, directory path, path to repository YAML file)")
+            .help("Repository URL (either http, https, git+URL[#[REF][:PATH]], directory path, path to repository YAML file)")
             .required(true)
+            .validator(|url| http::validate_url(&url).map_err(|e| e.to_string()))
             .index(1))
         )
         .subcommand(
@@ -28,7 +86,7 @@ pub fn repo_management_app<'a, 'b>() -> App<'a, 'b> {
             .setting(AppSettings::ColoredHelp)
             .about("Removes a repository")
             .arg(Arg::with_name("REPO")
-            .help("Repository URL (either http, https, directory path, path to repository YAML file)")
+            .help("Repository URL or alias")
             .required(true)
             .index(1))
         )
@@ -41,6 +99,11 @@ pub fn repo_management_app<'a, 'b>() -> App<'a, 'b> {
             SubCommand::with_name("update")
             .setting(AppSettings::ColoredHelp)
             .about("Refreshes the list of commands provided by the repositories")
+        )
+        .subcommand(
+            SubCommand::with_name("conflicts")
+            .setting(AppSettings::ColoredHelp)
+            .about("Shows the commands defined by several repositories, and which definition wins")
         );
 }
 
@@ -49,7 +112,23 @@ pub fn handle<'a>(app: &ArgMatches<'a>) -> Result<(), Error> {
     match subcommand {
         "list" => handle_list(),
         "update" => handle_update(),
-        "add" => handle_add(args.unwrap().value_of("REPO").unwrap()),
+        "conflicts" => handle_conflicts(),
+        "add" => {
+            let args = args.unwrap();
+            handle_add(Repository {
+                url: String::from(args.value_of("REPO").unwrap()),
+                alias: args.value_of("alias").map(String::from),
+                priority: args
+                    .value_of("priority")
+                    .map(|priority| priority.parse().unwrap())
+                    .unwrap_or(0),
+                key: args
+                    .value_of("key")
+                    .map(|key| signature::parse_public_key(key).unwrap()),
+                auth: auth_from_args(args),
+                insecure: args.is_present("insecure"),
+            })
+        }
         "remove" => handle_remove(args.unwrap().value_of("REPO").unwrap()),
         _ => bail!("Repo subcommand unrecognized: {}", subcommand),
     }
@@ -64,9 +143,18 @@ fn handle_list() -> Result<(), Error> {
             "macondo repo add SOME_REPO".green()
         );
     } else {
+        if let Some(project_config) = &config.project_config {
+            println!("\nIncluding the repositories of {}", project_config.blue());
+        }
         println!("\nCurrent repositories:\n");
         for repo in config.repositories {
-            println!("{} provides:\n", repo.blue().bold().underline());
+            println!(
+                "{} ({}, priority {}{}) provides:\n",
+                repo.url.blue().bold().underline(),
+                repo.alias(),
+                repo.priority,
+                if repo.key.is_some() { ", signed" } else { "" }
+            );
             let cmds = cmd::load_commands(vec![repo])?;
             cmd::list_commands(&cmds, true);
         }
@@ -77,39 +165,227 @@ fn handle_list() -> Result<(), Error> {
 fn handle_update() -> Result<(), Error> {
     let conf = config::load_config()?;
     for repo in conf.repositories {
-        if is_cacheable(&repo) {
-            println!("Updating remote repository: {}", repo.blue());
+        if is_cacheable(&repo.url) {
+            println!("Updating remote repository: {}", repo.url.blue());
             get_from_cache(&repo, true)?;
+        } else if git::is_git_resource(&repo.url) {
+            println!("Updating git repository: {}", repo.url.blue());
+            update_git_repo(&repo)?;
         }
     }
     return Ok(());
 }
 
-fn handle_add(repo: &str) -> Result<(), Error> {
-    let conf = config::load_config()?;
+/// Fetches the new commits of a git repository and reports the commits and
+/// commands that changed
+fn update_git_repo(repository: &Repository) -> Result<(), Error> {
+    let repo = repository.url.as_str();
+    let before = if git::is_checked_out(repo)? {
+        // the checkout may not be trusted anymore, e.g. after a key change
+        cmd::load_commands(vec![repository.clone()]).unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    let update = git::update_checkout(repo)?;
+    let previous = match &update.previous {
+        Some(previous) if previous == &update.current => {
+            println!(
+                "  Already up to date at {}",
+                git::short_commit(&update.current)
+            );
+            return Ok(());
+        }
+        Some(previous) => previous,
+        None => {
+            println!("  Checked out {}", git::short_commit(&update.current));
+            return Ok(());
+        }
+    };
+
+    let commits = git::log_between(repo, previous, &update.current)?;
+    if commits.is_empty() {
+        // the reference moved to a commit that is not a descendant
+        println!(
+            "  Moved from {} to {}",
+            git::short_commit(previous),
+            git::short_commit(&update.current)
+        );
+    } else {
+        println!(
+            "  {}..{} ({} new commits)",
+            git::short_commit(previous),
+            git::short_commit(&update.current),
+            commits.len()
+        );
+        for commit in &commits {
+            println!("    {}", commit);
+        }
+    }
+
+    let after = cmd::load_commands(vec![repository.clone()])?;
+    print_command_changes(&before, &after);
+    return Ok(());
+}
+
+/// Prints the commands that were added, removed or modified
+fn print_command_changes(before: &[Cmd], after: &[Cmd]) {
+    let find = |cmds: &[Cmd], name: &str| cmds.iter().find(|cmd| cmd.name == name).cloned();
+    let mut names: Vec<&str> = before
+        .iter()
+        .chain(after.iter())
+        .map(|cmd| cmd.name.as_str())
+        .collect();
+    names.sort();
+    names.dedup();
+
+    let mut changes = Vec::new();
+    for name in names {
+        match (find(before, name), find(after, name)) {
+            (None, Some(cmd)) => changes.push(format!(
+                "    {} {} {}",
+                "+".green(),
+                name.green(),
+                cmd.version
+            )),
+            (Some(cmd), None) => {
+                changes.push(format!("    {} {} {}", "-".red(), name.red(), cmd.version))
+            }
+            (Some(old), Some(new)) if old != new => changes.push(if old.version != new.version {
+                format!(
+                    "    {} {} {} -> {}",
+                    "~".yellow(),
+                    name.yellow(),
+                    old.version,
+                    new.version
+                )
+            } else {
+                format!("    {} {}", "~".yellow(), name.yellow())
+            }),
+            _ => (),
+        }
+    }
+    if changes.is_empty() {
+        println!("  No command changed");
+    } else {
+        println!("  Commands changed:");
+        for change in changes {
+            println!("{}", change);
+        }
+    }
+}
+
+/// Prints every command name defined by several repositories, along with
+/// the definition that wins and how to reach the shadowed ones
+fn handle_conflicts() -> Result<(), Error> {
+    let config = config::load_config()?;
+    let loaded = cmd::load_repositories(&config.repositories)?;
+    let conflicts = cmd::find_conflicts(&loaded);
+    if conflicts.is_empty() {
+        println!("No command is defined by more than one repository");
+        return Ok(());
+    }
+    for (name, defs) in conflicts {
+        println!("{}", name.bold());
+        for (idx, (repo, cmd)) in defs.iter().enumerate() {
+            let outcome = if idx == 0 {
+                "wins".green()
+            } else {
+                format!("shadowed, run it as {}/{}", repo.alias(), name).yellow()
+            };
+            println!(
+                "  {: <20}{: <10}priority {: <6}{} ({})",
+                repo.alias().blue(),
+                cmd.version,
+                repo.priority,
+                outcome,
+                repo.url
+            );
+        }
+        if defs[0].0.priority == defs[1].0.priority {
+            println!(
+                "  {}",
+                "Priorities are tied, so the repository listed last wins".yellow()
+            );
+        }
+        println!();
+    }
+    return Ok(());
+}
+
+/// Where to find the credentials of the repository. Only the names of the
+/// variables and files are kept, so that no secret ends up in the config.
+fn auth_from_args(args: &ArgMatches) -> Option<RepositoryAuth> {
+    let value = |name| args.value_of(name).map(String::from);
+    let auth = RepositoryAuth {
+        token_env: value("token_env"),
+        token_file: value("token_file"),
+        username: value("username"),
+        password_env: value("password_env"),
+        password_file: value("password_file"),
+        client_cert: value("client_cert"),
+        client_cert_password_env: value("client_cert_password_env"),
+    };
+    return Some(auth).filter(|auth| auth != &RepositoryAuth::default());
+}
+
+/// Aliases are the first part of qualified names, so they can't contain slashes
+fn validate_alias(alias: &str) -> Result<(), String> {
+    if alias.is_empty() || alias.contains('/') || alias.contains(char::is_whitespace) {
+        return Err(String::from(
+            "must be a non-empty name without slashes or spaces",
+        ));
+    }
+    return Ok(());
+}
+
+fn handle_add(repo: Repository) -> Result<(), Error> {
+    let conf = config::load_global_config()?;
+
+    if !conf
+        .repositories
+        .iter()
+        .any(|existing| existing.url == repo.url)
+    {
+        if let Some(existing) = conf
+            .repositories
+            .iter()
+            .find(|existing| existing.alias() == repo.alias())
+        {
+            bail!(
+                "Alias {} is already used by {}. Choose another one with --alias",
+                repo.alias().red(),
+                existing.url
+            );
+        }
+        // remote repositories are fetched first, so that a wrong URL or
+        // reference is not saved in the config
+        if is_cacheable(&repo.url) {
+            println!("Fetching repository...");
+            get_from_cache(&repo, true)?;
+        } else if git::is_git_resource(&repo.url) {
+            println!("Cloning repository...");
+            git::update_checkout(&repo.url)?;
+        }
+        if repo.key.is_some() {
+            // fails unless its contents are signed with the key
+            cmd::load_repositories(&[repo.clone()])?;
+        }
 
-    let repo = String::from(repo);
-    if !conf.repositories.contains(&repo) {
         // build a new repository list
-        let mut new_repositories: Vec<String> = Vec::new();
+        let mut new_repositories: Vec<Repository> = Vec::new();
         new_repositories.extend(conf.repositories);
-        new_repositories.push(String::from(&repo));
+        new_repositories.push(repo.clone());
         // write new configuration
         let new_conf = MacondoConfig {
             repositories: new_repositories,
+            ..conf
         };
         config::save_config(&new_conf)?;
-        println!("Added new repo {}", &repo.green());
-
-        // if a cacheable resource, try to update it
-        if is_cacheable(&repo) {
-            println!("Fetching repository...");
-            get_from_cache(&repo, true)?;
-        }
+        println!("Added new repo {}", &repo.url.green());
     } else {
         println!(
             "{} {}",
-            repo.blue(),
+            repo.url.blue(),
             "repository is present already".yellow()
         );
     }
@@ -118,16 +394,20 @@ fn handle_add(repo: &str) -> Result<(), Error> {
 }
 
 fn handle_remove(repo: &str) -> Result<(), Error> {
-    let conf = config::load_config()?;
+    let conf = config::load_global_config()?;
 
-    let repo = String::from(repo);
-    if let Some(pos) = conf.repositories.iter().position(|j| j == &repo) {
-        println!("Removing {}", &repo.red());
-        let mut new_repositories: Vec<String> = Vec::new();
+    if let Some(pos) = conf
+        .repositories
+        .iter()
+        .position(|j| j.url == repo || j.alias() == repo)
+    {
+        println!("Removing {}", &conf.repositories[pos].url.red());
+        let mut new_repositories: Vec<Repository> = Vec::new();
         new_repositories.extend(conf.repositories);
         new_repositories.remove(pos);
         let new_conf = MacondoConfig {
             repositories: new_repositories,
+            ..conf
         };
         return config::save_config(&new_conf);
     } else {
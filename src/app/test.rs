@@ -0,0 +1,292 @@
+use super::cmd_builder::build_on_the_fly_if_necessary;
+use super::core::find_command;
+use crate::cmd::executer::ensure_volumes;
+use crate::cmd::testing::{load_test_cases, TestCase};
+use crate::cmd::{self, Cmd};
+use crate::config;
+use crate::config::Repository;
+use crate::docker;
+use crate::docker::BuildOptions;
+use crate::exec;
+use crate::util::paths::{expand_path, write_atomically};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use colored::*;
+use easy_error::{bail, Error, ResultExt};
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+pub fn test_app<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("test")
+        .about("Runs the test cases of the provided command (or all commands in the provided directory)")
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .setting(AppSettings::DisableVersion)
+        .setting(AppSettings::ColoredHelp)
+        .setting(AppSettings::UnifiedHelpMessage)
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .short("f")
+                .takes_value(true)
+                .possible_values(&["tap", "junit"])
+                .default_value("tap")
+                .help("Format of the report"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .help("Writes the report to the provided file instead of printing it"),
+        )
+        .arg(
+            Arg::with_name("COMMAND_OR_DIR")
+                .help("Name of the command, path to a command file, or a directory that is traversed in search of .mcd files")
+                .required(true)
+                .index(1),
+        )
+}
+
+/// Outcome of a single test case
+struct TestResult {
+    command: String,
+    name: String,
+    failures: Vec<String>,
+    duration: Duration,
+}
+
+pub fn handle_test<'a>(test_options: &ArgMatches<'a>, verbose: bool) -> Result<(), Error> {
+    let target = test_options.value_of("COMMAND_OR_DIR").unwrap();
+    let config = config::load_config()?;
+    let cmds = if Path::new(target).exists() {
+        cmd::load_commands(vec![Repository::from(target)])?
+    } else {
+        let cmd = find_command(target, &cmd::lock::load_configured_repositories(&config)?)?;
+        vec![cmd::lock::enforce_lock(cmd, &config)?]
+    };
+    let options = BuildOptions {
+        verbose,
+        base_image_pins: config.base_image_pins.clone(),
+        ..BuildOptions::default()
+    };
+
+    let mut results: Vec<TestResult> = Vec::new();
+    for cmd in cmds {
+        results.extend(test_command(cmd, &options));
+    }
+
+    let report = match test_options.value_of("format").unwrap() {
+        "junit" => junit_report(&results),
+        _ => tap_report(&results),
+    };
+    match test_options.value_of("output") {
+        Some(output) => {
+            let output = expand_path(output);
+            write_atomically(&output, report.as_bytes())?;
+            eprintln!("Wrote test report to {}", output.green());
+        }
+        None => print!("{}", report),
+    }
+
+    let failed = results.iter().filter(|r| !r.failures.is_empty()).count();
+    if failed > 0 {
+        bail!("{} of {} tests failed", failed, results.len());
+    }
+    eprintln!("{} {} tests passed", "✔".green(), results.len());
+    return Ok(());
+}
+
+/// Builds the command and runs all its test cases. Problems that prevent
+/// the test cases from running are reported as failures of every case.
+fn test_command(cmd: Cmd, options: &BuildOptions) -> Vec<TestResult> {
+    let command = cmd.name.clone();
+    let cases = match load_test_cases(&cmd) {
+        Ok(cases) => cases,
+        Err(e) => {
+            return vec![TestResult {
+                command,
+                name: String::from("loading test cases"),
+                failures: vec![error_chain(&e)],
+                duration: Duration::default(),
+            }]
+        }
+    };
+    if cases.is_empty() {
+        eprintln!("{}: {} has no tests", "Warning".yellow(), command.blue());
+        return Vec::new();
+    }
+
+    eprintln!("Testing {}...", command.blue());
+    let built = docker::managed_labels(&cmd, "aligned").and_then(|labels| {
+        let cmd = build_on_the_fly_if_necessary(cmd, options)?;
+        if !cmd.align_with_host_user {
+            return Ok(cmd);
+        }
+        return Ok(Cmd {
+            registry: docker::align_with_host_user(
+                &cmd.registry,
+                &BuildOptions {
+                    labels,
+                    ..options.clone()
+                },
+            )?,
+            ..cmd
+        });
+    });
+    let built = match built {
+        Ok(built) => built,
+        Err(e) => {
+            let failure = format!("failed to build: {}", error_chain(&e));
+            return cases
+                .into_iter()
+                .map(|case| TestResult {
+                    command: command.clone(),
+                    name: case.name,
+                    failures: vec![failure.clone()],
+                    duration: Duration::default(),
+                })
+                .collect();
+        }
+    };
+
+    return cases
+        .into_iter()
+        .map(|case| {
+            let start = Instant::now();
+            let failures = match run_test_case(&built, &case) {
+                Ok(failures) => failures,
+                Err(e) => vec![error_chain(&e)],
+            };
+            TestResult {
+                command: command.clone(),
+                name: case.name,
+                failures,
+                duration: start.elapsed(),
+            }
+        })
+        .collect();
+}
+
+/// Runs a test case in a scratch directory that contains its fixtures.
+/// The scratch directory is the working directory of the command, so PWD
+/// volumes and relative arguments resolve into it.
+fn run_test_case(cmd: &Cmd, case: &TestCase) -> Result<Vec<String>, Error> {
+    let scratch = TempDir::new().context("Failed to create scratch directory")?;
+    case.write_fixtures(scratch.path())?;
+
+    let (exit_code, stdout, stderr) = run_in_dir(cmd, case, scratch.path())?;
+    return Ok(case.check(exit_code, &stdout, &stderr));
+}
+
+fn run_in_dir(cmd: &Cmd, case: &TestCase, dir: &Path) -> Result<(i32, String, String), Error> {
+    // there is no terminal to attach to while testing
+    let cmd = Cmd {
+        needs_tty: false,
+        ..cmd.clone()
+    };
+    let args: Vec<&str> = case.args.iter().map(String::as_str).collect();
+    let docker_run = docker::build_docker_run(&cmd, args, false, dir)?;
+    ensure_volumes(&cmd, &docker_run)?;
+    let docker_args = docker::docker_run_to_args(&docker_run);
+    let stdin = case.stdin.as_deref().unwrap_or("");
+    let output = exec::exec_with_input("docker", &docker_args, stdin, Some(dir))?;
+    let exit_code = match output.status.code() {
+        Some(exit_code) => exit_code,
+        None => bail!("{} was killed by a signal", cmd.name),
+    };
+    return Ok((
+        exit_code,
+        String::from_utf8_lossy(&output.stdout).to_string(),
+        String::from_utf8_lossy(&output.stderr).to_string(),
+    ));
+}
+
+/// Renders the results in the Test Anything Protocol
+fn tap_report(results: &[TestResult]) -> String {
+    let mut report = format!("TAP version 13\n1..{}\n", results.len());
+    for (idx, result) in results.iter().enumerate() {
+        let status = if result.failures.is_empty() {
+            "ok"
+        } else {
+            "not ok"
+        };
+        report.push_str(&format!(
+            "{} {} - {}: {}\n",
+            status,
+            idx + 1,
+            result.command,
+            result.name.replace('#', "\\#")
+        ));
+        if !result.failures.is_empty() {
+            report.push_str("  ---\n  failures:\n");
+            for failure in &result.failures {
+                let failure = serde_json::to_string(failure).unwrap();
+                report.push_str(&format!("    - {}\n", failure));
+            }
+            report.push_str("  ...\n");
+        }
+    }
+    return report;
+}
+
+/// Renders the results as a JUnit XML report, with a test suite per command
+fn junit_report(results: &[TestResult]) -> String {
+    let failed = results.iter().filter(|r| !r.failures.is_empty()).count();
+    let mut report = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites name=\"macondo\" tests=\"{}\" failures=\"{}\">\n",
+        results.len(),
+        failed
+    );
+    let commands: BTreeSet<&str> = results.iter().map(|r| r.command.as_str()).collect();
+    for command in commands {
+        let suite: Vec<&TestResult> = results.iter().filter(|r| r.command == command).collect();
+        let duration: Duration = suite.iter().map(|r| r.duration).sum();
+        report.push_str(&format!(
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+            xml_escape(command),
+            suite.len(),
+            suite.iter().filter(|r| !r.failures.is_empty()).count(),
+            duration.as_secs_f64()
+        ));
+        for result in suite {
+            report.push_str(&format!(
+                "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"",
+                xml_escape(command),
+                xml_escape(&result.name),
+                result.duration.as_secs_f64()
+            ));
+            if result.failures.is_empty() {
+                report.push_str("/>\n");
+            } else {
+                report.push_str(&format!(
+                    ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>\n",
+                    xml_escape(&result.failures[0]),
+                    xml_escape(&result.failures.join("\n"))
+                ));
+            }
+        }
+        report.push_str("  </testsuite>\n");
+    }
+    report.push_str("</testsuites>\n");
+    return report;
+}
+
+fn xml_escape(value: &str) -> String {
+    return value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;");
+}
+
+/// Formats an error along with its causes in a single line
+fn error_chain(e: &Error) -> String {
+    let mut message = e.to_string();
+    let mut source = std::error::Error::source(e);
+    while let Some(cause) = source {
+        message.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    return message;
+}
@@ -4,8 +4,8 @@ use macondo::app::core::{execute_command, handle_meta_commands_or_return_cmd, ma
 fn main() -> Result<(), Terminator> {
     let matches = main_app().get_matches();
 
-    return if let Some(cmd) = handle_meta_commands_or_return_cmd(&matches)? {
-        execute_command(cmd, &matches)
+    return if let Some((cmd, config)) = handle_meta_commands_or_return_cmd(&matches)? {
+        execute_command(cmd, &config, &matches)
     } else {
         Ok(())
     };
@@ -1,8 +1,10 @@
-use crate::cmd::Cmd;
 use crate::app::cmd_builder;
+use crate::cmd::Cmd;
 use crate::docker;
+use crate::docker::BuildOptions;
 use crate::exec;
 use easy_error::{Error, ResultExt, Terminator};
+use std::env;
 use std::fs::create_dir_all;
 use std::path::Path;
 use std::process::exit;
@@ -12,14 +14,17 @@ pub fn execute_command(
     args: Vec<&str>,
     dry_run: bool,
     disable_dynamic_mounts: bool,
-    verbose: bool,
+    options: &BuildOptions,
 ) -> Result<(), Terminator> {
-    let mut cmd = cmd_builder::build_on_the_fly_if_necessary(cmd, verbose)?;
+    // labels of the aligned image describe the command, not what it was built into
+    let labels = docker::managed_labels(&cmd, "aligned")?;
+    let mut cmd = cmd_builder::build_on_the_fly_if_necessary(cmd, options)?;
     if cmd.align_with_host_user {
-        cmd = align_host_user_if_necessary(cmd, verbose)?;
+        cmd = align_host_user_if_necessary(cmd, labels, options.verbose)?;
     }
 
-    let docker_run = docker::build_docker_run(&cmd, args, disable_dynamic_mounts)?;
+    let working_dir = env::current_dir().context("Failed to get current directory")?;
+    let docker_run = docker::build_docker_run(&cmd, args, disable_dynamic_mounts, &working_dir)?;
     let docker_args = docker::docker_run_to_args(&docker_run);
     if dry_run {
         println!("docker {}", docker_args.join(" "));
@@ -34,7 +39,7 @@ pub fn execute_command(
     return Ok(());
 }
 
-fn ensure_volumes(cmd: &Cmd, docker_run: &docker::DockerRun) -> Result<(), Error> {
+pub fn ensure_volumes(cmd: &Cmd, docker_run: &docker::DockerRun) -> Result<(), Error> {
     for vol in &docker_run.volumes {
         let from = Path::new(&vol.from);
         if !from.exists() {
@@ -61,8 +66,17 @@ fn ensure_volumes(cmd: &Cmd, docker_run: &docker::DockerRun) -> Result<(), Error
 
 /// If cmd.align_with_host_user is set to true, build a new image based on the
 // command's one where a clone of the host user is created in the docker container.
-fn align_host_user_if_necessary(cmd: Cmd, verbose: bool) -> Result<Cmd, Terminator> {
-    let new_command_tag = docker::align_with_host_user(&cmd.registry, verbose)?;
+fn align_host_user_if_necessary(
+    cmd: Cmd,
+    labels: Vec<(String, String)>,
+    verbose: bool,
+) -> Result<Cmd, Terminator> {
+    let options = BuildOptions {
+        verbose,
+        labels,
+        ..BuildOptions::default()
+    };
+    let new_command_tag = docker::align_with_host_user(&cmd.registry, &options)?;
     Ok(Cmd {
         registry: new_command_tag,
         ..cmd
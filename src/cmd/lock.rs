@@ -0,0 +1,242 @@
+use crate::cmd::{Cmd, LoadedRepository};
+use crate::config::{MacondoConfig, Repository};
+use crate::docker;
+use crate::util::cache::{get_from_cache, is_cacheable};
+use crate::util::git;
+use crate::util::hash::sha256_hex;
+use crate::util::paths::expand_path;
+use easy_error::{bail, Error, ResultExt};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// Records exactly what the configured repositories provided when
+/// `macondo lock` ran, so that everyone can run the same commands
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Lockfile {
+    pub repositories: Vec<LockedRepository>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LockedRepository {
+    pub url: String,
+    // git:COMMIT for git repositories, sha256:HEX of the contents otherwise
+    pub hash: String,
+    pub commands: Vec<LockedCommand>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LockedCommand {
+    pub name: String,
+    pub version: String,
+    // See `docker::command_hash`
+    pub hash: String,
+    // Digest reference (REPO@sha256:...) of the image of commands that run
+    // a published image. Commands built on the fly have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+}
+
+pub fn load_lockfile(path: &str) -> Result<Lockfile, Error> {
+    if !Path::new(path).exists() {
+        bail!(
+            "Lockfile {} does not exist. Run macondo lock to create it",
+            path
+        );
+    }
+    let doc = fs::read_to_string(path).context(format!("Failed to read lockfile {}", path))?;
+    return Ok(serde_yaml::from_str(&doc).context(format!("Failed to parse lockfile {}", path))?);
+}
+
+/// Hash of what a repository currently provides: the commit checked out for
+/// git repositories, or the SHA-256 of the cached HTTP body, the manifest
+/// or command file, or all the files of a directory
+pub fn repository_hash(repo: &Repository) -> Result<String, Error> {
+    if git::is_git_resource(&repo.url) {
+        return Ok(format!("git:{}", git::current_commit(&repo.url)?));
+    }
+    let path = if is_cacheable(&repo.url) {
+        get_from_cache(repo, false)?
+    } else {
+        expand_path(&repo.url)
+    };
+    let path = Path::new(&path);
+    if !path.is_dir() {
+        let contents = fs::read(path).context(format!("Failed to read {}", path.display()))?;
+        return Ok(format!("sha256:{}", sha256_hex(&contents)));
+    }
+
+    let mut entries: Vec<_> = WalkDir::new(path)
+        .into_iter()
+        .filter_entry(|entry| entry.file_name() != ".git")
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .collect();
+    entries.sort();
+    // each file contributes its relative path and the hash of its contents,
+    // so that renames change the digest too
+    let mut digest = String::new();
+    for entry in entries {
+        let contents = fs::read(&entry).context(format!("Failed to read {}", entry.display()))?;
+        let relative = entry.strip_prefix(path).unwrap_or(&entry);
+        digest.push_str(&format!(
+            "{}\0{}\n",
+            relative.to_string_lossy(),
+            sha256_hex(&contents)
+        ));
+    }
+    return Ok(format!("sha256:{}", sha256_hex(digest.as_bytes())));
+}
+
+/// Fails unless the configured repositories are exactly the ones of the
+/// lockfile, with the same contents. `loaded` holds what each of them was
+/// loaded from (see `locked_source`), in the same order.
+pub fn verify_repositories(
+    repos: &[Repository],
+    loaded: &[LoadedRepository],
+    lockfile: &Lockfile,
+) -> Result<(), Error> {
+    for (repo, source) in repos.iter().zip(loaded) {
+        let locked = match lockfile
+            .repositories
+            .iter()
+            .find(|locked| locked.url == repo.url)
+        {
+            Some(locked) => locked,
+            None => bail!(
+                "Repository {} is not in the lockfile. Run macondo lock to update it",
+                repo.url
+            ),
+        };
+        let hash = repository_hash(&source.repository)?;
+        if hash != locked.hash {
+            bail!(
+                "Repository {} changed since it was locked (locked {}, found {}). Run macondo lock to update the lockfile",
+                repo.url,
+                locked.hash,
+                hash
+            );
+        }
+    }
+    for locked in &lockfile.repositories {
+        if !repos.iter().any(|repo| repo.url == locked.url) {
+            bail!(
+                "Repository {} is in the lockfile but not configured. Run macondo lock to update it",
+                locked.url
+            );
+        }
+    }
+    return Ok(());
+}
+
+/// Returns the command as locked: it must match a locked definition, and
+/// commands that run published images are pinned to the locked digest
+pub fn pin_command(cmd: Cmd, lockfile: &Lockfile) -> Result<Cmd, Error> {
+    let hash = docker::command_hash(&cmd)?;
+    let locked = lockfile
+        .repositories
+        .iter()
+        .flat_map(|repo| repo.commands.iter())
+        .find(|locked| locked.name == cmd.name && locked.hash == hash);
+    let locked = match locked {
+        Some(locked) => locked,
+        None => bail!(
+            "Command {} does not match any command of the lockfile. Run macondo lock to update it",
+            cmd.name
+        ),
+    };
+    return Ok(match &locked.image {
+        Some(image) => Cmd {
+            registry: image.clone(),
+            ..cmd
+        },
+        None => cmd,
+    });
+}
+
+/// Loads the configured repositories. In locked mode, they must match the
+/// lockfile.
+pub fn load_configured_repositories(
+    config: &MacondoConfig,
+) -> Result<Vec<LoadedRepository>, Error> {
+    if !config.locked {
+        return super::load_repositories(&config.repositories);
+    }
+    let lockfile = load_lockfile(&config.lockfile_path())?;
+    let mut sources = Vec::new();
+    for repo in &config.repositories {
+        sources.push(locked_source(repo, &lockfile)?);
+    }
+    let mut loaded = super::load_repositories(&sources)?;
+    verify_repositories(&config.repositories, &loaded, &lockfile)?;
+    for (loaded, repo) in loaded.iter_mut().zip(&config.repositories) {
+        loaded.repository = repo.clone();
+    }
+    return Ok(loaded);
+}
+
+/// Git repositories can go back to (or forward to) any commit, so instead of
+/// failing when their checkout differs from the lockfile, they are loaded
+/// from a checkout of the locked commit. The checkout of their reference,
+/// which other projects may use, is left alone.
+fn locked_source(repo: &Repository, lockfile: &Lockfile) -> Result<Repository, Error> {
+    let commit = lockfile
+        .repositories
+        .iter()
+        .find(|locked| locked.url == repo.url)
+        .and_then(|locked| locked.hash.strip_prefix("git:"));
+    return Ok(match commit {
+        Some(commit) if git::is_git_resource(&repo.url) => Repository {
+            url: git::resource_at_commit(&repo.url, commit)?,
+            ..repo.clone()
+        },
+        _ => repo.clone(),
+    });
+}
+
+/// In locked mode, pins the command to its locked definition (see
+/// `pin_command`). Otherwise it is returned as-is.
+pub fn enforce_lock(cmd: Cmd, config: &MacondoConfig) -> Result<Cmd, Error> {
+    if !config.locked {
+        return Ok(cmd);
+    }
+    return pin_command(cmd, &load_lockfile(&config.lockfile_path())?);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locking(url: &str, hash: &str) -> Lockfile {
+        return Lockfile {
+            repositories: vec![LockedRepository {
+                url: String::from(url),
+                hash: String::from(hash),
+                commands: vec![],
+            }],
+        };
+    }
+
+    #[test]
+    fn loads_git_repositories_from_their_locked_commit() {
+        let repo = Repository::from("git+https://example.com/commands.git#main:cmds");
+        let source = locked_source(&repo, &locking(&repo.url, "git:0123abcd")).unwrap();
+        assert_eq!(
+            source.url,
+            "git+https://example.com/commands.git#0123abcd:cmds"
+        );
+        assert_eq!(source.alias, repo.alias);
+
+        let other = Repository::from("git+https://example.com/other.git");
+        let lockfile = locking(&repo.url, "git:0123abcd");
+        assert_eq!(locked_source(&other, &lockfile).unwrap(), other);
+        let http = Repository::from("https://example.com/commands.yaml");
+        let lockfile = locking(&http.url, "sha256:0123abcd");
+        assert_eq!(locked_source(&http, &lockfile).unwrap(), http);
+    }
+}
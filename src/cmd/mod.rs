@@ -1,48 +1,154 @@
 extern crate colored;
+use crate::config::Repository;
+use crate::docker;
 use crate::util::cache::{get_from_cache, is_cacheable};
+use crate::util::git;
 use crate::util::paths::expand_path;
+use crate::util::signature;
 use colored::*;
-use easy_error::{Error, ResultExt};
+use easy_error::{bail, Error, ResultExt};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::{fs, path::Path};
-use walkdir::WalkDir;
 
-pub mod parser;
 pub mod executer;
+pub mod lock;
+pub mod parser;
+pub mod testing;
 
-/// Loads all the commands provided by the manifests listed in ~/.macondo
-pub fn load_commands(files: Vec<String>) -> Result<Vec<Cmd>, Error> {
-    let mut all_cmds = HashMap::new();
-    for resource in files {
-        let file: String = if is_cacheable(&resource) {
-            get_from_cache(&resource, false)?
-        } else {
-            expand_path(&resource)
-        };
-        let file_path = Path::new(&file);
-        let cmds = if file_path.is_dir() {
-            // A manifest file can point to a directory, i which case
-            // it is traversed in search of .mcd files which can be parsed as commands
-            load_commands_from_directory(file_path)
-        } else if file.ends_with(".mcd") {
-            Ok(vec![parser::parse_command_file(&file)?])
+/// The commands provided by a repository of the config
+pub struct LoadedRepository {
+    pub repository: Repository,
+    pub cmds: Vec<Cmd>,
+}
+
+/// Loads all the commands provided by the repositories listed in ~/.macondo.
+///
+/// When several repositories define a command with the same name, the one
+/// with the highest priority wins (see `resolve_commands`). Commands are
+/// sorted by name.
+pub fn load_commands(repositories: Vec<Repository>) -> Result<Vec<Cmd>, Error> {
+    return Ok(resolve_commands(&load_repositories(&repositories)?));
+}
+
+/// Loads the commands of each repository, without resolving name collisions
+pub fn load_repositories(repositories: &[Repository]) -> Result<Vec<LoadedRepository>, Error> {
+    let mut loaded = Vec::new();
+    for repository in repositories {
+        loaded.push(LoadedRepository {
+            repository: repository.clone(),
+            cmds: load_resource(repository)?,
+        });
+    }
+    return Ok(loaded);
+}
+
+fn load_resource(repository: &Repository) -> Result<Vec<Cmd>, Error> {
+    let resource = repository.url.as_str();
+    let file: String = if is_cacheable(resource) {
+        get_from_cache(repository, false)?
+    } else if git::is_git_resource(resource) {
+        git::get_checkout(resource)?
+    } else {
+        expand_path(resource)
+    };
+    let file_path = Path::new(&file);
+    // signatures are checked on every load, so that contents modified since
+    // they were fetched (or that are not fetched at all) are caught too
+    if let Some(key) = &repository.key {
+        let verified = if file_path.is_dir() {
+            signature::verify_directory(file_path, key)
         } else {
-            load_commands_from_manifest(&file)
+            signature::verify_file(&file, key)
         };
-        for cmd in cmds? {
-            if let Some(previous_cmd) = all_cmds.insert(cmd.name.clone(), cmd) {
-                eprintln!(
-                    "{}: command {} was overwritten by {}",
-                    "Warning".yellow(),
-                    &previous_cmd.name,
-                    &resource.blue()
-                );
-            }
+        verified.context(format!("Repository {} is not trusted", resource))?;
+    }
+    let cmds = if file_path.is_dir() {
+        // A manifest file can point to a directory, i which case
+        // it is traversed in search of .mcd files which can be parsed as commands
+        load_commands_from_directory(file_path)?
+    } else if file.ends_with(".mcd") {
+        vec![parser::parse_command_file(&file)?]
+    } else {
+        load_commands_from_manifest(&file)?
+    };
+    // within a repository, the last definition of a name wins
+    let mut by_name: BTreeMap<String, Cmd> = BTreeMap::new();
+    for cmd in cmds {
+        by_name.insert(cmd.name.clone(), cmd);
+    }
+    return Ok(by_name.into_iter().map(|(_, cmd)| cmd).collect());
+}
+
+/// Every definition of a command name across repositories, ordered from
+/// the one that wins to the most shadowed one. Only names defined by more
+/// than one repository are returned.
+pub fn find_conflicts(loaded: &[LoadedRepository]) -> Vec<(String, Vec<(&Repository, &Cmd)>)> {
+    let mut definitions: BTreeMap<&str, Vec<(usize, &Repository, &Cmd)>> = BTreeMap::new();
+    for (position, repo) in loaded.iter().enumerate() {
+        for cmd in &repo.cmds {
+            definitions
+                .entry(&cmd.name)
+                .or_default()
+                .push((position, &repo.repository, cmd));
         }
     }
-    return Ok(all_cmds.values().cloned().collect());
+    return definitions
+        .into_iter()
+        .filter(|(_, defs)| defs.len() > 1)
+        .map(|(name, mut defs)| {
+            // highest priority first and, on ties, the last one listed
+            defs.sort_by(|(pos_a, repo_a, _), (pos_b, repo_b, _)| {
+                (repo_b.priority, pos_b).cmp(&(repo_a.priority, pos_a))
+            });
+            let defs = defs.into_iter().map(|(_, repo, cmd)| (repo, cmd)).collect();
+            (String::from(name), defs)
+        })
+        .collect();
+}
+
+/// Picks the command that wins for each name, sorted by name. Collisions
+/// that are not settled by priorities are reported, as the winner then
+/// depends on the order of the repositories in the config.
+pub fn resolve_commands(loaded: &[LoadedRepository]) -> Vec<Cmd> {
+    let conflicts = find_conflicts(loaded);
+    for (name, defs) in &conflicts {
+        let (winner, _) = defs[0];
+        if defs[1].0.priority == winner.priority {
+            eprintln!(
+                "{}: command {} is defined by several repositories, using the one from {}. Set their priorities to choose (see {})",
+                "Warning".yellow(),
+                name,
+                winner.alias().blue(),
+                "macondo repo conflicts".green()
+            );
+        }
+    }
+
+    let mut by_name: BTreeMap<&str, &Cmd> = BTreeMap::new();
+    for repo in loaded {
+        for cmd in &repo.cmds {
+            by_name.insert(&cmd.name, cmd);
+        }
+    }
+    for (name, defs) in &conflicts {
+        by_name.insert(name, defs[0].1);
+    }
+    return by_name.into_iter().map(|(_, cmd)| cmd.clone()).collect();
+}
+
+/// Finds a command by its qualified name, ALIAS/COMMAND, which reaches
+/// commands shadowed by other repositories
+pub fn find_qualified_command(name: &str, loaded: &[LoadedRepository]) -> Option<Cmd> {
+    let (alias, cmd_name) = name.split_once('/')?;
+    return loaded
+        .iter()
+        .rev()
+        .filter(|repo| repo.repository.alias() == alias)
+        .flat_map(|repo| repo.cmds.iter())
+        .find(|cmd| cmd.name == cmd_name)
+        .cloned();
 }
 
 fn load_commands_from_directory(dir: &Path) -> Result<Vec<Cmd>, Error> {
@@ -55,34 +161,52 @@ fn load_commands_from_directory(dir: &Path) -> Result<Vec<Cmd>, Error> {
 }
 
 fn find_all_command_files(dir: &Path) -> Vec<String> {
-    return WalkDir::new(dir)
+    return signature::directory_files(dir)
         .into_iter()
-        .filter_map(|e| e.ok())
         .filter(|e| e.path().to_str().unwrap().ends_with(".mcd"))
         .map(|e| String::from(e.path().to_str().unwrap()))
         .collect();
 }
 
-fn load_commands_from_manifest(manifest: &str) -> Result<Vec<Cmd>, Error> {
-    let mut cmds = Vec::new();
+/// Loads a repository manifest as-is, i.e. without processing its commands
+pub fn load_repo(manifest: &str) -> Result<Repo, Error> {
     let doc =
         fs::read_to_string(&manifest).context(format!("Could not load manifest {}", &manifest))?;
     let repo: Repo = serde_yaml::from_str(&doc).context(format!(
         "Could not parse manifest {}. Make sure it is a valid yaml document.",
         &manifest
     ))?;
+    return Ok(repo);
+}
+
+fn load_commands_from_manifest(manifest: &str) -> Result<Vec<Cmd>, Error> {
+    let mut cmds = Vec::new();
+    let repo = load_repo(manifest)?;
     for cmd in repo.commands {
-        cmds.push(Cmd {
-            registry: cmd.registry.replace("${version}", &cmd.version),
-            ..cmd
-        });
+        let registry = cmd.registry.replace("${version}", &cmd.version);
+        // digest-pinned images (repo@sha256:...) are run as-is, so make
+        // sure the digest is sane before anyone tries to run them
+        if let (_, Some(digest)) = docker::split_digest(&registry) {
+            if !docker::is_valid_digest(digest) {
+                bail!(
+                    "Command {} in manifest {} references an invalid image digest: {}",
+                    cmd.name,
+                    &manifest,
+                    registry
+                );
+            }
+        }
+        cmds.push(Cmd { registry, ..cmd });
+    }
+    for image in repo.images {
+        cmds.push(docker::import_command(&image)?);
     }
     return Ok(cmds);
 }
 
 /// Prints all available commands, grouping them according to their group key
 pub fn list_commands(commands: &Vec<Cmd>, show_version: bool) {
-    let mut groups = HashMap::new();
+    let mut groups = BTreeMap::new();
     for cmd in commands {
         let group_name = if cmd.group != "" { &cmd.group } else { "Other" };
         groups.entry(group_name).or_insert(Vec::new()).push((
@@ -92,6 +216,9 @@ pub fn list_commands(commands: &Vec<Cmd>, show_version: bool) {
         ));
     }
 
+    // general commands go first, then the groups in alphabetical order
+    let mut groups: Vec<(&str, Vec<_>)> = groups.into_iter().collect();
+    groups.sort_by_key(|(k, _)| *k != "Other");
     for (k, v) in &groups {
         if *k == "Other" {
             println!("General commands:\n");
@@ -114,9 +241,43 @@ pub fn list_commands(commands: &Vec<Cmd>, show_version: bool) {
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct Repo {
+    #[serde(default)]
     pub commands: Vec<Cmd>,
+    // References of images built by macondo, whose commands are fully
+    // described by the labels of the image (see `macondo import`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+}
+
+impl Repo {
+    /// Updates or inserts the provided commands.
+    ///
+    /// A command replaces every existing command with the same name, unless
+    /// `keep_versions` is set, in which case only the one with the same name
+    /// and version is replaced and other versions are kept. Replaced commands
+    /// keep their position; new ones are appended.
+    pub fn merge(&mut self, cmds: Vec<Cmd>, keep_versions: bool) {
+        for cmd in cmds {
+            let mut merged: Vec<Cmd> = Vec::new();
+            let mut inserted = false;
+            for existing in self.commands.drain(..) {
+                let is_replaced = existing.name == cmd.name
+                    && (!keep_versions || existing.version == cmd.version);
+                if !is_replaced {
+                    merged.push(existing);
+                } else if !inserted {
+                    merged.push(cmd.clone());
+                    inserted = true;
+                }
+            }
+            if !inserted {
+                merged.push(cmd);
+            }
+            self.commands = merged;
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -156,6 +317,30 @@ pub struct Cmd {
     // using the `--user` flag which has a higher precedence.
     #[serde(default = "to_true")]
     pub align_with_host_user: bool,
+    // Arguments passed to `docker build` via --build-arg (KEY=VALUE, or just
+    // KEY to forward the value from the host environment)
+    #[serde(default)]
+    pub build_args: Vec<String>,
+    // BuildKit secrets exposed to the build with --secret. Each one has the
+    // form id=ID,src=FILE or id=ID,env=VAR and is never baked into the image.
+    #[serde(default)]
+    pub build_secrets: Vec<String>,
+    // Extra packages installed on top of the base image, using the package
+    // manager detected in it unless `package_manager` says otherwise
+    #[serde(default)]
+    pub packages: Vec<String>,
+    pub package_manager: Option<String>,
+    // Helper libraries bundled with macondo that the command relies on,
+    // e.g. argsparse. They are copied to /NAME.sh in the command image.
+    #[serde(default)]
+    pub uses: Vec<String>,
+    // Globs (relative to the command file) of extra files, like helper
+    // modules or data files, that are copied into the command image
+    #[serde(default)]
+    pub includes: Vec<String>,
+    // Test cases declared with `@test` annotations (see `macondo test`)
+    #[serde(default)]
+    pub tests: Vec<testing::TestCase>,
     #[serde(default)]
     pub extra: HashMap<String, String>,
 }
@@ -173,3 +358,100 @@ fn to_true() -> bool {
 fn default_version() -> String {
     String::from("0.1.0")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn repo(url: &str, alias: Option<&str>, priority: i32, cmds: &[&str]) -> LoadedRepository {
+        let cmds = cmds
+            .iter()
+            .map(|name| Cmd {
+                name: String::from(*name),
+                registry: format!("{}/{}", url, name),
+                ..serde_yaml::from_str("{name: '', registry: '', volumes: []}").unwrap()
+            })
+            .collect();
+        return LoadedRepository {
+            repository: Repository {
+                alias: alias.map(String::from),
+                priority,
+                ..Repository::from(url)
+            },
+            cmds,
+        };
+    }
+
+    fn registries(cmds: &[Cmd]) -> Vec<&str> {
+        return cmds.iter().map(|cmd| cmd.registry.as_str()).collect();
+    }
+
+    #[test]
+    fn last_listed_wins_on_equal_priority() {
+        let loaded = vec![
+            repo("first", None, 0, &["ls", "cat"]),
+            repo("second", None, 0, &["ls"]),
+        ];
+        assert_eq!(
+            registries(&resolve_commands(&loaded)),
+            vec!["first/cat", "second/ls"]
+        );
+        let conflicts = find_conflicts(&loaded);
+        assert_eq!(conflicts.len(), 1);
+        let (name, defs) = &conflicts[0];
+        assert_eq!(name, "ls");
+        let urls: Vec<&str> = defs.iter().map(|(repo, _)| repo.url.as_str()).collect();
+        assert_eq!(urls, vec!["second", "first"]);
+    }
+
+    #[test]
+    fn higher_priority_wins_even_if_listed_first() {
+        let loaded = vec![
+            repo("first", None, 10, &["ls"]),
+            repo("second", None, 0, &["ls"]),
+            repo("third", None, -1, &["ls"]),
+        ];
+        assert_eq!(registries(&resolve_commands(&loaded)), vec!["first/ls"]);
+        let (_, defs) = &find_conflicts(&loaded)[0];
+        let urls: Vec<&str> = defs.iter().map(|(repo, _)| repo.url.as_str()).collect();
+        assert_eq!(urls, vec!["first", "second", "third"]);
+    }
+
+    #[test]
+    fn finds_shadowed_commands_by_qualified_name() {
+        let loaded = vec![
+            repo("https://example.com/team.yaml", Some("ops"), 0, &["ls"]),
+            repo("https://example.com/mine.yaml", None, 10, &["ls"]),
+        ];
+        assert_eq!(
+            registries(&resolve_commands(&loaded)),
+            vec!["https://example.com/mine.yaml/ls"]
+        );
+        let shadowed = find_qualified_command("ops/ls", &loaded).unwrap();
+        assert_eq!(shadowed.registry, "https://example.com/team.yaml/ls");
+        // the alias replaces the name derived from the URL
+        assert!(find_qualified_command("team/ls", &loaded).is_none());
+        let mine = find_qualified_command("mine/ls", &loaded).unwrap();
+        assert_eq!(mine.registry, "https://example.com/mine.yaml/ls");
+        assert!(find_qualified_command("ops/cat", &loaded).is_none());
+        assert!(find_qualified_command("ls", &loaded).is_none());
+    }
+
+    #[test]
+    fn command_files_skip_git_directories() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join("sub/.git")).unwrap();
+        fs::write(dir.path().join("sub/hello.mcd"), "").unwrap();
+        fs::write(dir.path().join("sub/.git/evil.mcd"), "").unwrap();
+        fs::write(dir.path().join("README.md"), "").unwrap();
+        assert_eq!(
+            find_all_command_files(dir.path()),
+            vec![dir
+                .path()
+                .join("sub/hello.mcd")
+                .to_string_lossy()
+                .to_string()]
+        );
+    }
+}
@@ -1,5 +1,8 @@
 use crate::cmd;
+use crate::docker::builders::{find_package_manager, PACKAGE_MANAGERS};
+use crate::docker::HELPERS;
 use crate::util::paths;
+use cmd::testing::{parse_test_annotation, TestCase};
 use cmd::Cmd;
 use easy_error::{bail, Error, ResultExt};
 use std::fs::File;
@@ -37,14 +40,22 @@ pub fn parse_command_file(file_path: &str) -> Result<Cmd, Error> {
     let mut enable_dynamic_volume_mounts = false;
     let mut needs_tty = false;
     let mut needs_ssh = false;
+    let mut build_args: Vec<String> = Vec::new();
+    let mut build_secrets: Vec<String> = Vec::new();
+    let mut packages: Vec<String> = Vec::new();
+    let mut package_manager: Option<String> = None;
+    let mut uses: Vec<String> = Vec::new();
+    let mut includes: Vec<String> = Vec::new();
+    let mut tests: Vec<TestCase> = Vec::new();
 
     let file =
         File::open(cmd_file).context(format!("Failed to load command file {}", file_path))?;
     let reader = BufReader::new(file);
     for (idx, line) in reader.lines().enumerate() {
         let line = line.context("Failed to read file")?;
-        if !line.starts_with("# @") {
-            // ignore non-annotation lines
+        if !line.starts_with("# @") && !line.starts_with("// @") {
+            // ignore non-annotation lines. Annotations are comments, either
+            // shell-like (#) or C-like (//) depending on the command language
             continue;
         }
 
@@ -66,6 +77,31 @@ pub fn parse_command_file(file_path: &str) -> Result<Cmd, Error> {
             "enable_dynamic_volume_mounts" => enable_dynamic_volume_mounts = value == "true",
             "vol" => volumes.push(value),
             "user" => user = value,
+            "build_arg" => build_args.push(value),
+            "build_secret" => build_secrets.push(value),
+            "uses" => {
+                for helper in value.split_whitespace() {
+                    if !HELPERS.iter().any(|(name, _)| name == &helper) {
+                        bail!("Unknown helper {} in @uses at line {}", helper, idx);
+                    }
+                    uses.push(String::from(helper));
+                }
+            }
+            "include" => includes.push(value),
+            "test" => tests.push(parse_test_annotation(&value)?),
+            "packages" => packages.extend(value.split_whitespace().map(String::from)),
+            "package_manager" => {
+                if find_package_manager(&value).is_none() {
+                    let supported: Vec<&str> = PACKAGE_MANAGERS.iter().map(|pm| pm.name).collect();
+                    bail!(
+                        "Unknown package manager {} at line {}. Supported: {}",
+                        value,
+                        idx,
+                        supported.join(", ")
+                    );
+                }
+                package_manager = Some(value)
+            }
             "from" => {
                 if value.starts_with("Dockerfile") {
                     let parent_folder = cmd_file.parent().unwrap().to_string_lossy();
@@ -116,13 +152,22 @@ pub fn parse_command_file(file_path: &str) -> Result<Cmd, Error> {
         needs_ssh,
         command_path,
         align_with_host_user,
+        build_args,
+        build_secrets,
+        packages,
+        package_manager,
+        uses,
+        includes,
+        tests,
     };
 
     return Ok(cmd);
 }
 
 fn parse_line(line: &str) -> Option<(String, String)> {
-    let annotation = line.replace("# @", "");
+    let annotation = line
+        .strip_prefix("# @")
+        .or_else(|| line.strip_prefix("// @"))?;
     let mut words = annotation.split_whitespace();
     if let Some(annotation_name) = words.next() {
         let rest: Vec<&str> = words.collect();
@@ -0,0 +1,146 @@
+use crate::cmd::Cmd;
+use easy_error::{bail, Error, ResultExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path};
+
+/// A test case of a command. They are declared with `@test` annotations,
+/// whose value is an inline YAML mapping, e.g.
+/// `# @test {args: [-l], stdout: {contains: [total]}}`, or in a
+/// `NAME.tests.yaml` file next to the command with a list of them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestCase {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    // Written to the standard input of the command
+    pub stdin: Option<String>,
+    // Files (path relative to the scratch directory => contents)
+    // created before running the command
+    #[serde(default)]
+    pub fixtures: BTreeMap<String, String>,
+    #[serde(default)]
+    pub exit_code: i32,
+    #[serde(default)]
+    pub stdout: OutputMatcher,
+    #[serde(default)]
+    pub stderr: OutputMatcher,
+}
+
+/// Expectations on an output of a command
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputMatcher {
+    // The whole output, ignoring leading and trailing whitespace
+    pub equals: Option<String>,
+    #[serde(default)]
+    pub contains: Vec<String>,
+    #[serde(default)]
+    pub not_contains: Vec<String>,
+}
+
+/// Parses the value of a `@test` annotation
+pub fn parse_test_annotation(value: &str) -> Result<TestCase, Error> {
+    return Ok(serde_yaml::from_str(value).context(format!("Invalid @test {}", value))?);
+}
+
+/// Returns the test cases of a command: the ones from its `@test`
+/// annotations followed by the ones from its `NAME.tests.yaml` file, if any
+pub fn load_test_cases(cmd: &Cmd) -> Result<Vec<TestCase>, Error> {
+    let mut cases = cmd.tests.clone();
+    if let Some(command_path) = &cmd.command_path {
+        let tests_file = Path::new(command_path).with_file_name(format!("{}.tests.yaml", cmd.name));
+        if tests_file.exists() {
+            let doc = fs::read_to_string(&tests_file)
+                .context(format!("Failed to read {}", tests_file.display()))?;
+            let from_file: Vec<TestCase> = serde_yaml::from_str(&doc)
+                .context(format!("Failed to parse {}", tests_file.display()))?;
+            cases.extend(from_file);
+        }
+    }
+    for case in &mut cases {
+        if case.name.is_empty() {
+            case.name = if case.args.is_empty() {
+                String::from("no arguments")
+            } else {
+                case.args.join(" ")
+            };
+        }
+    }
+    return Ok(cases);
+}
+
+impl TestCase {
+    /// Creates the fixtures of the test case in the provided directory
+    pub fn write_fixtures(&self, dir: &Path) -> Result<(), Error> {
+        for (path, contents) in &self.fixtures {
+            if Path::new(path)
+                .components()
+                .any(|c| c == Component::ParentDir || c == Component::RootDir)
+            {
+                bail!(
+                    "Invalid fixture {} in test {}: fixtures must live in the scratch directory",
+                    path,
+                    self.name
+                );
+            }
+            let file = dir.join(path);
+            if let Some(parent) = file.parent() {
+                fs::create_dir_all(parent)
+                    .context(format!("Failed to create {}", parent.display()))?;
+            }
+            fs::write(&file, contents).context(format!("Failed to write fixture {}", path))?;
+        }
+        return Ok(());
+    }
+
+    /// Returns the expectations that the outcome of the command did not meet
+    pub fn check(&self, exit_code: i32, stdout: &str, stderr: &str) -> Vec<String> {
+        let mut failures = Vec::new();
+        if exit_code != self.exit_code {
+            failures.push(format!(
+                "expected exit code {}, got {}",
+                self.exit_code, exit_code
+            ));
+        }
+        failures.extend(self.stdout.check("stdout", stdout));
+        failures.extend(self.stderr.check("stderr", stderr));
+        return failures;
+    }
+}
+
+impl OutputMatcher {
+    fn check(&self, output_name: &str, output: &str) -> Vec<String> {
+        let mut failures = Vec::new();
+        if let Some(expected) = &self.equals {
+            if expected.trim() != output.trim() {
+                failures.push(format!(
+                    "expected {} to be {:?}, got {:?}",
+                    output_name,
+                    expected.trim(),
+                    output.trim()
+                ));
+            }
+        }
+        for expected in &self.contains {
+            if !output.contains(expected.as_str()) {
+                failures.push(format!(
+                    "expected {} to contain {:?}",
+                    output_name, expected
+                ));
+            }
+        }
+        for unexpected in &self.not_contains {
+            if output.contains(unexpected.as_str()) {
+                failures.push(format!(
+                    "expected {} not to contain {:?}",
+                    output_name, unexpected
+                ));
+            }
+        }
+        return failures;
+    }
+}
@@ -1,13 +1,53 @@
-use crate::util::paths;
+use crate::util::hash::sha256_hex;
+use crate::util::{paths, prompt};
 use colored::*;
 use easy_error::{Error, ResultExt};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::env::current_dir;
 use std::fs;
 use std::fs::File;
 use std::io::prelude::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+/// Name of the project config files, searched from the working directory up
+const PROJECT_CONFIG_FILE: &str = ".macondo.yaml";
+/// Set to 1 to use untrusted project configs without asking, e.g. in CI
+const TRUST_PROJECT_ENV: &str = "MACONDO_TRUST_PROJECT";
+/// Set to 1 to only run what the lockfile allows (see `MacondoConfig::locked`)
+pub const LOCKED_ENV: &str = "MACONDO_LOCKED";
+/// Name of the lockfile written by `macondo lock`
+const LOCKFILE: &str = ".macondo.lock";
+
+/// Loads the global config merged with the config of the project the
+/// working directory belongs to, if any (see `find_project_config`)
 pub fn load_config() -> Result<MacondoConfig, Error> {
+    let mut config = load_global_config()?;
+    if env::var(LOCKED_ENV).map_or(false, |value| value == "1") {
+        config.locked = true;
+    }
+    let project_file = match find_project_config(&current_dir().unwrap()) {
+        Some(project_file) => project_file,
+        None => return Ok(config),
+    };
+    let project_file = project_file.to_string_lossy().to_string();
+    let doc = fs::read_to_string(&project_file)
+        .context(format!("Failed to load project config {}", project_file))?;
+    let project: ProjectConfig = serde_yaml::from_str(&doc).context(format!(
+        "Failed to parse project config {} as YAML",
+        project_file
+    ))?;
+    if !is_project_trusted(&project_file, &doc, &project, &mut config)? {
+        return Ok(config);
+    }
+    config.merge_project(&project_file, project);
+    return Ok(config);
+}
+
+/// Loads ~/.macondo only. This is the config to modify and save, as it
+/// doesn't include the settings of the current project.
+pub fn load_global_config() -> Result<MacondoConfig, Error> {
     // ensure a config file exists
     let config_filename = config_file_path();
     let config_file = Path::new(&config_filename);
@@ -25,9 +65,7 @@ pub fn load_config() -> Result<MacondoConfig, Error> {
             .context("Failed to parse ~/.macondo config file as YAML");
     }
 
-    let config = MacondoConfig {
-        repositories: Vec::new(),
-    };
+    let config = MacondoConfig::default();
     save_config(&config)?;
 
     let tool_name = app_name();
@@ -51,6 +89,92 @@ pub fn save_config(config: &MacondoConfig) -> Result<(), Error> {
         .context("Failed to write config to ~/.macondo");
 }
 
+/// Finds the closest project config, looking in `dir` and its ancestors
+pub fn find_project_config(dir: &Path) -> Option<PathBuf> {
+    return dir
+        .ancestors()
+        .map(|ancestor| ancestor.join(PROJECT_CONFIG_FILE))
+        .find(|candidate| candidate.is_file());
+}
+
+/// Project configs can add repositories, whose commands run with access to
+/// the user's files, so they are only used once the user trusts them. Trust
+/// is tied to the contents of the file: any change asks again.
+fn is_project_trusted(
+    project_file: &str,
+    doc: &str,
+    project: &ProjectConfig,
+    config: &mut MacondoConfig,
+) -> Result<bool, Error> {
+    let hash = sha256_hex(doc.as_bytes());
+    let known = config.project_configs.get(project_file);
+    match known {
+        Some(known) if known.hash == hash => {
+            if !known.trusted {
+                eprintln!(
+                    "{}: ignoring untrusted project config {}. Remove it from the project_configs of ~/.macondo to be asked again",
+                    "Warning".yellow(),
+                    project_file
+                );
+            }
+            return Ok(known.trusted);
+        }
+        _ => (),
+    }
+    if env::var(TRUST_PROJECT_ENV).map_or(false, |value| value == "1") {
+        return Ok(true);
+    }
+    if !prompt::is_interactive() {
+        eprintln!(
+            "{}: ignoring project config {}, as it has not been trusted yet. Run macondo from a terminal to review it, or set {}=1",
+            "Warning".yellow(),
+            project_file,
+            TRUST_PROJECT_ENV
+        );
+        return Ok(false);
+    }
+
+    eprintln!(
+        "{} project config {}",
+        if known.is_some() { "Changed" } else { "Found" },
+        project_file.blue()
+    );
+    if !project.repositories.is_empty() {
+        eprintln!("It adds the following repositories, whose commands will be able to run:");
+        let repositories = serde_yaml::to_string(&project.repositories)
+            .context("Failed to serialize the repositories of the project")?;
+        for line in repositories.lines().filter(|line| *line != "---") {
+            eprintln!("  {}", line);
+        }
+        if project.repositories.iter().any(|repo| repo.insecure) {
+            eprintln!(
+                "{}: TLS certificates are not verified for the insecure ones. Anyone on the network path can tamper with their commands",
+                "WARNING".red().bold()
+            );
+        }
+    }
+    if !project.base_image_pins.is_empty() {
+        eprintln!("It pins the base images of the following builders:");
+        let pins: BTreeMap<&String, &String> = project.base_image_pins.iter().collect();
+        for (builder, pin) in pins {
+            eprintln!("  {}: {}", builder, pin);
+        }
+    }
+    let trusted = prompt::confirm("Do you trust it?", false)?;
+    // the global config is saved as loaded, without any project settings
+    let mut global = load_global_config()?;
+    global.project_configs.insert(
+        String::from(project_file),
+        ProjectTrust {
+            hash: hash.clone(),
+            trusted,
+        },
+    );
+    save_config(&global)?;
+    config.project_configs = global.project_configs;
+    return Ok(trusted);
+}
+
 fn app_name() -> String {
     let tool_name = std::env::current_exe().unwrap();
     let tool_name = tool_name.file_name().unwrap();
@@ -61,8 +185,288 @@ fn config_file_path() -> String {
     format!("{}/.macondo", paths::home_dir())
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct MacondoConfig {
     #[serde(default)]
-    pub repositories: Vec<String>,
+    pub repositories: Vec<Repository>,
+    // Default tag or digest of the base image of package-based builders,
+    // keyed by builder name (e.g. AlpinePackages: "3.18"). A pin in the
+    // command's @from annotation takes precedence.
+    #[serde(default)]
+    pub base_image_pins: HashMap<String, String>,
+    // How long cached HTTP repositories are used before checking whether
+    // they changed, e.g. 12h or 7d. Forever, if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_ttl: Option<String>,
+    // Proxy and CA settings for fetching HTTP repositories
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http: Option<HttpSettings>,
+    // Whether the user trusts each project config (keyed by path) they have
+    // been asked about, along with a hash of the contents they reviewed
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub project_configs: BTreeMap<String, ProjectTrust>,
+    // Whether commands can only be loaded and run as recorded in the
+    // lockfile (see `macondo lock`)
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub locked: bool,
+    // Project config merged into this one, if any
+    #[serde(skip)]
+    pub project_config: Option<String>,
+}
+
+impl MacondoConfig {
+    /// Adds the settings of a project config on top of these: its
+    /// repositories go last, so they win ties, and its pins take precedence.
+    /// Relative paths of repositories are resolved against the project config.
+    fn merge_project(&mut self, project_file: &str, project: ProjectConfig) {
+        let project_dir = Path::new(project_file).parent().unwrap();
+        for repo in project.repositories {
+            let repo = Repository {
+                url: resolve_relative_url(&repo.url, project_dir),
+                ..repo
+            };
+            if !self.repositories.iter().any(|known| known.url == repo.url) {
+                self.repositories.push(repo);
+            }
+        }
+        self.base_image_pins.extend(project.base_image_pins);
+        self.locked |= project.locked;
+        self.project_config = Some(String::from(project_file));
+    }
+
+    /// The lockfile lives next to the project config, if any, so that it
+    /// can be committed along with it. Otherwise it lives in the home.
+    pub fn lockfile_path(&self) -> String {
+        return match &self.project_config {
+            Some(project_config) => Path::new(project_config)
+                .with_file_name(LOCKFILE)
+                .to_string_lossy()
+                .to_string(),
+            None => format!("{}/{}", paths::home_dir(), LOCKFILE),
+        };
+    }
+}
+
+/// Settings that a project can ship along with its code, in a
+/// `.macondo.yaml` file at its root (or any directory above where macondo
+/// runs)
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    #[serde(default)]
+    pub repositories: Vec<Repository>,
+    #[serde(default)]
+    pub base_image_pins: HashMap<String, String>,
+    // Makes everyone working on the project run the locked commands
+    #[serde(default)]
+    pub locked: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProjectTrust {
+    pub hash: String,
+    pub trusted: bool,
+}
+
+/// Local paths of repositories that are neither absolute nor relative to
+/// the home or working directory are relative to `dir`
+fn resolve_relative_url(url: &str, dir: &Path) -> String {
+    let is_remote = url.contains("://") || url.starts_with("git+");
+    let is_anchored = url.starts_with('/') || url.starts_with('~') || url.starts_with("PWD");
+    if is_remote || is_anchored {
+        return String::from(url);
+    }
+    return dir.join(url).to_string_lossy().to_string();
+}
+
+/// How to reach HTTP repositories from restricted networks. Each setting
+/// falls back to the environment variable that usually carries it.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpSettings {
+    // Proxy for HTTP and HTTPS requests. Defaults to HTTP_PROXY and
+    // HTTPS_PROXY, respectively
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    // Comma separated hosts and domains (.example.com) reached without the
+    // proxy, or * for all. Defaults to NO_PROXY
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub no_proxy: Option<String>,
+    // PEM file with root certificates trusted on top of the system ones.
+    // Defaults to MACONDO_CA_FILE
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<String>,
+}
+
+/// A command repository listed in the config. It is either just its URL or
+/// a mapping with the URL and, optionally, an alias, a priority and the
+/// minisign public key its contents must be signed with:
+///
+/// ```yaml
+/// repositories:
+///   - ~/my-commands
+///   - url: https://example.com/commands.yaml
+///     alias: team
+///     priority: 10
+///     key: RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3
+///     auth:
+///       token_env: ARTIFACTORY_TOKEN
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "RepositoryEntry", into = "RepositoryEntry")]
+pub struct Repository {
+    pub url: String,
+    // Name used to reach its commands as ALIAS/COMMAND. Derived from the URL
+    // when not set
+    pub alias: Option<String>,
+    // When several repositories define a command with the same name, the one
+    // with the highest priority wins. On ties, the last one listed wins.
+    pub priority: i32,
+    // Base64 minisign public key. When set, commands are only loaded from
+    // contents signed with it
+    pub key: Option<String>,
+    // Where to find the credentials of an HTTP repository
+    pub auth: Option<RepositoryAuth>,
+    // Skips the verification of TLS certificates. Only meant as a last
+    // resort, as anyone on the network path can then tamper with commands
+    pub insecure: bool,
+}
+
+/// Where to find the credentials of an HTTP repository. Only references to
+/// them (environment variables and files) are stored in the config, never
+/// the credentials themselves. Without any, those of ~/.netrc are used.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RepositoryAuth {
+    // Bearer token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_env: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_file: Option<String>,
+    // Basic auth
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_env: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<String>,
+    // PKCS#12 bundle with a client certificate and its key, for mutual TLS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cert_password_env: Option<String>,
+}
+
+impl Repository {
+    /// The explicit alias of the repository, or the last segment of its URL
+    /// without extension (e.g. `commands` for `git+https://host/commands.git`)
+    pub fn alias(&self) -> String {
+        if let Some(alias) = &self.alias {
+            return alias.clone();
+        }
+        let url = self.url.trim_start_matches("git+");
+        let url = url.split('#').next().unwrap_or(url).trim_end_matches('/');
+        let name = url.rsplit('/').next().unwrap_or(url);
+        let name = [".git", ".yaml", ".yml", ".mcd"]
+            .iter()
+            .fold(name, |name, extension| name.trim_end_matches(extension));
+        return if name.is_empty() || name == "~" {
+            String::from("repo")
+        } else {
+            String::from(name)
+        };
+    }
+}
+
+impl From<String> for Repository {
+    fn from(url: String) -> Self {
+        return Repository {
+            url,
+            alias: None,
+            priority: 0,
+            key: None,
+            auth: None,
+            insecure: false,
+        };
+    }
+}
+
+impl From<&str> for Repository {
+    fn from(url: &str) -> Self {
+        return Repository::from(String::from(url));
+    }
+}
+
+/// How a repository is written in the config
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RepositoryEntry {
+    Url(String),
+    Detailed {
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        alias: Option<String>,
+        #[serde(default, skip_serializing_if = "is_zero")]
+        priority: i32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        auth: Option<RepositoryAuth>,
+        #[serde(default, skip_serializing_if = "is_false")]
+        insecure: bool,
+    },
+}
+
+impl From<RepositoryEntry> for Repository {
+    fn from(entry: RepositoryEntry) -> Self {
+        return match entry {
+            RepositoryEntry::Url(url) => Repository::from(url),
+            RepositoryEntry::Detailed {
+                url,
+                alias,
+                priority,
+                key,
+                auth,
+                insecure,
+            } => Repository {
+                url,
+                alias,
+                priority,
+                key,
+                auth,
+                insecure,
+            },
+        };
+    }
+}
+
+impl From<Repository> for RepositoryEntry {
+    fn from(repository: Repository) -> Self {
+        // plain URLs are kept as such, so that existing configs don't change
+        return if repository.alias.is_none()
+            && repository.priority == 0
+            && repository.key.is_none()
+            && repository.auth.is_none()
+            && !repository.insecure
+        {
+            RepositoryEntry::Url(repository.url)
+        } else {
+            RepositoryEntry::Detailed {
+                url: repository.url,
+                alias: repository.alias,
+                priority: repository.priority,
+                key: repository.key,
+                auth: repository.auth,
+                insecure: repository.insecure,
+            }
+        };
+    }
+}
+
+fn is_zero(value: &i32) -> bool {
+    *value == 0
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
 }
@@ -0,0 +1,10 @@
+# Vendored copy of argsparse.sh from https://github.com/Anvil/bash-argsparse
+# (WTFPL). It is embedded into the macondo binary and copied into the images
+# of commands that declare `@uses argsparse`, so that builds work offline.
+#
+# Replace this file with the argsparse.sh of a release tag of the repository
+# above (not of its master branch), keeping its license header, and rebuild
+# macondo. Until then, sourcing it fails instead of leaving the command with
+# undefined argsparse functions.
+echo "argsparse.sh is not vendored in this build of macondo (src/docker/argsparse.sh)" >&2
+return 1 2>/dev/null || exit 1
@@ -0,0 +1,325 @@
+use crate::export::sh::sh_quote;
+use easy_error::{bail, Error};
+
+/// A builder knows how to produce the Dockerfile instructions of an image
+/// that has a list of packages installed. Commands select a builder with
+/// the `@from` annotation, e.g. `@from AlpinePackages jq curl`.
+///
+/// The base image can be pinned to a tag or digest with `Name@tag`
+/// (e.g. `AlpinePackages@3.18` or `UbuntuPackages@jammy`), and packages
+/// can be pinned to a version with `package=version`.
+pub struct PackageBuilder {
+    /// Name used in the `@from` annotation
+    pub name: &'static str,
+    /// Image the command image is built from, unless pinned to something else
+    pub base_image: &'static str,
+    /// How a package pinned to a version is spelled for this package manager.
+    /// `{name}` and `{version}` are replaced. None if pinning is unsupported.
+    pub pinned_package: Option<&'static str>,
+    /// Dockerfile instructions that create the image. `{base_image}` and
+    /// `{packages}` are replaced with the actual values.
+    pub template: &'static str,
+}
+
+const BUILDERS: &[PackageBuilder] = &[
+    PackageBuilder {
+        name: "AlpinePackages",
+        base_image: "alpine:3.20",
+        pinned_package: Some("{name}={version}"),
+        template: "FROM {base_image}
+RUN apk --no-cache add bash {packages}",
+    },
+    PackageBuilder {
+        name: "UbuntuPackages",
+        base_image: "ubuntu:22.04",
+        pinned_package: Some("{name}={version}"),
+        template: "FROM {base_image}
+RUN apt-get update && apt-get install -y bash {packages} && rm -rf /var/lib/apt/lists/* && ln -sv /usr/games/* /usr/bin/ || exit 0",
+    },
+    PackageBuilder {
+        name: "DebianPackages",
+        base_image: "debian:bookworm-slim",
+        pinned_package: Some("{name}={version}"),
+        template: "FROM {base_image}
+RUN apt-get update && apt-get install -y --no-install-recommends bash {packages} && rm -rf /var/lib/apt/lists/*",
+    },
+    PackageBuilder {
+        name: "FedoraPackages",
+        base_image: "fedora:40",
+        pinned_package: Some("{name}-{version}"),
+        template: "FROM {base_image}
+RUN dnf install -y bash {packages} && dnf clean all",
+    },
+    PackageBuilder {
+        name: "ArchPackages",
+        base_image: "archlinux:base",
+        pinned_package: None,
+        template: "FROM {base_image}
+RUN pacman -Syu --noconfirm bash {packages} && pacman -Scc --noconfirm",
+    },
+    PackageBuilder {
+        name: "PipPackages",
+        base_image: "python:3.12-slim",
+        pinned_package: Some("{name}=={version}"),
+        template: "FROM {base_image}
+RUN pip install --no-cache-dir {packages}",
+    },
+    PackageBuilder {
+        name: "NpmPackages",
+        base_image: "node:20-slim",
+        pinned_package: Some("{name}@{version}"),
+        template: "FROM {base_image}
+RUN npm install --global {packages} && npm cache clean --force",
+    },
+    PackageBuilder {
+        // crates are compiled in a throwaway stage so that the toolchain
+        // does not end up in the command image. The stage starts from the
+        // base image too, so that binaries link against the same glibc.
+        name: "CargoInstall",
+        base_image: "debian:bookworm-slim",
+        pinned_package: Some("{name}@{version}"),
+        template: "FROM {base_image} AS cargo-install
+RUN apt-get update && apt-get install -y --no-install-recommends ca-certificates curl gcc libc6-dev && rm -rf /var/lib/apt/lists/*
+RUN curl --proto '=https' --tlsv1.2 -sSf https://sh.rustup.rs | sh -s -- -y --no-modify-path --profile minimal --default-toolchain 1.79.0
+RUN /root/.cargo/bin/cargo install --root /opt/cargo {packages}
+FROM {base_image}
+COPY --from=cargo-install /opt/cargo/bin/ /usr/local/bin/",
+    },
+    PackageBuilder {
+        name: "GemPackages",
+        base_image: "ruby:3.3-slim",
+        pinned_package: Some("{name}:{version}"),
+        template: "FROM {base_image}
+RUN gem install --no-document {packages}",
+    },
+];
+
+/// A builder reference parsed out of a `@from` value
+pub struct BuilderReference {
+    pub builder: &'static PackageBuilder,
+    /// Tag or digest the base image is pinned to, if any
+    pub pin: Option<String>,
+    pub packages: Vec<String>,
+}
+
+/// Returns the builder referenced by a `@from` value (if any) along with
+/// the base image pin and the list of packages it should install
+pub fn find_builder(from: &str) -> Option<BuilderReference> {
+    let mut words = from.split_whitespace();
+    let mut name_and_pin = words.next()?.splitn(2, '@');
+    let name = name_and_pin.next()?;
+    let pin = name_and_pin.next().map(String::from);
+    let builder = BUILDERS.iter().find(|builder| builder.name == name)?;
+    let packages = words.map(String::from).collect();
+    return Some(BuilderReference {
+        builder,
+        pin,
+        packages,
+    });
+}
+
+impl PackageBuilder {
+    /// Returns the base image pinned to the provided tag or digest.
+    /// Without a pin, the builder's default base image is used.
+    pub fn base_image(&self, pin: Option<&str>) -> String {
+        let repository = match self.base_image.rfind(':') {
+            Some(idx) => &self.base_image[..idx],
+            None => self.base_image,
+        };
+        return match pin {
+            Some(digest) if digest.starts_with("sha256:") => format!("{}@{}", repository, digest),
+            Some(tag) => format!("{}:{}", repository, tag),
+            None => String::from(self.base_image),
+        };
+    }
+
+    /// Renders the Dockerfile instructions that install the provided packages
+    /// on top of `base_image`
+    pub fn render(&self, base_image: &str, packages: &[String]) -> Result<String, Error> {
+        let mut install: Vec<String> = Vec::new();
+        for package in packages {
+            install.push(package_spec(self.name, self.pinned_package, package)?);
+        }
+        return Ok(self
+            .template
+            .replace("{base_image}", base_image)
+            .replace("{packages}", &install.join(" ")));
+    }
+}
+
+/// A package manager that can be used to install the packages listed in
+/// the `@packages` annotation on top of an arbitrary base image
+pub struct PackageManager {
+    /// Name used in the `@package_manager` annotation
+    pub name: &'static str,
+    /// Binary whose presence in the base image reveals the package manager
+    pub binary: &'static str,
+    /// Dockerfile instructions that install `{packages}`
+    pub install: &'static str,
+    /// Same as `PackageBuilder::pinned_package`
+    pub pinned_package: Option<&'static str>,
+}
+
+/// Known package managers, in the order they are probed
+pub const PACKAGE_MANAGERS: &[PackageManager] = &[
+    PackageManager {
+        name: "apk",
+        binary: "apk",
+        install: "RUN apk --no-cache add {packages}",
+        pinned_package: Some("{name}={version}"),
+    },
+    PackageManager {
+        name: "apt",
+        binary: "apt-get",
+        install: "RUN apt-get update && apt-get install -y --no-install-recommends {packages} && rm -rf /var/lib/apt/lists/*",
+        pinned_package: Some("{name}={version}"),
+    },
+    PackageManager {
+        name: "dnf",
+        binary: "dnf",
+        install: "RUN dnf install -y {packages} && dnf clean all",
+        pinned_package: Some("{name}-{version}"),
+    },
+    PackageManager {
+        name: "microdnf",
+        binary: "microdnf",
+        install: "RUN microdnf install -y {packages} && microdnf clean all",
+        pinned_package: Some("{name}-{version}"),
+    },
+    PackageManager {
+        name: "pacman",
+        binary: "pacman",
+        install: "RUN pacman -Sy --noconfirm {packages} && pacman -Scc --noconfirm",
+        pinned_package: None,
+    },
+];
+
+/// Returns the package manager with the provided name (e.g. apt)
+pub fn find_package_manager(name: &str) -> Option<&'static PackageManager> {
+    return PACKAGE_MANAGERS.iter().find(|pm| pm.name == name);
+}
+
+impl PackageManager {
+    /// Renders the Dockerfile instructions that install the provided packages.
+    /// They run as root, switching back to `user`, the user of the base
+    /// image, afterwards.
+    pub fn render(&self, packages: &[String], user: &str) -> Result<String, Error> {
+        let mut install: Vec<String> = Vec::new();
+        for package in packages {
+            install.push(package_spec(self.name, self.pinned_package, package)?);
+        }
+        let install = self.install.replace("{packages}", &install.join(" "));
+        return Ok(match user {
+            "" | "root" | "0" | "0:0" => install,
+            user => format!("USER root\n{}\nUSER {}", install, user),
+        });
+    }
+}
+
+/// Translates a bare `name=version` into the package manager's own syntax
+/// and quotes the result for the shell. Anything else, e.g. `requests>=2` or
+/// a spec already in the package manager's syntax, is passed through as is.
+fn package_spec(
+    installer: &str,
+    pinned_package: Option<&str>,
+    package: &str,
+) -> Result<String, Error> {
+    let pinned = match package.split_once('=') {
+        Some((name, version))
+            if !name.is_empty()
+                && !name.contains(|c| "<>!~=".contains(c))
+                && !version.starts_with('=') =>
+        {
+            Some((name, version))
+        }
+        _ => None,
+    };
+    let spec = match (pinned, pinned_package) {
+        (None, _) => String::from(package),
+        (Some((name, version)), Some(pinned_package)) => pinned_package
+            .replace("{name}", name)
+            .replace("{version}", version),
+        (Some(_), None) => bail!(
+            "{} does not support version-pinned packages ({})",
+            installer,
+            package
+        ),
+    };
+    return Ok(sh_quote(&spec));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translates_bare_pins() {
+        let spec = package_spec("dnf", Some("{name}-{version}"), "jq=1.6");
+        assert_eq!(spec.unwrap(), "'jq-1.6'");
+        let spec = package_spec("npm", Some("{name}@{version}"), "typescript=5.4.5");
+        assert_eq!(spec.unwrap(), "'typescript@5.4.5'");
+    }
+
+    #[test]
+    fn passes_other_specs_through() {
+        let pip = Some("{name}=={version}");
+        for package in &[
+            "requests>=2",
+            "pkg~=1.0",
+            "pkg!=1.0",
+            "pkg<=1",
+            "pkg==1.0",
+            "jq",
+        ] {
+            let spec = package_spec("pip", pip, package).unwrap();
+            assert_eq!(spec, format!("'{}'", package));
+        }
+    }
+
+    #[test]
+    fn quotes_specs() {
+        let spec = package_spec("pip", Some("{name}=={version}"), "requests[socks]>=2,<3");
+        assert_eq!(spec.unwrap(), "'requests[socks]>=2,<3'");
+        let spec = package_spec("apk", Some("{name}={version}"), "it's");
+        assert_eq!(spec.unwrap(), "'it'\\''s'");
+    }
+
+    #[test]
+    fn installs_packages_as_root() {
+        let apk = find_package_manager("apk").unwrap();
+        let packages = vec![String::from("jq")];
+        assert_eq!(
+            apk.render(&packages, "").unwrap(),
+            "RUN apk --no-cache add 'jq'"
+        );
+        assert_eq!(
+            apk.render(&packages, "app:app").unwrap(),
+            "USER root\nRUN apk --no-cache add 'jq'\nUSER app:app"
+        );
+    }
+
+    #[test]
+    fn compiles_crates_on_the_base_image() {
+        let cargo = find_builder("CargoInstall@bullseye-slim ripgrep").unwrap();
+        let base_image = cargo.builder.base_image(cargo.pin.as_deref());
+        assert_eq!(base_image, "debian:bullseye-slim");
+        let dockerfile = cargo.builder.render(&base_image, &cargo.packages).unwrap();
+        let stages: Vec<&str> = dockerfile
+            .lines()
+            .filter(|line| line.starts_with("FROM "))
+            .collect();
+        assert_eq!(
+            stages,
+            vec![
+                "FROM debian:bullseye-slim AS cargo-install",
+                "FROM debian:bullseye-slim"
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_pins_without_support() {
+        assert!(package_spec("pacman", None, "jq=1.6").is_err());
+        assert!(package_spec("pacman", None, "jq").is_ok());
+    }
+}
@@ -0,0 +1,116 @@
+use easy_error::{bail, Error, ResultExt};
+use glob::Pattern;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Resolves the `@include` globs of a command, relative to the directory
+/// of the command, into the list of files (also relative to that directory)
+/// that must be part of its build context. Directories are included
+/// recursively, and files listed in the .dockerignore of the directory of
+/// the command are skipped.
+pub fn resolve_includes(command_dir: &Path, patterns: &[String]) -> Result<Vec<PathBuf>, Error> {
+    let docker_ignore = DockerIgnore::load(command_dir)?;
+    let mut files: Vec<PathBuf> = Vec::new();
+    for pattern in patterns {
+        if Path::new(pattern)
+            .components()
+            .any(|c| c == Component::ParentDir || c == Component::RootDir)
+        {
+            bail!(
+                "Invalid @include {}: included files must live next to the command",
+                pattern
+            );
+        }
+
+        let full_pattern = command_dir.join(pattern);
+        let matches = glob::glob(&full_pattern.to_string_lossy())
+            .context(format!("Invalid @include pattern {}", pattern))?;
+        let mut matched_any = false;
+        for entry in matches {
+            let entry = entry.context(format!("Failed to resolve @include {}", pattern))?;
+            for file in WalkDir::new(&entry).into_iter().filter_map(|e| e.ok()) {
+                if file.file_type().is_file() {
+                    let relative = file.path().strip_prefix(command_dir).unwrap();
+                    if !docker_ignore.is_ignored(relative) && !files.iter().any(|f| f == relative) {
+                        files.push(relative.to_path_buf());
+                    }
+                }
+            }
+            matched_any = true;
+        }
+        if !matched_any {
+            bail!("@include {} did not match any file", pattern);
+        }
+    }
+    return Ok(files);
+}
+
+/// Copies the provided files (relative to `from`) into `to`,
+/// keeping their relative paths
+pub fn copy_files(from: &Path, to: &Path, files: &[PathBuf]) -> Result<(), Error> {
+    for file in files {
+        let target = to.join(file);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)
+                .context(format!("Failed to create {}", parent.display()))?;
+        }
+        fs::copy(from.join(file), &target).context(format!(
+            "Failed to copy {} to the build context",
+            file.display()
+        ))?;
+    }
+    return Ok(());
+}
+
+/// Patterns of a .dockerignore file
+pub struct DockerIgnore {
+    // (pattern, is_exception) in file order; the last matching one wins
+    patterns: Vec<(Pattern, bool)>,
+}
+
+impl DockerIgnore {
+    /// Loads the .dockerignore file of the provided directory, if any
+    pub fn load(dir: &Path) -> Result<DockerIgnore, Error> {
+        let mut patterns = Vec::new();
+        let file = dir.join(".dockerignore");
+        if file.exists() {
+            let contents = fs::read_to_string(&file)
+                .context(format!("Failed to read {}", file.display()))?;
+            for line in contents.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let (line, is_exception) = match line.strip_prefix('!') {
+                    Some(rest) => (rest.trim(), true),
+                    None => (line, false),
+                };
+                let line = line.trim_start_matches('/').trim_end_matches('/');
+                let pattern = Pattern::new(line).context(format!(
+                    "Invalid pattern '{}' in {}",
+                    line,
+                    file.display()
+                ))?;
+                patterns.push((pattern, is_exception));
+            }
+        }
+        return Ok(DockerIgnore { patterns });
+    }
+
+    /// Whether the provided path (relative to the context) is ignored.
+    /// A pattern that matches a directory also matches everything in it.
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let mut ignored = false;
+        for (pattern, is_exception) in &self.patterns {
+            let matches = path
+                .ancestors()
+                .filter(|p| !p.as_os_str().is_empty())
+                .any(|p| pattern.matches_path(p));
+            if matches {
+                ignored = !is_exception;
+            }
+        }
+        return ignored;
+    }
+}
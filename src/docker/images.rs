@@ -0,0 +1,212 @@
+use super::{COMMAND_NAME_LABEL, HASH_LABEL, KIND_LABEL, MANAGED_LABEL};
+use crate::exec;
+use easy_error::{bail, Error, ResultExt};
+
+/// A local image created by macondo
+pub struct ManagedImage {
+    pub id: String,
+    pub tags: Vec<String>,
+    pub digests: Vec<String>,
+    // Empty for images that are not labeled with a command
+    pub command: String,
+    pub hash: String,
+    pub kind: String,
+    // Unix timestamp (seconds)
+    pub created: u64,
+    pub size: u64,
+    // Layers of the image, which start with the ones of its parent
+    pub layers: Vec<String>,
+}
+
+/// Lists the local images that carry the label macondo puts in all the
+/// images it creates
+pub fn list_managed_images() -> Result<Vec<ManagedImage>, Error> {
+    let filter = format!("label={}=true", MANAGED_LABEL);
+    let ids = docker_output(
+        vec!["image", "ls", "--quiet", "--no-trunc", "--filter", &filter],
+        "Failed to list images",
+    )?;
+    let mut ids: Vec<&str> = ids.lines().filter(|id| !id.is_empty()).collect();
+    // an image with several tags is listed once per tag
+    ids.sort();
+    ids.dedup();
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let format = format!(
+        "{{{{.Id}}}}\t{{{{join .RepoTags \",\"}}}}\t{{{{join .RepoDigests \",\"}}}}\t{{{{index .Config.Labels \"{}\"}}}}\t{{{{index .Config.Labels \"{}\"}}}}\t{{{{index .Config.Labels \"{}\"}}}}\t{{{{.Created}}}}\t{{{{.Size}}}}\t{{{{join .RootFS.Layers \",\"}}}}",
+        COMMAND_NAME_LABEL, HASH_LABEL, KIND_LABEL
+    );
+    let mut args = vec!["image", "inspect", "--format", &format];
+    args.extend(ids);
+    let inspected = docker_output(args, "Failed to inspect images")?;
+
+    let mut images = Vec::new();
+    for line in inspected.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 9 {
+            bail!("Unexpected output of docker image inspect: {}", line);
+        }
+        images.push(ManagedImage {
+            id: String::from(fields[0]),
+            tags: split_list(fields[1]),
+            digests: split_list(fields[2]),
+            command: label_value(fields[3]),
+            hash: label_value(fields[4]),
+            kind: label_value(fields[5]),
+            created: parse_timestamp(fields[6]).unwrap_or(0),
+            size: fields[7].parse().unwrap_or(0),
+            layers: split_list(fields[8]),
+        });
+    }
+    return Ok(images);
+}
+
+/// Whether `image` was built on top of `parent`
+pub fn is_parent(parent: &ManagedImage, image: &ManagedImage) -> bool {
+    return !parent.layers.is_empty()
+        && image.layers.len() > parent.layers.len()
+        && image.layers.starts_with(&parent.layers);
+}
+
+/// Removes a local image. Fails if a container still uses it.
+pub fn remove_image(image: &ManagedImage) -> Result<(), Error> {
+    // removing by id fails for images with several tags, so they are untagged
+    // one by one, which removes the image along with the last one
+    let mut references: Vec<&str> = image.tags.iter().map(String::as_str).collect();
+    if references.is_empty() {
+        references.push(&image.id);
+    }
+    let mut args = vec!["image", "rm"];
+    args.extend(references);
+    docker_output(args, &format!("Failed to remove image {}", image.id))?;
+    return Ok(());
+}
+
+fn docker_output(args: Vec<&str>, error_message: &str) -> Result<String, Error> {
+    let result = exec::exec_and_capture_output("docker", args, Vec::new(), false, None, "")
+        .context(error_message)?;
+    if !result.status.success() {
+        bail!(
+            "{}: {}",
+            error_message,
+            String::from_utf8_lossy(&result.stderr).trim()
+        );
+    }
+    return Ok(String::from_utf8_lossy(&result.stdout).to_string());
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    return value
+        .split(',')
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect();
+}
+
+/// Missing labels are rendered as <no value> by docker's templates
+fn label_value(value: &str) -> String {
+    return if value == "<no value>" {
+        String::new()
+    } else {
+        String::from(value)
+    };
+}
+
+/// Parses the RFC 3339 timestamps docker uses (e.g. 2024-05-01T12:34:56.789Z
+/// or 2024-05-01T12:34:56+02:00) into a unix timestamp
+fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    let number = |range: std::ops::Range<usize>| timestamp.get(range)?.parse::<i64>().ok();
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+
+    // days since the epoch of a date of the proleptic Gregorian calendar
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let mut seconds = days * 86400 + hour * 3600 + minute * 60 + second;
+    // timezone offset, if not UTC
+    let rest = timestamp.get(19..)?;
+    if let Some(offset_start) = rest.find(|c| c == '+' || c == '-') {
+        let offset = &rest[offset_start..];
+        let sign = if offset.starts_with('-') { -1 } else { 1 };
+        let hours: i64 = offset.get(1..3)?.parse().ok()?;
+        let minutes: i64 = offset.get(4..6)?.parse().ok()?;
+        seconds -= sign * (hours * 3600 + minutes * 60);
+    }
+    return if seconds < 0 {
+        None
+    } else {
+        Some(seconds as u64)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_utc_timestamps() {
+        assert_eq!(parse_timestamp("2024-05-01T12:34:56Z"), Some(1714566896));
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_timestamp("2000-03-01T00:00:00Z"), Some(951868800));
+    }
+
+    #[test]
+    fn parses_fractional_seconds() {
+        assert_eq!(
+            parse_timestamp("2024-05-01T12:34:56.789123456Z"),
+            Some(1714566896)
+        );
+        assert_eq!(
+            parse_timestamp("2024-05-01T12:34:56.5+02:00"),
+            Some(1714559696)
+        );
+    }
+
+    #[test]
+    fn applies_timezone_offsets() {
+        assert_eq!(
+            parse_timestamp("2024-05-01T12:34:56+02:00"),
+            Some(1714559696)
+        );
+        assert_eq!(
+            parse_timestamp("2024-05-01T12:34:56-05:30"),
+            Some(1714586696)
+        );
+    }
+
+    #[test]
+    fn handles_leap_days() {
+        assert_eq!(parse_timestamp("2024-02-29T00:00:00Z"), Some(1709164800));
+        assert_eq!(parse_timestamp("2024-03-01T00:00:00Z"), Some(1709251200));
+        // 1900 was not a leap year, 2000 was
+        assert_eq!(
+            parse_timestamp("2000-02-29T00:00:00Z").map(|t| t + 86400),
+            parse_timestamp("2000-03-01T00:00:00Z")
+        );
+    }
+
+    #[test]
+    fn rejects_pre_epoch_and_invalid_timestamps() {
+        assert_eq!(parse_timestamp("1969-12-31T23:59:59Z"), None);
+        assert_eq!(parse_timestamp("1900-02-28T23:59:59Z"), None);
+        assert_eq!(parse_timestamp("1970-01-01T00:30:00+01:00"), None);
+        assert_eq!(parse_timestamp(""), None);
+        assert_eq!(parse_timestamp("2024-05-01"), None);
+        assert_eq!(parse_timestamp("not a timestamp at all"), None);
+        assert_eq!(parse_timestamp("2024-05-01T12:34:56+0"), None);
+        // multibyte characters where digits or the offset are expected
+        assert_eq!(parse_timestamp("2024-05-01T12:34:5é"), None);
+        assert_eq!(parse_timestamp("2024-05-01T12:34:56+é:00"), None);
+    }
+}
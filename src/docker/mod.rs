@@ -1,19 +1,31 @@
+pub mod builders;
+mod context;
+pub mod images;
 mod volumes;
 extern crate serde_json;
 use crate::cmd::Cmd;
+use crate::util::cache;
+use crate::util::http;
+use crate::util::hash::sha256_hex;
 use crate::util::paths;
-use crate::util::paths::expand_path;
+use crate::util::paths::{expand_path, write_atomically};
 use crate::exec;
 use easy_error::{bail, Error, ResultExt};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::Write;
 use std::iter::FromIterator;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::sync::OnceLock;
+use indicatif::ProgressBar;
 use tempfile::NamedTempFile;
 use tempfile::TempDir;
 use users::{get_current_gid, get_current_uid, get_current_username};
+use builders::{find_package_manager, PackageManager, PACKAGE_MANAGERS};
 use volumes::{get_dynamic_volume_mounts, parse_volume_mounting, DynamicVolumeMount, VolumeMount};
 use colored::*;
 
@@ -37,22 +49,24 @@ struct EnvVar {
 // all the information needed to run the command via Docker
 /// This should take care of mounting volumes, reparsing arguments,
 /// setting a workdir, adding env variables, etc.
+/// PWD and relative paths given as arguments resolve against `working_dir`.
 pub fn build_docker_run(
     cmd: &Cmd,
     args: Vec<&str>,
     disable_dynamic_mounts: bool,
+    working_dir: &Path,
 ) -> Result<DockerRun, Error> {
     let mut volumes: Vec<VolumeMount> = Vec::new();
     // Add volumes explicitly set in the command manifest
     for vol in &cmd.volumes {
-        volumes.push(parse_volume_mounting(&vol)?);
+        volumes.push(parse_volume_mounting(&in_working_dir(vol, working_dir))?);
     }
 
     // if dynamic volume mounting is enable, try to figure out if there are
     // paths to mount and adjust the arguments to point to their new full path
     let args = if cmd.enable_dynamic_volume_mounts && !disable_dynamic_mounts {
         let (dynamic_mounts, new_args): (Vec<VolumeMount>, Vec<String>) =
-            get_dynamic_volumes_and_new_args(args, &volumes);
+            get_dynamic_volumes_and_new_args(args, &volumes, working_dir);
         volumes.extend(dynamic_mounts);
         new_args
     } else {
@@ -61,7 +75,7 @@ pub fn build_docker_run(
 
     // Set an appropriate working directory if one is required
     let workdir = if let Some(workdir) = &cmd.workdir {
-        let dynamic_mount = infer_workdir(workdir, &volumes);
+        let dynamic_mount = infer_workdir(workdir, &volumes, working_dir);
         if let Some(mount) = dynamic_mount.mount {
             volumes.push(mount);
         }
@@ -171,16 +185,26 @@ pub fn docker_run_to_args(docker_run: &DockerRun) -> Vec<String> {
     return docker_args;
 }
 
+/// Replaces the PWD a path starts with, if any, with `working_dir`
+fn in_working_dir(path: &str, working_dir: &Path) -> String {
+    return match path.strip_prefix("PWD") {
+        Some(rest) => format!("{}{}", working_dir.display(), rest),
+        None => String::from(path),
+    };
+}
+
 fn get_dynamic_volumes_and_new_args(
     args: Vec<&str>,
     existent_vols: &Vec<VolumeMount>,
+    working_dir: &Path,
 ) -> (Vec<VolumeMount>, Vec<String>) {
     let mut new_args: Vec<String> = Vec::new();
     let mut volumes: HashSet<VolumeMount> = HashSet::new();
     for arg in &args {
-        let as_path = Path::new(&arg);
+        let as_path = working_dir.join(arg);
         if as_path.exists() {
-            let dynamic_mount = get_dynamic_volume_mounts(arg, existent_vols);
+            let dynamic_mount =
+                get_dynamic_volume_mounts(&as_path.to_string_lossy(), existent_vols);
             if let Some(mount) = dynamic_mount.mount {
                 volumes.insert(mount);
             }
@@ -192,9 +216,13 @@ fn get_dynamic_volumes_and_new_args(
     return (Vec::from_iter(volumes.iter().cloned()), new_args);
 }
 
-fn infer_workdir(workdir: &str, existent_mounts: &Vec<VolumeMount>) -> DynamicVolumeMount {
+fn infer_workdir(
+    workdir: &str,
+    existent_mounts: &Vec<VolumeMount>,
+    working_dir: &Path,
+) -> DynamicVolumeMount {
     if workdir.starts_with("~") || workdir.starts_with("PWD") {
-        let host_path = expand_path(workdir);
+        let host_path = expand_path(&in_working_dir(workdir, working_dir));
 
         if !Path::new(&host_path).exists() {
             panic!("The command was configured with a WORKDIR that does not exist in your local filesystem: {}
@@ -218,59 +246,287 @@ fn infer_workdir(workdir: &str, existent_mounts: &Vec<VolumeMount>) -> DynamicVo
     }
 }
 
+/// Options that tweak how `docker build` is invoked
+#[derive(Default, Clone)]
+pub struct BuildOptions {
+    pub verbose: bool,
+    // Progress bar where to report what is being done. When absent,
+    // each docker invocation draws its own spinner.
+    pub progress: Option<ProgressBar>,
+    // --build-arg values (KEY=VALUE or KEY)
+    pub build_args: Vec<String>,
+    // --secret values (id=ID,src=FILE or id=ID,env=VAR). Requires BuildKit.
+    pub secrets: Vec<String>,
+    // --cache-from sources
+    pub cache_from: Vec<String>,
+    // --cache-to destination. Requires buildx.
+    pub cache_to: Option<String>,
+    // --label values added to the built image
+    pub labels: Vec<(String, String)>,
+    // Default base image pins of package-based builders, from the config.
+    // The config is loaded once by the caller, as builds run concurrently.
+    pub base_image_pins: HashMap<String, String>,
+}
+
+/// Label holding the digest of the base image a command image was built from
+pub const BASE_IMAGE_LABEL: &str = "io.macondo.base-image";
+/// Label holding the command (serialized as JSON) an image was built for
+pub const COMMAND_LABEL: &str = "io.macondo.command";
+/// Label present in every image created by macondo
+pub const MANAGED_LABEL: &str = "io.macondo.managed";
+/// Label holding the name of the command an image was created for
+pub const COMMAND_NAME_LABEL: &str = "io.macondo.command-name";
+/// Label holding the `command_hash` of the command an image was created for
+pub const HASH_LABEL: &str = "io.macondo.hash";
+/// Label telling what an image is: the image of a command (build) or its
+/// user-aligned version (aligned)
+pub const KIND_LABEL: &str = "io.macondo.kind";
+
+/// Helper libraries that commands can opt into with `@uses NAME`. They are
+/// embedded into the binary so that builds do not need network access, and
+/// are copied to /NAME.sh in the command image.
+pub const HELPERS: &[(&str, &str)] = &[("argsparse", include_str!("argsparse.sh"))];
+
+// Directory of the temporary build context where included files are copied
+const INCLUDES_DIR: &str = "_includes";
+
+// Whether docker buildx is installed, checked once per run
+static BUILDX_AVAILABLE: OnceLock<bool> = OnceLock::new();
+
 /// Builds a docker image that wraps the provided command
 ///
-/// The image is built using the provided base docker image
+/// The image is built using the provided Dockerfile instructions, which
+/// must at least include the FROM of the base image.
 /// A default entrypoint is provided that runs "/the_command"
+///
+/// The build context is a temporary directory that only contains the
+/// command file, the helpers it uses and the files matched by its
+/// `includes` globs. Included files are copied to /macondo/NAME/ in the
+/// image, NAME being the command name, keeping their path relative to the
+/// command.
 pub fn build_command_image_from_base(
     command_name: &str,
     command_path: &Path,
-    base_image: &str,
+    image_setup: &str,
     extra_commands: &str,
-    verbose: bool,
+    helpers: &[String],
+    includes: &[String],
+    options: &BuildOptions,
 ) -> Result<String, Error> {
-    let dockerfile_dir = TempDir::new().context("Could not create temp Dockerfile")?;
+    let (context_dir, dockerfile_path) = write_command_build_context(
+        command_name,
+        command_path,
+        image_setup,
+        extra_commands,
+        helpers,
+        includes,
+    )?;
+    return build_image(
+        command_name,
+        context_dir.path(),
+        command_path,
+        Some(&dockerfile_path.path().to_string_lossy()),
+        options,
+    );
+}
+
+/// Assembles the build context of `build_command_image_from_base`, and its
+/// Dockerfile within it
+fn write_command_build_context(
+    command_name: &str,
+    command_path: &Path,
+    image_setup: &str,
+    extra_commands: &str,
+    helpers: &[String],
+    includes: &[String],
+) -> Result<(TempDir, NamedTempFile), Error> {
+    let context_dir = TempDir::new().context("Could not create temp build context")?;
+    fs::copy(
+        command_path.join(command_name),
+        context_dir.path().join(command_name),
+    )
+    .context(format!(
+        "Failed to copy {} to the build context",
+        command_name
+    ))?;
+
+    let included_files = context::resolve_includes(command_path, includes)?;
+    context::copy_files(
+        command_path,
+        &context_dir.path().join(INCLUDES_DIR),
+        &included_files,
+    )?;
+
+    let mut helper_files: Vec<String> = Vec::new();
+    for helper in helpers {
+        let contents = match HELPERS.iter().find(|(name, _)| name == helper) {
+            Some((_, contents)) => contents,
+            None => bail!("Unknown helper {} used by {}", helper, command_name),
+        };
+        let helper_file = format!("{}.sh", helper);
+        fs::write(context_dir.path().join(&helper_file), contents).context(format!(
+            "Failed to copy {} to the build context",
+            helper_file
+        ))?;
+        helper_files.push(helper_file);
+    }
+
     let mut dockerfile_path: NamedTempFile =
-        NamedTempFile::new_in(&dockerfile_dir).context("Could not create temp Dockerfile")?;
+        NamedTempFile::new_in(&context_dir).context("Could not create temp Dockerfile")?;
     write_dummy_dockerfile(
         dockerfile_path.as_file_mut(),
-        base_image,
+        image_setup,
         extra_commands,
+        &helper_files,
+        !included_files.is_empty(),
         command_name,
     )?;
+    return Ok((context_dir, dockerfile_path));
+}
+
+/// Builds the image of a command that comes with its own Dockerfile.
+///
+/// Without includes, the directory of the command is the build context, as
+/// usual. Otherwise a minimal context is assembled with the Dockerfile, the
+/// command and its includes. Either way, the files listed in .dockerignore
+/// are left out.
+pub fn build_dockerfile_image(
+    command_name: &str,
+    command_path: &Path,
+    includes: &[String],
+    options: &BuildOptions,
+) -> Result<String, Error> {
+    if includes.is_empty() {
+        return build_image(command_name, command_path, command_path, None, options);
+    }
+
+    let mut files: Vec<PathBuf> = vec![PathBuf::from("Dockerfile"), PathBuf::from(command_name)];
+    // docker applies it again to the minimal context
+    if command_path.join(".dockerignore").exists() {
+        files.push(PathBuf::from(".dockerignore"));
+    }
+    for file in context::resolve_includes(command_path, includes)? {
+        if !files.contains(&file) {
+            files.push(file);
+        }
+    }
+
+    let context_dir = TempDir::new().context("Could not create temp build context")?;
+    context::copy_files(command_path, context_dir.path(), &files)?;
     return build_image(
         command_name,
+        context_dir.path(),
         command_path,
-        Some(&dockerfile_path.path().to_string_lossy()),
-        verbose,
+        None,
+        options,
     );
 }
 
 /// Builds a new Docker image based on the provided one
 /// with a user whose username, user id and user group mirrors
 /// that of the host user.
-pub fn align_with_host_user(image_name: &str, verbose: bool) -> Result<String, Error> {
+pub fn align_with_host_user(image_name: &str, options: &BuildOptions) -> Result<String, Error> {
     let dockerfile_dir = TempDir::new().context("Could not create temp Dockerfile")?;
     let mut dockerfile_path: NamedTempFile =
         NamedTempFile::new_in(&dockerfile_dir).context("Could not create temp Dockerfile")?;
     write_user_alignment_dockerfile(dockerfile_path.as_file_mut(), image_name)?;
     return build_image(
-        &format!("{}.aligned", image_name),
+        &aligned_image_tag(image_name),
+        dockerfile_dir.path(),
         dockerfile_dir.path(),
         Some(&dockerfile_path.path().to_string_lossy()),
-        verbose,
+        options,
     );
 }
 
+/// Tag of the user-aligned version of the provided image.
+/// Digest references (repo@sha256:...) can't just get a suffix appended,
+/// so the digest becomes the tag instead: repo:sha256-....aligned
+pub fn aligned_image_tag(image_name: &str) -> String {
+    return match split_digest(image_name) {
+        (name, Some(digest)) => format!(
+            "{}:{}.aligned",
+            strip_tag(name),
+            digest.replace(":", "-")
+        ),
+        (name, None) => format!("{}.aligned", name),
+    };
+}
+
+/// Splits an image reference into its name and its digest (if any)
+/// e.g. registry:5000/repo:tag@sha256:abc -> (registry:5000/repo:tag, Some(sha256:abc))
+pub fn split_digest(image: &str) -> (&str, Option<&str>) {
+    let mut parts = image.splitn(2, '@');
+    return (parts.next().unwrap_or_default(), parts.next());
+}
+
+/// Removes the tag of an image name, e.g. registry:5000/repo:tag -> registry:5000/repo
+pub fn strip_tag(image: &str) -> &str {
+    let name_start = image.rfind('/').map(|idx| idx + 1).unwrap_or(0);
+    return match image[name_start..].rfind(':') {
+        Some(idx) => &image[..name_start + idx],
+        None => image,
+    };
+}
+
+/// Whether a digest looks like a valid sha256 content digest
+pub fn is_valid_digest(digest: &str) -> bool {
+    return match digest.strip_prefix("sha256:") {
+        Some(hex) => hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None => false,
+    };
+}
+
+/// Returns the digest reference (repo@sha256:...) of an image that was
+/// pushed to a registry, as recorded by docker after the push
+pub fn pushed_image_digest(image: &str) -> Result<String, Error> {
+    let result = exec::exec_and_capture_output(
+        "docker",
+        vec![
+            "image",
+            "inspect",
+            "--format",
+            "{{range .RepoDigests}}{{println .}}{{end}}",
+            image,
+        ],
+        Vec::new(),
+        false,
+        None,
+        "",
+    )
+    .context(format!("Failed to inspect {}", image))?;
+    return match repository_digest(image, &String::from_utf8_lossy(&result.stdout)) {
+        Some(digest) => Ok(digest),
+        None => bail!("Could not find the digest of {} after pushing it", image),
+    };
+}
+
+/// Picks the digest reference of the repository of `image` out of its
+/// RepoDigests, one per line. An image tagged in several repositories has
+/// a digest for each of them.
+fn repository_digest(image: &str, repo_digests: &str) -> Option<String> {
+    let repository = strip_tag(split_digest(image).0);
+    return repo_digests
+        .lines()
+        .map(str::trim)
+        .find(|digest| digest.starts_with(&format!("{}@", repository)))
+        .map(String::from);
+}
+
 /// Builds a docker image from the provided context.
 /// Tags it with the provided tag and returns the name of the image.
 /// If a dockerfile_path is provided, use that as the -f Dockerfile
-/// TODO: detect if buildkit is available. If so, use it.
+/// Relative secret files (src=FILE) are found in `command_path`, the
+/// directory of the command, as the context may be a temporary copy.
+///
+/// BuildKit is used whenever it is available. It is required for
+/// secrets, and buildx is required to export the cache with --cache-to.
 pub fn build_image(
     tag: &str,
     context_path: &Path,
+    command_path: &Path,
     dockerfile_path: Option<&str>,
-    verbose: bool,
+    options: &BuildOptions,
 ) -> Result<String, Error> {
     let dockerfile = if let Some(custom_dockerfile) = dockerfile_path {
         String::from(custom_dockerfile)
@@ -287,19 +543,100 @@ pub fn build_image(
         String::from(default_dockerfile)
     };
 
-    let docker_build_args = vec!["build", "-t", tag, "-f", &dockerfile, "."];
+    let buildkit = is_buildkit_available();
+    if !buildkit && !options.secrets.is_empty() {
+        bail!(
+            "Failed to build {}: build secrets require BuildKit, which does not seem to be available",
+            tag
+        );
+    }
+    if options.cache_to.is_some() && !is_buildx_available() {
+        bail!(
+            "Failed to build {}: --cache-to requires docker buildx to be installed",
+            tag
+        );
+    }
 
-    let result = exec::exec_and_capture_output(
-        "docker",
-        docker_build_args,
-        Vec::new(),
-        verbose,
+    // Secrets sourced from env vars are written to files that only live
+    // for the duration of the build
+    let secrets_dir = TempDir::new().context("Could not create temp dir for build secrets")?;
+    let mut secrets: Vec<String> = Vec::new();
+    for secret in &options.secrets {
+        secrets.push(secret_to_build_flag(
+            secret,
+            command_path,
+            secrets_dir.path(),
+        )?);
+    }
+
+    let mut docker_build_args: Vec<String> = Vec::new();
+    if options.cache_to.is_some() {
+        // the cache can only be exported by buildx, which must be told
+        // to load the result into the local images store
+        docker_build_args.extend(vec!["buildx".to_string(), "build".to_string()]);
+        docker_build_args.push("--load".to_string());
+    } else {
+        docker_build_args.push("build".to_string());
+    }
+    docker_build_args.extend(vec!["-t".to_string(), tag.to_string()]);
+    docker_build_args.extend(vec!["-f".to_string(), dockerfile]);
+    // the proxy settings are forwarded so that package installs work behind
+    // a proxy too. They are predefined args, kept out of the image history,
+    // and passed by name so that proxy credentials are not printed. Build
+    // args of the command come later, so they win.
+    let proxy_env_vars = http::proxy_env_vars()?;
+    for (name, _) in &proxy_env_vars {
+        docker_build_args.push("--build-arg".to_string());
+        docker_build_args.push(name.to_string());
+    }
+    for build_arg in &options.build_args {
+        docker_build_args.push("--build-arg".to_string());
+        docker_build_args.push(build_arg.to_string());
+    }
+    for secret in secrets {
+        docker_build_args.push("--secret".to_string());
+        docker_build_args.push(secret);
+    }
+    for cache_from in &options.cache_from {
+        docker_build_args.push("--cache-from".to_string());
+        docker_build_args.push(cache_from.to_string());
+    }
+    if let Some(cache_to) = &options.cache_to {
+        docker_build_args.push("--cache-to".to_string());
+        docker_build_args.push(cache_to.to_string());
+    }
+    // every image macondo creates is labeled, so that `macondo gc` finds it
+    docker_build_args.push("--label".to_string());
+    docker_build_args.push(format!("{}=true", MANAGED_LABEL));
+    for (key, value) in &options.labels {
+        docker_build_args.push("--label".to_string());
+        docker_build_args.push(format!("{}={}", key, value));
+    }
+    docker_build_args.push(".".to_string());
+
+    let mut env_vars: Vec<(&str, &str)> = proxy_env_vars
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+    if buildkit {
+        env_vars.push(("DOCKER_BUILDKIT", "1"));
+    }
+
+    let result = run_docker(
+        docker_build_args.iter().map(|arg| arg.as_str()).collect(),
+        env_vars,
         Some(context_path),
         &format!("Building Docker image for {}...", &tag),
+        options,
     )
     .context("Failed to build docker image")?;
 
     if result.status.code().unwrap_or(-1) != 0 {
+        if options.progress.is_some() {
+            // other builds are drawing their progress; don't dump the whole
+            // output, but keep the gist of what went wrong in the error
+            bail!("Failed to build docker image: {}", last_line(&result.stderr));
+        }
         println!("{}", String::from_utf8_lossy(&result.stdout));
         println!("{}", String::from_utf8_lossy(&result.stderr));
         bail!("Failed to build docker image");
@@ -308,17 +645,462 @@ pub fn build_image(
     return Ok(String::from(tag));
 }
 
+/// Labels that describe the provided command: the whole command serialized
+/// as JSON, plus the standard OCI annotations for its metadata
+pub fn command_labels(cmd: &Cmd) -> Result<Vec<(String, String)>, Error> {
+    // where the command came from is meaningless once it is in an image, and
+    // build args and secrets may hold credentials that anyone able to inspect
+    // the image would read
+    let cmd = Cmd {
+        command_path: None,
+        build_args: Vec::new(),
+        build_secrets: Vec::new(),
+        ..cmd.clone()
+    };
+    let serialized = serde_json::to_string(&cmd)
+        .context(format!("Failed to serialize command {}", cmd.name))?;
+    let mut labels = vec![
+        (String::from(COMMAND_LABEL), serialized),
+        (
+            String::from("org.opencontainers.image.title"),
+            cmd.name.clone(),
+        ),
+        (
+            String::from("org.opencontainers.image.version"),
+            cmd.version.clone(),
+        ),
+    ];
+    if cmd.description != "" {
+        labels.push((
+            String::from("org.opencontainers.image.description"),
+            cmd.description.clone(),
+        ));
+    }
+    return Ok(labels);
+}
+
+/// Labels that tell `macondo gc` which command (and which version of it)
+/// an image was created for. `kind` is either build or aligned.
+pub fn managed_labels(cmd: &Cmd, kind: &str) -> Result<Vec<(String, String)>, Error> {
+    return Ok(vec![
+        (String::from(COMMAND_NAME_LABEL), cmd.name.clone()),
+        (String::from(HASH_LABEL), command_hash(cmd)?),
+        (String::from(KIND_LABEL), String::from(kind)),
+    ]);
+}
+
+/// Hash of everything the image of a command is created from: the command
+/// itself and, for local commands, the command file, its Dockerfile and its
+/// included files. An image whose hash differs from the one of the current
+/// command is outdated. It is a SHA-256, as lockfiles rely on it to vouch for
+/// command definitions.
+pub fn command_hash(cmd: &Cmd) -> Result<String, Error> {
+    // serialized through a Value so that maps (e.g. extra) have a stable order
+    let definition = serde_json::to_value(&Cmd {
+        command_path: None,
+        ..cmd.clone()
+    })
+    .context(format!("Failed to serialize command {}", cmd.name))?;
+    let mut data = serde_json::to_vec(&definition)
+        .context(format!("Failed to serialize command {}", cmd.name))?;
+    if let Some(command_path) = &cmd.command_path {
+        let command_path = Path::new(command_path);
+        let command_dir = command_path.parent().unwrap_or(Path::new("."));
+        let mut files = vec![PathBuf::from(command_path.file_name().unwrap())];
+        if cmd.registry == "Dockerfile" {
+            files.push(PathBuf::from("Dockerfile"));
+        }
+        files.extend(context::resolve_includes(command_dir, &cmd.includes)?);
+        for file in files {
+            let file = command_dir.join(file);
+            data.extend(fs::read(&file).context(format!("Failed to read {}", file.display()))?);
+        }
+    }
+    return Ok(sha256_hex(&data));
+}
+
+/// Reconstructs a command out of the labels of an image built by macondo.
+/// The image is pulled if it is not available locally.
+///
+/// Images pinned to a digest can't change, so the command they describe is
+/// cached per digest and later imports don't involve docker at all.
+pub fn import_command(image: &str) -> Result<Cmd, Error> {
+    let cache_file = match split_digest(image) {
+        (_, Some(digest)) if is_valid_digest(digest) => {
+            Some(cache::image_cache_file(digest, "command.json"))
+        }
+        _ => None,
+    };
+    let cached = cache_file
+        .as_ref()
+        .and_then(|cache_file| fs::read_to_string(cache_file).ok())
+        .and_then(|serialized| serde_json::from_str::<Cmd>(&serialized).ok());
+    if let Some(cmd) = cached {
+        return Ok(Cmd {
+            registry: String::from(image),
+            command_path: None,
+            ..cmd
+        });
+    }
+
+    let format = format!("{{{{index .Config.Labels \"{}\"}}}}", COMMAND_LABEL);
+    let inspect = || {
+        return exec::exec_and_capture_output(
+            "docker",
+            vec!["image", "inspect", "--format", &format, image],
+            Vec::new(),
+            false,
+            None,
+            "",
+        )
+        .context(format!("Failed to inspect {}", image));
+    };
+    let mut result = inspect()?;
+    if !result.status.success() {
+        let pull = exec::exec_and_capture_output(
+            "docker",
+            vec!["pull", image],
+            Vec::new(),
+            false,
+            None,
+            &format!("Pulling {}...", image),
+        )
+        .context(format!("Failed to pull {}", image))?;
+        if !pull.status.success() {
+            bail!("Failed to pull {}: {}", image, last_line(&pull.stderr));
+        }
+        result = inspect()?;
+    }
+    let serialized = String::from_utf8_lossy(&result.stdout);
+    let serialized = serialized.trim();
+    if !result.status.success() || serialized.is_empty() || serialized == "<no value>" {
+        bail!(
+            "Image {} does not describe a macondo command (it has no {} label)",
+            image,
+            COMMAND_LABEL
+        );
+    }
+
+    let cmd: Cmd = serde_json::from_str(serialized).context(format!(
+        "Failed to parse the command described by {}",
+        image
+    ))?;
+    if let Some(cache_file) = &cache_file {
+        // the cache only saves a docker call, so imports go on without it
+        let _ = write_atomically(cache_file, serialized.as_bytes());
+    }
+    return Ok(Cmd {
+        registry: String::from(image),
+        command_path: None,
+        ..cmd
+    });
+}
+
+/// Pulls an image from its registry
+pub fn pull_image(image: &str, options: &BuildOptions) -> Result<(), Error> {
+    let result = run_docker(
+        vec!["pull", image],
+        Vec::new(),
+        None,
+        &format!("Pulling {}...", image),
+        options,
+    )
+    .context(format!("Failed to pull {}", image))?;
+    if !result.status.success() {
+        bail!("Failed to pull {}: {}", image, last_line(&result.stderr));
+    }
+    return Ok(());
+}
+
+/// Resolves an image reference (e.g. alpine:3.18) to its immutable digest
+/// reference (e.g. alpine@sha256:...), pulling the image if necessary.
+/// Images that were never pushed to the registry of their repository have
+/// no digest there, in which case the image reference is returned as-is.
+pub fn resolve_image_digest(image: &str, options: &BuildOptions) -> Result<String, Error> {
+    if !exec::does_command_succeed("docker", vec!["image", "inspect", image]) {
+        pull_image(image, options)?;
+    }
+
+    let result = exec::exec_and_capture_output(
+        "docker",
+        vec![
+            "image",
+            "inspect",
+            "--format",
+            "{{range .RepoDigests}}{{println .}}{{end}}",
+            image,
+        ],
+        Vec::new(),
+        false,
+        None,
+        "",
+    )
+    .context(format!("Failed to inspect {}", image))?;
+    let digest = repository_digest(image, &String::from_utf8_lossy(&result.stdout));
+    return Ok(digest.unwrap_or(String::from(image)));
+}
+
+/// What installing packages on top of a base image needs to know about it
+pub struct BaseImage {
+    /// Local id of the image (sha256:...)
+    pub id: String,
+    /// User the image runs as, empty if unset
+    pub user: String,
+}
+
+/// Inspects a base image, pulling it if necessary
+pub fn inspect_base_image(image: &str, options: &BuildOptions) -> Result<BaseImage, Error> {
+    if !exec::does_command_succeed("docker", vec!["image", "inspect", image]) {
+        pull_image(image, options)?;
+    }
+
+    let result = exec::exec_and_capture_output(
+        "docker",
+        vec![
+            "image",
+            "inspect",
+            "--format",
+            "{{.Id}} {{.Config.User}}",
+            image,
+        ],
+        Vec::new(),
+        false,
+        None,
+        "",
+    )
+    .context(format!("Failed to inspect {}", image))?;
+    if !result.status.success() {
+        bail!("Failed to inspect {}: {}", image, last_line(&result.stderr));
+    }
+    let output = String::from_utf8_lossy(&result.stdout);
+    let (id, user) = output.trim().split_once(' ').unwrap_or((output.trim(), ""));
+    return Ok(BaseImage {
+        id: String::from(id),
+        user: String::from(user.trim()),
+    });
+}
+
+/// Figures out which of the known package managers is available in the
+/// provided image by probing for their binaries in a throwaway container.
+/// The result is cached per image id, so that the probe runs once per image.
+pub fn detect_package_manager(
+    image: &str,
+    base_image: &BaseImage,
+    options: &BuildOptions,
+) -> Result<&'static PackageManager, Error> {
+    let cache_file = cache::image_cache_file(&base_image.id, "package-manager");
+    if let Ok(name) = fs::read_to_string(&cache_file) {
+        if let Some(pm) = find_package_manager(name.trim()) {
+            return Ok(pm);
+        }
+    }
+
+    let binaries: Vec<&str> = PACKAGE_MANAGERS.iter().map(|pm| pm.binary).collect();
+    let probe = format!(
+        "for pm in {}; do command -v $pm > /dev/null && echo $pm && exit 0; done",
+        binaries.join(" ")
+    );
+    let result = run_docker(
+        vec!["run", "--rm", "--entrypoint", "sh", image, "-c", &probe],
+        Vec::new(),
+        None,
+        &format!("Detecting package manager of {}...", image),
+        &BuildOptions {
+            verbose: false,
+            ..options.clone()
+        },
+    )
+    .context(format!("Failed to detect package manager of {}", image))?;
+
+    let binary = String::from_utf8_lossy(&result.stdout).trim().to_string();
+    if let Some(pm) = PACKAGE_MANAGERS.iter().find(|pm| pm.binary == binary) {
+        if options.verbose {
+            eprintln!("Detected {} package manager in {}", pm.name.blue(), image);
+        }
+        // the cache only saves a container run, so builds go on without it
+        let _ = write_atomically(&cache_file, pm.name.as_bytes());
+        return Ok(pm);
+    }
+    bail!(
+        "Could not detect the package manager of {}. Set it explicitly with the @package_manager annotation",
+        image
+    );
+}
+
+/// Returns the size in bytes of a local image
+pub fn image_size(image: &str) -> Result<u64, Error> {
+    let result = exec::exec_and_capture_output(
+        "docker",
+        vec!["image", "inspect", "--format", "{{.Size}}", image],
+        Vec::new(),
+        false,
+        None,
+        "",
+    )
+    .context(format!("Failed to inspect {}", image))?;
+    let size = String::from_utf8_lossy(&result.stdout);
+    return Ok(size
+        .trim()
+        .parse()
+        .context(format!("Failed to get size of {}", image))?);
+}
+
+/// Runs a docker command reporting progress on `options.progress`
+/// if present, or on its own spinner otherwise
+fn run_docker(
+    args: Vec<&str>,
+    env_vars: Vec<(&str, &str)>,
+    from_dir: Option<&Path>,
+    message: &str,
+    options: &BuildOptions,
+) -> io::Result<Output> {
+    return match &options.progress {
+        Some(progress) => exec::exec_and_report_progress(
+            progress,
+            "docker",
+            args,
+            env_vars,
+            options.verbose,
+            from_dir,
+            message,
+        ),
+        None => exec::exec_and_capture_output(
+            "docker",
+            args,
+            env_vars,
+            options.verbose,
+            from_dir,
+            message,
+        ),
+    };
+}
+
+fn last_line(output: &[u8]) -> String {
+    let output = String::from_utf8_lossy(output);
+    return output
+        .lines()
+        .rev()
+        .find(|line| !line.trim().is_empty())
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+}
+
+/// Whether `docker build` can use BuildKit. An explicit DOCKER_BUILDKIT
+/// env var wins; otherwise BuildKit is used if buildx is installed.
+pub fn is_buildkit_available() -> bool {
+    if let Ok(value) = env::var("DOCKER_BUILDKIT") {
+        return value == "1" || value == "true";
+    }
+    return is_buildx_available();
+}
+
+fn is_buildx_available() -> bool {
+    return *BUILDX_AVAILABLE
+        .get_or_init(|| exec::does_command_succeed("docker", vec!["buildx", "version"]));
+}
+
+/// Turns a build secret annotation into a value for `docker build --secret`.
+///
+/// Relative secret files are resolved against `command_path`. Secrets read
+/// from the environment (id=ID,env=VAR) are written into `secrets_dir` so
+/// that they can be passed as files, which every BuildKit version
+/// understands.
+fn secret_to_build_flag(
+    secret: &str,
+    command_path: &Path,
+    secrets_dir: &Path,
+) -> Result<String, Error> {
+    let mut id: Option<&str> = None;
+    let mut src: Option<PathBuf> = None;
+    let mut env_var: Option<&str> = None;
+    for part in secret.split(',') {
+        let mut key_value = part.trim().splitn(2, '=');
+        match (key_value.next(), key_value.next()) {
+            (Some("id"), Some(value)) => id = Some(value),
+            (Some("src"), Some(value)) | (Some("source"), Some(value)) => {
+                src = Some(command_path.join(expand_path(value)))
+            }
+            (Some("env"), Some(value)) => env_var = Some(value),
+            _ => bail!(
+                "Invalid build secret '{}'. Expected format: id=ID,src=FILE or id=ID,env=VAR",
+                secret
+            ),
+        }
+    }
+
+    let id = match id {
+        Some(id) => id,
+        None => bail!("Build secret '{}' is missing an id", secret),
+    };
+    // the id names the file the secret is written to in `secrets_dir`
+    let valid_id = id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-');
+    if id.is_empty() || !valid_id || id.contains("..") {
+        bail!(
+            "Invalid build secret id '{}'. Ids can only contain letters, digits, '_', '.' and '-', and not '..'",
+            id
+        );
+    }
+
+    let src = match (src, env_var) {
+        (Some(src), None) => {
+            if !src.is_file() {
+                bail!(
+                    "File {} for build secret {} does not exist",
+                    src.display(),
+                    id
+                );
+            }
+            String::from(src.to_string_lossy())
+        }
+        (None, Some(env_var)) => {
+            let value = env::var(env_var).context(format!(
+                "Environment variable {} for build secret {} is not set",
+                env_var, id
+            ))?;
+            let secret_file = secrets_dir.join(id);
+            let mut file = File::create(&secret_file)
+                .context(format!("Failed to write build secret {}", id))?;
+            file.write_all(value.as_bytes())
+                .context(format!("Failed to write build secret {}", id))?;
+            String::from(secret_file.to_string_lossy())
+        }
+        _ => bail!(
+            "Build secret {} must have exactly one of src=FILE or env=VAR",
+            id
+        ),
+    };
+
+    return Ok(format!("id={},src={}", id, src));
+}
+
+/// Directory of the image where the files included by a command are copied
+fn includes_dir(command_name: &str) -> String {
+    let name = command_name.strip_suffix(".mcd").unwrap_or(command_name);
+    return format!("/macondo/{}", name);
+}
+
 fn write_dummy_dockerfile(
     dockerfile: &mut File,
-    base_image: &str,
+    image_setup: &str,
     extra_commands: &str,
+    helper_files: &[String],
+    copy_includes: bool,
     command_name: &str,
 ) -> Result<(), Error> {
-    let contents = f!("FROM {base_image}
+    let mut copy_files: String = helper_files
+        .iter()
+        .map(|helper| f!("COPY {helper} /{helper}\nRUN chmod a+r /{helper}\n"))
+        .collect();
+    if copy_includes {
+        let includes_dir = includes_dir(command_name);
+        copy_files.push_str(&f!("COPY {INCLUDES_DIR}/ {includes_dir}/\n"));
+    }
+    let contents = f!("{image_setup}
 {extra_commands}
-ADD https://raw.githubusercontent.com/Anvil/bash-argsparse/master/argsparse.sh /
-RUN chmod a+r /argsparse.sh
-COPY {command_name} /{command_name}
+{copy_files}COPY {command_name} /{command_name}
 RUN chmod a+x /{command_name}
 ENTRYPOINT [ \"/{command_name}\" ]
 ");
@@ -327,8 +1109,15 @@ ENTRYPOINT [ \"/{command_name}\" ]
     Ok(())
 }
 
+/// Dockerfile instructions (without FROM) that create a user mirroring the
+/// host one. The user is described by the MACONDO_HOST_* build args, whose
+/// defaults are the provided placeholders.
+pub fn user_alignment_instructions() -> &'static str {
+    return include_str!("user-alignment.sh");
+}
+
 fn write_user_alignment_dockerfile(dockerfile: &mut File, base_image: &str) -> Result<(), Error> {
-    let user_alignment = include_str!("user-alignment.sh")
+    let user_alignment = user_alignment_instructions()
         .replace(
             "MACONDO_HOST_USER_ID_PLACEHOLDER",
             &get_current_uid().to_string(),
@@ -348,3 +1137,170 @@ fn write_user_alignment_dockerfile(dockerfile: &mut File, base_image: &str) -> R
     write!(dockerfile, "{}", contents).context("Failed to write user alignment Dockerfile")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_secret_files_against_the_command() {
+        let command_dir = TempDir::new().unwrap();
+        let secrets_dir = TempDir::new().unwrap();
+        fs::write(command_dir.path().join("token.txt"), "s3cr3t").unwrap();
+        let flag = secret_to_build_flag(
+            "id=token,src=token.txt",
+            command_dir.path(),
+            secrets_dir.path(),
+        );
+        assert_eq!(
+            flag.unwrap(),
+            format!(
+                "id=token,src={}",
+                command_dir.path().join("token.txt").display()
+            )
+        );
+
+        let err = secret_to_build_flag(
+            "id=token,src=missing",
+            command_dir.path(),
+            secrets_dir.path(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("does not exist"), "{}", err);
+    }
+
+    #[test]
+    fn writes_env_secrets_to_files() {
+        let secrets_dir = TempDir::new().unwrap();
+        env::set_var("MACONDO_TEST_BUILD_SECRET", "s3cr3t");
+        let flag = secret_to_build_flag(
+            "id=npm.token, env=MACONDO_TEST_BUILD_SECRET",
+            Path::new("."),
+            secrets_dir.path(),
+        );
+        let secret_file = secrets_dir.path().join("npm.token");
+        assert_eq!(
+            flag.unwrap(),
+            format!("id=npm.token,src={}", secret_file.display())
+        );
+        assert_eq!(fs::read_to_string(secret_file).unwrap(), "s3cr3t");
+    }
+
+    #[test]
+    fn rejects_invalid_secrets() {
+        let secrets_dir = TempDir::new().unwrap();
+        env::set_var("MACONDO_TEST_INVALID_SECRET", "s3cr3t");
+        for secret in &[
+            "id=../escape,env=MACONDO_TEST_INVALID_SECRET",
+            "id=a/b,env=MACONDO_TEST_INVALID_SECRET",
+            "id=a..b,env=MACONDO_TEST_INVALID_SECRET",
+            "id=,env=MACONDO_TEST_INVALID_SECRET",
+            "env=MACONDO_TEST_INVALID_SECRET",
+            "id=token",
+            "id=token,src=token.txt,env=MACONDO_TEST_INVALID_SECRET",
+            "id=token,type=file",
+        ] {
+            let result = secret_to_build_flag(secret, Path::new("."), secrets_dir.path());
+            assert!(result.is_err(), "{} was accepted", secret);
+        }
+        // nothing was written outside of (or into) the secrets dir
+        assert_eq!(fs::read_dir(secrets_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn picks_the_digest_of_the_image_repository() {
+        let repo_digests = "mirror.example.com/library/alpine@sha256:aaa
+alpine@sha256:bbb
+registry:5000/alpine@sha256:ccc
+";
+        for image in &["alpine", "alpine:3.18", "alpine@sha256:bbb"] {
+            assert_eq!(
+                repository_digest(image, repo_digests),
+                Some(String::from("alpine@sha256:bbb"))
+            );
+        }
+        assert_eq!(
+            repository_digest("registry:5000/alpine:3.18", repo_digests),
+            Some(String::from("registry:5000/alpine@sha256:ccc"))
+        );
+        // another repository whose name ends the same is not a match
+        assert_eq!(repository_digest("library/alpine", repo_digests), None);
+        assert_eq!(repository_digest("debian:bookworm", repo_digests), None);
+        assert_eq!(repository_digest("alpine", ""), None);
+    }
+
+    #[test]
+    fn finds_the_digest_of_pushed_images() {
+        // `docker image inspect` output after pushing an image that was also
+        // pushed to another registry under a repository of the same name
+        let repo_digests = "
+docker.io/myuser/commands@sha256:1111
+localhost:5000/myuser/commands@sha256:2222
+";
+        assert_eq!(
+            repository_digest("localhost:5000/myuser/commands:hello-0.1.0", repo_digests),
+            Some(String::from("localhost:5000/myuser/commands@sha256:2222"))
+        );
+        assert_eq!(
+            repository_digest("myuser/commands:hello-0.1.0", repo_digests),
+            None
+        );
+        assert_eq!(
+            repository_digest("localhost:5000/myuser/other:hello-0.1.0", repo_digests),
+            None
+        );
+    }
+
+    #[test]
+    fn copies_embedded_helpers_into_the_build_context() {
+        let command_dir = TempDir::new().unwrap();
+        fs::write(command_dir.path().join("hello.mcd"), "echo hello").unwrap();
+        let (context_dir, dockerfile) = write_command_build_context(
+            "hello.mcd",
+            command_dir.path(),
+            "FROM alpine",
+            "",
+            &[String::from("argsparse")],
+            &[],
+        )
+        .unwrap();
+
+        let helper = fs::read_to_string(context_dir.path().join("argsparse.sh")).unwrap();
+        assert_eq!(helper, HELPERS[0].1);
+        let dockerfile = fs::read_to_string(dockerfile.path()).unwrap();
+        assert!(
+            dockerfile.contains("COPY argsparse.sh /argsparse.sh\n"),
+            "{}",
+            dockerfile
+        );
+        assert!(!dockerfile.contains("ADD "), "{}", dockerfile);
+
+        let unknown = write_command_build_context(
+            "hello.mcd",
+            command_dir.path(),
+            "FROM alpine",
+            "",
+            &[String::from("unknown")],
+            &[],
+        );
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn keeps_build_args_and_secrets_out_of_labels() {
+        let cmd: Cmd = serde_yaml::from_str(
+            "
+name: hello
+registry: alpine
+volumes: []
+build_args: [NPM_TOKEN=s3cr3t]
+build_secrets: [id=token,env=TOKEN]
+",
+        )
+        .unwrap();
+        let labels = command_labels(&cmd).unwrap();
+        let (_, serialized) = labels.iter().find(|(key, _)| key == COMMAND_LABEL).unwrap();
+        assert!(!serialized.contains("s3cr3t"), "{}", serialized);
+        assert!(!serialized.contains("TOKEN"), "{}", serialized);
+    }
+}
@@ -2,11 +2,14 @@ extern crate users;
 use crate::util::progress_bar;
 use colored::*;
 use easy_error::{bail, Error, ResultExt};
+use indicatif::ProgressBar;
 use std::env::current_dir;
 use std::io;
 use std::io::ErrorKind::NotFound;
+use std::io::Write;
 use std::path::Path;
 use std::process::{Command, Output, Stdio};
+use std::thread;
 
 pub fn exec(program: &str, args: Vec<String>, from_dir: Option<&Path>) -> Result<i32, Error> {
     let mut command = Command::new(program);
@@ -61,7 +64,48 @@ pub fn exec_and_capture_output(
         eprintln!("{}", message);
     }
 
-    // Run the command without showing any output. Just wait for it to finished.
+    let output = run_and_wait(program, args, env_vars, verbose, run_from_dir);
+    spinner.finish_and_clear();
+    return output;
+}
+
+/// Same as `exec_and_capture_output`, but instead of drawing its own spinner
+/// it shows `message` in the provided progress bar, which is left running.
+/// Useful when several commands run concurrently, each one with its own bar.
+pub fn exec_and_report_progress(
+    progress: &ProgressBar,
+    program: &str,
+    args: Vec<&str>,
+    env_vars: Vec<(&str, &str)>,
+    verbose: bool,
+    from_dir: Option<&Path>,
+    message: &str,
+) -> io::Result<Output> {
+    let current_directory = current_dir().unwrap();
+    let run_from_dir = from_dir.or(Some(&current_directory)).unwrap();
+
+    progress.set_message(message);
+    if verbose {
+        eprintln!(
+            "{} (executing {} {} from {})",
+            message,
+            program.blue().bold(),
+            args.join(" ").blue(),
+            run_from_dir.to_string_lossy().bright_yellow()
+        );
+    }
+
+    return run_and_wait(program, args, env_vars, verbose, run_from_dir);
+}
+
+// Run the command without showing any output (unless verbose). Just wait for it to finished.
+fn run_and_wait(
+    program: &str,
+    args: Vec<&str>,
+    env_vars: Vec<(&str, &str)>,
+    verbose: bool,
+    run_from_dir: &Path,
+) -> io::Result<Output> {
     let mut command = Command::new(program);
     command.args(&args);
     if !verbose {
@@ -74,9 +118,7 @@ pub fn exec_and_capture_output(
         command.env(key, val);
     }
 
-    let output = command.spawn().unwrap().wait_with_output();
-    spinner.finish_and_clear();
-    return output;
+    return command.spawn()?.wait_with_output();
 }
 
 pub fn does_command_exist(command: &str) -> bool {
@@ -92,3 +134,53 @@ pub fn does_command_exist(command: &str) -> bool {
     }
     return true;
 }
+
+/// Runs the provided command silently and returns whether it exited successfully
+pub fn does_command_succeed(command: &str, args: Vec<&str>) -> bool {
+    let result = Command::new(command)
+        .args(&args)
+        .stderr(Stdio::null())
+        .stdout(Stdio::null())
+        .stdin(Stdio::null())
+        .status();
+    return match result {
+        Ok(status) => status.success(),
+        Err(_) => false,
+    };
+}
+
+/// Runs the provided command writing `input` to its standard input,
+/// and captures its output
+pub fn exec_with_input(
+    program: &str,
+    args: &[String],
+    input: &str,
+    from_dir: Option<&Path>,
+) -> Result<Output, Error> {
+    let mut command = Command::new(program);
+    if let Some(dir) = from_dir {
+        command.current_dir(dir);
+    }
+    let mut child = command
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(format!("Could not spawn: {} {}", program, args.join(" ")))?;
+    // written from another thread, so that a command that does not read its
+    // input (or fills the output pipes first) can't deadlock us
+    let mut stdin = child.stdin.take().unwrap();
+    let input = String::from(input);
+    let writer = thread::spawn(move || {
+        // the command may exit without reading everything
+        let _ = stdin.write_all(input.as_bytes());
+    });
+    let output = child.wait_with_output().context(format!(
+        "Error waiting on output of: {} {}",
+        program,
+        args.join(" ")
+    ))?;
+    let _ = writer.join();
+    return Ok(output);
+}
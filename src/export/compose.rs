@@ -0,0 +1,118 @@
+use super::{
+    container_path_in, is_host_relative, parse_raw_volume, unsupported_features_warnings, Export,
+    RawVolume,
+};
+use crate::cmd::Cmd;
+use easy_error::{Error, ResultExt};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Serialize)]
+struct ComposeFile {
+    services: BTreeMap<String, Service>,
+}
+
+#[derive(Serialize)]
+struct Service {
+    image: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    command: Vec<String>,
+    stdin_open: bool,
+    tty: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    working_dir: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    volumes: Vec<String>,
+    environment: BTreeMap<String, String>,
+}
+
+/// Renders a docker compose file with a single service that runs the command
+/// with the provided arguments. `~` and `PWD` in host paths are expressed with
+/// variables that compose interpolates when the file is used.
+pub fn export(cmd: &Cmd, args: &[String]) -> Result<Export, Error> {
+    let mut warnings = unsupported_features_warnings(cmd, args);
+
+    let mut raw_volumes: Vec<RawVolume> = Vec::new();
+    for vol in &cmd.volumes {
+        raw_volumes.push(parse_raw_volume(vol)?);
+    }
+    let mut volumes: Vec<String> = raw_volumes.iter().map(volume_spec).collect();
+
+    let working_dir = match &cmd.workdir {
+        Some(workdir) if is_host_relative(workdir) => {
+            Some(match container_path_in(workdir, &raw_volumes) {
+                Some(container_path) => compose_path(&container_path),
+                None => {
+                    // same as macondo: mount it under /_mnt
+                    let host_path = compose_path(workdir);
+                    volumes.push(format!("{}:/_mnt{}", host_path, host_path));
+                    format!("/_mnt{}", host_path)
+                }
+            })
+        }
+        Some(workdir) => Some(workdir.clone()),
+        None => None,
+    };
+
+    let mut environment = BTreeMap::new();
+    environment.insert(
+        String::from("HOST_USER_ID"),
+        String::from("${HOST_USER_ID:-1000}"),
+    );
+    environment.insert(
+        String::from("HOST_GROUP_ID"),
+        String::from("${HOST_GROUP_ID:-1000}"),
+    );
+    if cmd.needs_ssh {
+        volumes.push(String::from("${SSH_AUTH_SOCK}:/ssh-auth-sock"));
+        environment.insert(
+            String::from("SSH_AUTH_SOCK"),
+            String::from("/ssh-auth-sock"),
+        );
+        warnings.push(String::from(
+            "the ssh-agent socket is only forwarded where SSH_AUTH_SOCK is set",
+        ));
+    }
+
+    let mut services = BTreeMap::new();
+    services.insert(
+        cmd.name.clone(),
+        Service {
+            image: cmd.registry.clone(),
+            command: args.iter().map(|arg| arg.replace("$", "$$")).collect(),
+            stdin_open: true,
+            tty: cmd.needs_tty,
+            user: if cmd.user != "" {
+                Some(cmd.user.clone())
+            } else {
+                None
+            },
+            working_dir,
+            volumes,
+            environment,
+        },
+    );
+
+    let contents = serde_yaml::to_string(&ComposeFile { services })
+        .context("Failed to serialize compose file as YAML")?;
+    return Ok(Export { contents, warnings });
+}
+
+fn volume_spec(vol: &RawVolume) -> String {
+    let mut spec = format!("{}:{}", compose_path(&vol.from), compose_path(&vol.to));
+    if let Some(options) = &vol.options {
+        spec.push(':');
+        spec.push_str(options);
+    }
+    return spec;
+}
+
+/// Expresses `~` and `PWD` with variables compose interpolates
+fn compose_path(path: &str) -> String {
+    if let Some(rest) = path.strip_prefix("PWD") {
+        return format!("${{PWD}}{}", rest);
+    }
+    return path.replace("~", "${HOME}");
+}
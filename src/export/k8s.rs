@@ -0,0 +1,345 @@
+use super::{
+    container_path_in, is_host_relative, parse_raw_volume, unsupported_features_warnings, Export,
+    RawVolume,
+};
+use crate::cmd::Cmd;
+use crate::util::paths::expand_path;
+use easy_error::{Error, ResultExt};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Options of the Kubernetes export
+pub struct K8sOptions<'a> {
+    /// Cron schedule. When present a CronJob is exported instead of a Job
+    pub schedule: Option<&'a str>,
+    /// Mount volumes as hostPath volumes instead of PersistentVolumeClaims
+    pub host_path_volumes: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    api_version: &'static str,
+    kind: &'static str,
+    metadata: Metadata,
+    spec: ManifestSpec,
+}
+
+#[derive(Serialize)]
+struct Metadata {
+    name: String,
+    labels: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ManifestSpec {
+    Job(JobSpec),
+    CronJob(CronJobSpec),
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CronJobSpec {
+    schedule: String,
+    concurrency_policy: &'static str,
+    job_template: JobTemplate,
+}
+
+#[derive(Serialize)]
+struct JobTemplate {
+    spec: JobSpec,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JobSpec {
+    backoff_limit: u32,
+    template: PodTemplate,
+}
+
+#[derive(Serialize)]
+struct PodTemplate {
+    spec: PodSpec,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PodSpec {
+    restart_policy: &'static str,
+    containers: Vec<Container>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    volumes: Vec<Volume>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Container {
+    name: String,
+    image: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    args: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    working_dir: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    env: Vec<EnvVar>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    volume_mounts: Vec<VolumeMount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    security_context: Option<SecurityContext>,
+}
+
+#[derive(Serialize)]
+struct EnvVar {
+    name: String,
+    value: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VolumeMount {
+    name: String,
+    mount_path: String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    read_only: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SecurityContext {
+    run_as_user: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    run_as_group: Option<u32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Volume {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host_path: Option<HostPath>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    persistent_volume_claim: Option<ClaimReference>,
+}
+
+#[derive(Serialize)]
+struct HostPath {
+    path: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ClaimReference {
+    claim_name: String,
+}
+
+/// Renders a Kubernetes Job (or CronJob) manifest that runs the command with
+/// the provided arguments. Volumes become PersistentVolumeClaim placeholders
+/// or hostPath volumes, and everything that only makes sense on the machine
+/// running macondo is reported as a warning.
+pub fn export(cmd: &Cmd, args: &[String], options: &K8sOptions) -> Result<Export, Error> {
+    let mut warnings = unsupported_features_warnings(cmd, args);
+    let name = resource_name(&cmd.name);
+
+    let mut raw_volumes: Vec<RawVolume> = Vec::new();
+    for vol in &cmd.volumes {
+        raw_volumes.push(parse_raw_volume(vol)?);
+    }
+
+    let mut volumes: Vec<Volume> = Vec::new();
+    let mut volume_mounts: Vec<VolumeMount> = Vec::new();
+    let mut add_volume = |host_path: &str, mount_path: String, read_only: bool| {
+        let volume_name = format!("volume-{}", volumes.len());
+        volumes.push(volume(&name, &volume_name, host_path, options));
+        volume_mounts.push(VolumeMount {
+            name: volume_name,
+            mount_path,
+            read_only,
+        });
+    };
+    for vol in &raw_volumes {
+        let read_only = vol
+            .options
+            .as_ref()
+            .map_or(false, |o| o.split(',').any(|o| o == "ro"));
+        add_volume(&vol.from, expand_path(&vol.to), read_only);
+    }
+
+    let working_dir = match &cmd.workdir {
+        Some(workdir) if is_host_relative(workdir) => {
+            Some(match container_path_in(workdir, &raw_volumes) {
+                Some(container_path) => expand_path(&container_path),
+                None => {
+                    let mount_path = format!("/_mnt{}", expand_path(workdir));
+                    add_volume(workdir, mount_path.clone(), false);
+                    mount_path
+                }
+            })
+        }
+        Some(workdir) => Some(workdir.clone()),
+        None => None,
+    };
+
+    if options.host_path_volumes {
+        if raw_volumes.iter().any(|vol| is_host_relative(&vol.from))
+            || cmd.workdir.as_ref().map_or(false, |w| is_host_relative(w))
+        {
+            warnings.push(String::from(
+                "~ and PWD were expanded with the paths of this machine, but hostPath volumes refer to the node's filesystem",
+            ));
+        }
+    } else if !volumes.is_empty() {
+        warnings.push(format!(
+            "volumes are mounted from PersistentVolumeClaims named {}-volume-N, which must be created before running the job",
+            name
+        ));
+    }
+
+    let mut env: Vec<EnvVar> = Vec::new();
+    let security_context = match parse_numeric_user(&cmd.user) {
+        Some((uid, gid)) => {
+            env.push(EnvVar {
+                name: String::from("HOST_USER_ID"),
+                value: uid.to_string(),
+            });
+            if let Some(gid) = gid {
+                env.push(EnvVar {
+                    name: String::from("HOST_GROUP_ID"),
+                    value: gid.to_string(),
+                });
+            }
+            Some(SecurityContext {
+                run_as_user: uid,
+                run_as_group: gid,
+            })
+        }
+        None => {
+            if cmd.user != "" {
+                warnings.push(format!(
+                    "user {} is not numeric (UID[:GID]), so it can't be set in the pod's security context",
+                    cmd.user
+                ));
+            }
+            None
+        }
+    };
+
+    if cmd.needs_tty {
+        warnings.push(String::from(
+            "the command needs a TTY, which jobs do not provide",
+        ));
+    }
+    if cmd.needs_ssh {
+        warnings.push(String::from(
+            "the ssh-agent socket can't be forwarded to a job",
+        ));
+    }
+
+    let job = JobSpec {
+        backoff_limit: 0,
+        template: PodTemplate {
+            spec: PodSpec {
+                restart_policy: "Never",
+                containers: vec![Container {
+                    name: name.clone(),
+                    image: cmd.registry.clone(),
+                    args: args.to_vec(),
+                    working_dir,
+                    env,
+                    volume_mounts,
+                    security_context,
+                }],
+                volumes,
+            },
+        },
+    };
+
+    let mut labels = BTreeMap::new();
+    labels.insert(String::from("app.kubernetes.io/name"), name.clone());
+    labels.insert(
+        String::from("app.kubernetes.io/version"),
+        label_value(&cmd.version),
+    );
+    labels.insert(
+        String::from("app.kubernetes.io/managed-by"),
+        String::from("macondo"),
+    );
+    let metadata = Metadata { name, labels };
+
+    let manifest = match options.schedule {
+        Some(schedule) => Manifest {
+            api_version: "batch/v1",
+            kind: "CronJob",
+            metadata,
+            spec: ManifestSpec::CronJob(CronJobSpec {
+                schedule: String::from(schedule),
+                concurrency_policy: "Forbid",
+                job_template: JobTemplate { spec: job },
+            }),
+        },
+        None => Manifest {
+            api_version: "batch/v1",
+            kind: "Job",
+            metadata,
+            spec: ManifestSpec::Job(job),
+        },
+    };
+
+    let contents = serde_yaml::to_string(&manifest)
+        .context("Failed to serialize Kubernetes manifest as YAML")?;
+    return Ok(Export { contents, warnings });
+}
+
+fn volume(job_name: &str, volume_name: &str, host_path: &str, options: &K8sOptions) -> Volume {
+    if options.host_path_volumes {
+        return Volume {
+            name: String::from(volume_name),
+            host_path: Some(HostPath {
+                path: expand_path(host_path),
+            }),
+            persistent_volume_claim: None,
+        };
+    }
+    return Volume {
+        name: String::from(volume_name),
+        host_path: None,
+        persistent_volume_claim: Some(ClaimReference {
+            claim_name: format!("{}-{}", job_name, volume_name),
+        }),
+    };
+}
+
+/// Parses a `UID[:GID]` user
+fn parse_numeric_user(user: &str) -> Option<(u32, Option<u32>)> {
+    let mut parts = user.splitn(2, ':');
+    let uid = parts.next()?.parse().ok()?;
+    return match parts.next() {
+        Some(gid) => Some((uid, Some(gid.parse().ok()?))),
+        None => Some((uid, None)),
+    };
+}
+
+/// Turns a command name into a valid Kubernetes resource name
+/// (lowercase alphanumerics and dashes, at most 63 characters)
+fn resource_name(name: &str) -> String {
+    let name: String = name
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .take(63)
+        .collect();
+    return String::from(name.trim_matches('-'));
+}
+
+/// Turns a string into a valid label value (alphanumerics, `-`, `_` and `.`)
+fn label_value(value: &str) -> String {
+    let value: String = value
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '-',
+        })
+        .take(63)
+        .collect();
+    return String::from(value.trim_matches(|c: char| !c.is_ascii_alphanumeric()));
+}
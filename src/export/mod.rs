@@ -0,0 +1,81 @@
+use crate::cmd::Cmd;
+use easy_error::{bail, Error};
+
+pub mod compose;
+pub mod k8s;
+pub mod sh;
+
+/// A command exported to some other format, along with warnings about
+/// the parts of the command that could not be represented in it
+pub struct Export {
+    pub contents: String,
+    pub warnings: Vec<String>,
+}
+
+/// A volume mounting as written in a command (FROM:TO[:OPTIONS]).
+/// Unlike `docker::VolumeMount`, `~` and `PWD` are not expanded, since
+/// exported commands expand them wherever they end up running.
+pub struct RawVolume {
+    pub from: String,
+    pub to: String,
+    pub options: Option<String>,
+}
+
+pub fn parse_raw_volume(mount: &str) -> Result<RawVolume, Error> {
+    let mut parts = mount.split(':');
+    return match (parts.next(), parts.next()) {
+        (Some(from), Some(to)) if from != "" && to != "" => Ok(RawVolume {
+            from: String::from(from),
+            to: String::from(to),
+            options: parts.next().map(String::from),
+        }),
+        _ => bail!(
+            "Failed to parse volume mounting '{}'. Expected format: FROM:TO[:OPTIONS]",
+            mount
+        ),
+    };
+}
+
+/// Whether a path is relative to the host user's home or working directory
+pub fn is_host_relative(path: &str) -> bool {
+    return path.starts_with('~') || path.starts_with("PWD");
+}
+
+/// Returns the path within the container of a (non expanded) host path,
+/// if one of the provided volumes contains it. The most specific volume wins.
+pub fn container_path_in(host_path: &str, volumes: &[RawVolume]) -> Option<String> {
+    let host_path = host_path.trim_end_matches('/');
+    let mut best: Option<&RawVolume> = None;
+    for vol in volumes {
+        let from = vol.from.trim_end_matches('/');
+        if host_path == from || host_path.starts_with(&format!("{}/", from)) {
+            if best.map_or(true, |b| b.from.len() < vol.from.len()) {
+                best = Some(vol);
+            }
+        }
+    }
+    return best.map(|vol| {
+        format!(
+            "{}{}",
+            vol.to.trim_end_matches('/'),
+            &host_path[vol.from.trim_end_matches('/').len()..]
+        )
+    });
+}
+
+/// Warnings shared by the formats that can't reproduce what macondo does
+/// on the host right before running a command
+pub fn unsupported_features_warnings(cmd: &Cmd, args: &[String]) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if cmd.enable_dynamic_volume_mounts && !args.is_empty() {
+        warnings.push(String::from(
+            "dynamic volume mounts can't be represented: arguments are passed as-is, so paths must exist in the container",
+        ));
+    }
+    if cmd.align_with_host_user {
+        warnings.push(String::from(
+            "the container user can't be aligned with the host user: the command runs as the image (or `user`) user",
+        ));
+    }
+    return warnings;
+}
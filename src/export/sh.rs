@@ -0,0 +1,258 @@
+use super::{is_host_relative, parse_raw_volume, Export};
+use crate::cmd::Cmd;
+use crate::docker;
+use easy_error::Error;
+
+/// Renders a standalone POSIX shell script that runs the command via Docker
+/// the same way macondo would: mounting its volumes, inferring its workdir,
+/// mounting the paths passed as arguments, forwarding SSH and aligning the
+/// container user with the host one. Host paths are resolved when the script
+/// runs, not when it is exported.
+pub fn export(cmd: &Cmd) -> Result<Export, Error> {
+    let mut script = format!(
+        "#!/bin/sh
+# {name} {version}{description}
+#
+# Generated by `macondo export {name} --format sh`. Runs the command with Docker
+# the same way macondo would, without needing macondo to be installed.
+#
+# Environment variables:
+#   MACONDO_DRY_RUN=1                 only print the docker command
+#   MACONDO_DISABLE_DYNAMIC_MOUNTS=1  do not mount the paths passed as arguments
+#   MACONDO_ALIGN_WITH_HOST_USER=0|1  run as a clone of the host user (default: {align})
+set -eu
+",
+        name = cmd.name,
+        version = cmd.version,
+        description = if cmd.description != "" {
+            format!(": {}", cmd.description)
+        } else {
+            String::new()
+        },
+        align = if cmd.align_with_host_user { 1 } else { 0 },
+    );
+    script.push_str(&format!(
+        "
+image={image}
+align=${{MACONDO_ALIGN_WITH_HOST_USER:-{align}}}
+",
+        image = sh_quote(&cmd.registry),
+        align = if cmd.align_with_host_user { 1 } else { 0 },
+    ));
+    script.push_str(HELPERS);
+
+    script.push_str("\nadd_opt -i\n");
+    if cmd.needs_tty {
+        script.push_str("add_opt -t\n");
+    }
+    if cmd.user != "" {
+        script.push_str(&format!("add_opt --user {}\n", sh_quote(&cmd.user)));
+    }
+
+    if !cmd.volumes.is_empty() {
+        script.push_str("\n# volumes of the command\n");
+    }
+    for vol in &cmd.volumes {
+        let vol = parse_raw_volume(vol)?;
+        let from = sh_path(&vol.from);
+        script.push_str(&format!("ensure_host_dir {}\n", from));
+        script.push_str(&format!("add_volume {} {}", from, sh_path(&vol.to)));
+        if let Some(options) = &vol.options {
+            script.push_str(&format!(" {}", sh_quote(options)));
+        }
+        script.push('\n');
+    }
+
+    if cmd.enable_dynamic_volume_mounts {
+        script.push_str(
+            "
+# mount the paths passed as arguments, and point the arguments to them
+if [ \"${MACONDO_DISABLE_DYNAMIC_MOUNTS:-0}\" != 1 ]; then
+    for arg do
+        shift
+        if [ -e \"$arg\" ]; then
+            resolve_path \"$arg\"
+            arg=$container_path
+        fi
+        set -- \"$@\" \"$arg\"
+    done
+fi
+",
+        );
+    }
+
+    if let Some(workdir) = &cmd.workdir {
+        if is_host_relative(workdir) {
+            script.push_str(&format!(
+                "
+workdir={}
+if [ ! -d \"$workdir\" ]; then
+    echo \"The command was configured with a WORKDIR that is not a directory in your local filesystem: $workdir\" >&2
+    exit 1
+fi
+resolve_path \"$workdir\"
+add_opt --workdir \"$container_path\"
+",
+                sh_path(workdir)
+            ));
+        } else {
+            script.push_str(&format!("\nadd_opt --workdir {}\n", sh_quote(workdir)));
+        }
+    }
+
+    if cmd.needs_ssh {
+        script.push_str(
+            "
+# forward the ssh-agent socket
+if [ -n \"${SSH_AUTH_SOCK:-}\" ]; then
+    add_volume \"$SSH_AUTH_SOCK\" /ssh-auth-sock
+    add_opt --env SSH_AUTH_SOCK=/ssh-auth-sock
+else
+    echo \"This commands requires access to SSH, most likely to clone a git repo. But the SSH auth socket was not found.\" >&2
+fi
+",
+        );
+    }
+
+    script.push_str(
+        "
+add_opt --env \"HOST_USER_ID=$(id -u)\" --env \"HOST_GROUP_ID=$(id -g)\"
+",
+    );
+
+    let user_alignment = docker::user_alignment_instructions()
+        .replace("MACONDO_HOST_USER_ID_PLACEHOLDER", "")
+        .replace("MACONDO_HOST_GROUP_ID_PLACEHOLDER", "")
+        .replace("MACONDO_HOST_USERNAME_PLACEHOLDER", "")
+        .replace("MACONDO_HOST_HOME_DIR_PLACEHOLDER", "");
+    script.push_str(&format!(
+        "
+# run as a clone of the host user, so that files written to the host are owned by it
+if [ \"$align\" = 1 ]; then
+    aligned_image={aligned_image}
+    if [ -z \"${{MACONDO_DRY_RUN:-}}\" ]; then
+        docker build -q -t \"$aligned_image\" \\
+            --build-arg \"MACONDO_HOST_USER_ID=$(id -u)\" \\
+            --build-arg \"MACONDO_HOST_GROUP_ID=$(id -g)\" \\
+            --build-arg \"MACONDO_HOST_USERNAME=$(id -un)\" \\
+            --build-arg \"MACONDO_HOST_HOME_DIR=$HOME\" \\
+            - > /dev/null <<'DOCKERFILE'
+FROM {image}
+{user_alignment}
+DOCKERFILE
+    fi
+    image=$aligned_image
+fi
+",
+        aligned_image = sh_quote(&docker::aligned_image_tag(&cmd.registry)),
+        image = cmd.registry,
+        user_alignment = user_alignment.trim_end(),
+    ));
+
+    script.push_str(
+        "
+if [ -n \"${MACONDO_DRY_RUN:-}\" ]; then
+    eval \"set -- $docker_opts \\\"\\$image\\\" \\\"\\$@\\\"\"
+    echo docker run \"$@\"
+    exit 0
+fi
+eval \"exec docker run $docker_opts \\\"\\$image\\\" \\\"\\$@\\\"\"
+",
+    );
+    return Ok(Export {
+        contents: script,
+        warnings: Vec::new(),
+    });
+}
+
+const HELPERS: &str = "
+docker_opts=''
+mounts=''
+tab=$(printf '\\t')
+
+# Appends its arguments, quoted, to the options passed to docker run
+add_opt() {
+    for opt do
+        docker_opts=\"$docker_opts '$(printf '%s' \"$opt\" | sed \"s/'/'\\\\\\\\''/g\")'\"
+    done
+}
+
+# Mounts the host path $1 at $2 (with options $3, if any)
+add_volume() {
+    add_opt --volume \"$1:$2${3:+:$3}\"
+    mounts=\"$mounts$1$tab$2
+\"
+}
+
+# Creates a missing host directory before it is mounted. Otherwise Docker
+# creates it owned by root, which an aligned user can't write to.
+ensure_host_dir() {
+    if [ ! -e \"$1\" ]; then
+        if [ \"$align\" = 1 ]; then
+            mkdir -p \"$1\"
+        else
+            echo \"Command will try to mount a directory or file that does not exist: $1\" >&2
+        fi
+    fi
+}
+
+# Sets container_path to the path of the host path $1 within the container.
+# If no mounted directory contains it, its directory gets mounted under /_mnt
+resolve_path() {
+    if [ -d \"$1\" ]; then
+        host_path=$(cd \"$1\" && pwd -P)
+    else
+        host_path=$(cd \"$(dirname \"$1\")\" && pwd -P)/$(basename \"$1\")
+    fi
+    best_from=''
+    best_to=''
+    while IFS=\"$tab\" read -r from to; do
+        [ -n \"$from\" ] && [ -d \"$from\" ] || continue
+        case \"$host_path\" in
+            \"$from\" | \"${from%/}\"/*)
+                # the most specific mount wins
+                if [ ${#from} -gt ${#best_from} ]; then
+                    best_from=$from
+                    best_to=$to
+                fi
+                ;;
+        esac
+    done <<EOF
+$mounts
+EOF
+    if [ -n \"$best_from\" ]; then
+        container_path=$best_to${host_path#\"$best_from\"}
+        return
+    fi
+    if [ -d \"$host_path\" ]; then
+        mount_dir=$host_path
+    else
+        mount_dir=$(dirname \"$host_path\")
+    fi
+    add_volume \"$mount_dir\" \"/_mnt$mount_dir\"
+    container_path=/_mnt$host_path
+}
+";
+
+/// Quotes a string so that the shell takes it literally
+pub fn sh_quote(value: &str) -> String {
+    return format!("'{}'", value.replace("'", "'\\''"));
+}
+
+/// Renders a path as a shell word, expanding PWD and ~ when the script runs,
+/// the same way `paths::expand_path` does
+fn sh_path(path: &str) -> String {
+    if let Some(rest) = path.strip_prefix("PWD") {
+        return format!("\"$PWD\"{}", quote_if_not_empty(rest));
+    }
+    let parts: Vec<String> = path.split('~').map(quote_if_not_empty).collect();
+    return parts.join("\"$HOME\"");
+}
+
+fn quote_if_not_empty(value: &str) -> String {
+    return if value.is_empty() {
+        String::new()
+    } else {
+        sh_quote(value)
+    };
+}
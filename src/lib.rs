@@ -3,6 +3,7 @@ extern crate fstrings;
 
 mod config;
 mod docker;
+mod export;
 mod util;
 
 pub mod app;
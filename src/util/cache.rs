@@ -1,15 +1,67 @@
-use crate::util::paths::ensure_file;
-use crate::util::paths::expand_path;
+use crate::config;
+use crate::config::Repository;
+use crate::util::hash::sha256_hex;
+use crate::util::http;
+use crate::util::paths::{expand_path, write_atomically};
+use crate::util::signature::{self, signature_file};
+use crate::util::time::{now, parse_age};
+use colored::*;
 use easy_error::{bail, Error, ResultExt};
 use reqwest;
-use std::fs::OpenOptions;
-use std::io::prelude::Write;
-use std::path::Path;
+use reqwest::blocking::Response;
+use reqwest::header::{CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Set to 1 to never hit the network: cached copies are used as they are
+pub const OFFLINE_ENV: &str = "MACONDO_OFFLINE";
+
+/// What is known about a cached resource. It is stored next to it, in a
+/// file with the same name plus a .json extension.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CacheMetadata {
+    pub url: String,
+    // Unix timestamp (seconds) of the last time the copy was known to be
+    // up to date
+    pub fetched_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    // sha256:HEX of HTTP bodies, git:COMMIT of git checkouts
+    #[serde(default)]
+    pub content_hash: String,
+}
+
+/// A resource in the cache store
+pub struct CacheEntry {
+    // The cached file, or directory for git checkouts
+    pub path: String,
+    pub metadata: CacheMetadata,
+}
 
 /// Tries to load an HTTP resource and caches it.
-/// If the cache file already existed, it will retrieve it as-is,
-/// unless `invalidate` is set to true
-pub fn get_from_cache(resource: &str, invalidate: bool) -> Result<String, Error> {
+///
+/// A cached copy is returned as-is while it is younger than the `cache_ttl`
+/// of the config (forever, if not set). Once it is older, or if `invalidate`
+/// is set to true, it is revalidated with a conditional request, so that
+/// unchanged resources are not downloaded again.
+///
+/// The cached copy is only replaced by a successful fetch. If a refresh due
+/// to the TTL fails, the cached copy is used with a warning.
+///
+/// When the repository has a minisign public key, the signature of the
+/// resource (its URL plus .minisig) is fetched along with it and a resource
+/// that doesn't match it is never cached. Requests carry the credentials of
+/// the repository, if any.
+pub fn get_from_cache(repository: &Repository, invalidate: bool) -> Result<String, Error> {
+    let resource = repository.url.as_str();
+    let key = repository.key.as_deref();
     if !is_cacheable(resource) {
         bail!(
             "Can't process {}. Only HTTP and HTTPS resources are cacheable",
@@ -17,41 +69,480 @@ pub fn get_from_cache(resource: &str, invalidate: bool) -> Result<String, Error>
         );
     }
 
-    let cache_file = expand_path(&format!(
+    let cache_file = http_cache_file(resource);
+    migrate_legacy_cache_file(resource, &cache_file)?;
+    let is_cached = Path::new(&cache_file).exists();
+    // a copy cached before the repository had a key, or modified since it
+    // was fetched, is fetched again from scratch
+    let is_trusted = match key {
+        Some(key) => is_cached && signature::verify_file(&cache_file, key).is_ok(),
+        None => is_cached,
+    };
+    let metadata = if is_trusted {
+        load_metadata(&cache_file).unwrap_or_default()
+    } else {
+        CacheMetadata::default()
+    };
+
+    if is_offline() {
+        if !is_cached {
+            bail!(
+                "{} is not cached and can't be fetched in offline mode",
+                resource
+            );
+        }
+        return Ok(cache_file);
+    }
+    if is_trusted && !invalidate && !is_stale(&metadata)? {
+        // if already cached and fresh, just return its path
+        return Ok(cache_file);
+    }
+
+    match fetch(repository, &cache_file, &metadata) {
+        Ok(()) => return Ok(cache_file),
+        Err(e) if is_trusted && !invalidate => {
+            eprintln!(
+                "{}: using the cached copy of {}, as it could not be refreshed: {}",
+                "Warning".yellow(),
+                resource,
+                e
+            );
+            return Ok(cache_file);
+        }
+        Err(e) => return Err(e),
+    }
+}
+
+pub fn is_cacheable(resource: &str) -> bool {
+    resource.starts_with("http://") || resource.starts_with("https://")
+}
+
+/// Root of the cache store: $XDG_CACHE_HOME/macondo, or ~/.cache/macondo
+pub fn cache_root() -> String {
+    return match env::var("XDG_CACHE_HOME") {
+        Ok(dir) if !dir.is_empty() => format!("{}/macondo", dir),
+        _ => expand_path("~/.cache/macondo"),
+    };
+}
+
+/// Cached copies are named after the hash of their URL, so that distinct
+/// URLs never share a file
+pub fn http_cache_file(resource: &str) -> String {
+    return format!("{}/http/{}", cache_root(), sha256_hex(resource.as_bytes()));
+}
+
+/// Facts about local images (e.g. their package manager) are cached per
+/// image id, which changes whenever the image does
+pub fn image_cache_file(image_id: &str, name: &str) -> String {
+    let id = image_id.trim_start_matches("sha256:");
+    return format!("{}/images/{}.{}", cache_root(), id, name);
+}
+
+/// Where caches lived before the cache store, directly under ~/.cache
+fn legacy_cache_file(resource: &str) -> String {
+    return expand_path(&format!(
         "~/.cache/{}",
         resource
             .replace("/", "_")
             .replace(":", "_")
             .replace(".", "_")
     ));
+}
 
-    if Path::new(&cache_file).exists() && !invalidate {
-        // if already cached, just return its path
-        return Ok(cache_file);
+/// Moves a copy cached before the cache store existed into it. The copy
+/// keeps being used until the TTL says otherwise, as it was before.
+fn migrate_legacy_cache_file(resource: &str, cache_file: &str) -> Result<(), Error> {
+    let legacy_file = legacy_cache_file(resource);
+    if Path::new(cache_file).exists() || !Path::new(&legacy_file).is_file() {
+        return Ok(());
     }
+    let contents = fs::read(&legacy_file).context(format!("Failed to read {}", legacy_file))?;
+    write_atomically(cache_file, &contents)?;
+    save_metadata(
+        cache_file,
+        &CacheMetadata {
+            url: String::from(resource),
+            content_hash: format!("sha256:{}", sha256_hex(&contents)),
+            ..CacheMetadata::default()
+        },
+    )?;
+    fs::remove_file(&legacy_file).context(format!("Failed to remove {}", legacy_file))?;
+    return Ok(());
+}
 
-    // Generic error message
-    let err = format!("Failed to create file for {} cache", resource);
+/// Lists the resources in the cache store, sorted by URL. Files without
+/// metadata are not listed.
+pub fn list_entries() -> Result<Vec<CacheEntry>, Error> {
+    let mut entries = Vec::new();
+    for kind in &["http", "git"] {
+        let dir = PathBuf::from(cache_root()).join(kind);
+        if !dir.exists() {
+            continue;
+        }
+        let files = fs::read_dir(&dir).context(format!("Failed to read {}", dir.display()))?;
+        for file in files.filter_map(|file| file.ok()) {
+            let path = file.path();
+            if path
+                .extension()
+                .map_or(true, |extension| extension != "json")
+            {
+                continue;
+            }
+            let cached = path.with_extension("").to_string_lossy().to_string();
+            if let Some(metadata) = load_metadata(&cached) {
+                entries.push(CacheEntry {
+                    path: cached,
+                    metadata,
+                });
+            }
+        }
+    }
+    entries.sort_by(|a, b| a.metadata.url.cmp(&b.metadata.url));
+    return Ok(entries);
+}
 
-    // Try to create file if it does not exist yet
-    ensure_file(&cache_file).context(&err)?;
+/// Removes a resource and its metadata from the cache store
+pub fn remove_entry(entry: &CacheEntry) -> Result<(), Error> {
+    let path = Path::new(&entry.path);
+    if path.is_dir() {
+        fs::remove_dir_all(path).context(format!("Failed to remove {}", entry.path))?;
+    } else if path.exists() {
+        fs::remove_file(path).context(format!("Failed to remove {}", entry.path))?;
+    }
+    let signature_file = signature_file(&entry.path);
+    if Path::new(&signature_file).exists() {
+        fs::remove_file(&signature_file).context(format!("Failed to remove {}", signature_file))?;
+    }
+    let metadata_file = metadata_file(&entry.path);
+    fs::remove_file(&metadata_file).context(format!("Failed to remove {}", metadata_file))?;
+    return Ok(());
+}
 
-    let contents = reqwest::blocking::get(resource)
-        .context(format!("Failed to fetch: {}", resource))?
-        .text()
+/// Whether macondo runs in offline mode (`--offline` or MACONDO_OFFLINE=1)
+pub fn is_offline() -> bool {
+    return env::var(OFFLINE_ENV).map_or(false, |value| value == "1");
+}
+
+/// Fetches the resource into the cache, sending the validators of the cached
+/// copy so that the server can answer that it did not change
+fn fetch(repository: &Repository, cache_file: &str, metadata: &CacheMetadata) -> Result<(), Error> {
+    let resource = repository.url.as_str();
+    let key = repository.key.as_deref();
+    let mut request = http::get(resource, repository)?;
+    if let Some(etag) = &metadata.etag {
+        request = request.header(IF_NONE_MATCH, etag.as_str());
+    }
+    if let Some(last_modified) = &metadata.last_modified {
+        request = request.header(IF_MODIFIED_SINCE, last_modified.as_str());
+    }
+    let response = request
+        .send()
         .context(format!("Failed to fetch: {}", resource))?;
 
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(&cache_file)
-        .context(&err)?;
-    file.write_all(contents.as_bytes()).context(&err)?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        if let Some(key) = key {
+            let contents =
+                fs::read(cache_file).context(format!("Failed to read {}", cache_file))?;
+            fetch_signature(repository, cache_file, &contents, key)?;
+        }
+        return save_metadata(
+            cache_file,
+            &CacheMetadata {
+                fetched_at: now(),
+                ..metadata.clone()
+            },
+        );
+    }
+    let response = check_status(response, resource)?;
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+    };
+    let mut new_metadata = CacheMetadata {
+        url: String::from(resource),
+        fetched_at: now(),
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+        content_type: header(CONTENT_TYPE),
+        content_hash: String::new(),
+    };
+    let contents = response
+        .bytes()
+        .context(format!("Failed to fetch: {}", resource))?;
+    new_metadata.content_hash = format!("sha256:{}", sha256_hex(&contents));
+    if let Some(key) = key {
+        fetch_signature(repository, cache_file, &contents, key)?;
+    }
 
-    return Ok(cache_file);
+    write_atomically(cache_file, &contents)
+        .context(format!("Failed to create file for {} cache", resource))?;
+    return save_metadata(cache_file, &new_metadata);
 }
 
-pub fn is_cacheable(resource: &str) -> bool {
-    resource.starts_with("http://") || resource.starts_with("https://")
+/// Fetches the signature of a resource and caches it, provided that it is a
+/// valid signature of `contents`. It is fetched even when the resource did
+/// not change, as it may have been signed again (e.g. with a new key).
+fn fetch_signature(
+    repository: &Repository,
+    cache_file: &str,
+    contents: &[u8],
+    key: &str,
+) -> Result<(), Error> {
+    let resource = repository.url.as_str();
+    let signature_url = signature_file(resource);
+    let response = http::get(&signature_url, repository)?
+        .send()
+        .context(format!("Failed to fetch the signature of {}", resource))?;
+    let signature = check_status(response, &signature_url)?
+        .text()
+        .context(format!("Failed to fetch the signature of {}", resource))?;
+    signature::verify(contents, &signature, key).context(format!("Untrusted {}", resource))?;
+    return write_atomically(&signature_file(cache_file), signature.as_bytes());
+}
+
+/// Fails on error statuses, pointing at the credentials when they are
+/// missing or rejected
+fn check_status(response: Response, resource: &str) -> Result<Response, Error> {
+    let status = response.status();
+    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        bail!(
+            "Failed to fetch {}: {}. Check the credentials of the repository (its auth in ~/.macondo, or ~/.netrc)",
+            resource,
+            status
+        );
+    }
+    return Ok(response
+        .error_for_status()
+        .context(format!("Failed to fetch: {}", resource))?);
+}
+
+/// A cached copy is stale once it is older than the TTL of the config
+fn is_stale(metadata: &CacheMetadata) -> Result<bool, Error> {
+    let ttl = match config::load_global_config()?.cache_ttl {
+        Some(ttl) => ttl,
+        None => return Ok(false),
+    };
+    let ttl = match parse_age(&ttl) {
+        Ok(ttl) => ttl,
+        Err(e) => bail!("Invalid cache_ttl in ~/.macondo: {}", e),
+    };
+    return Ok(now().saturating_sub(metadata.fetched_at) >= ttl);
+}
+
+fn metadata_file(cache_file: &str) -> String {
+    return format!("{}.json", cache_file);
+}
+
+/// Metadata of a cached file or directory, if it has any
+pub fn load_metadata(cache_file: &str) -> Option<CacheMetadata> {
+    return fs::read_to_string(metadata_file(cache_file))
+        .ok()
+        .and_then(|doc| serde_json::from_str(&doc).ok());
+}
+
+pub fn save_metadata(cache_file: &str, metadata: &CacheMetadata) -> Result<(), Error> {
+    let doc = serde_json::to_string(metadata).context("Failed to serialize cache metadata")?;
+    return write_atomically(&metadata_file(cache_file), doc.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_env::{self, TestEnv};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// A local HTTP server that answers each connection with the next of
+    /// the given responses. It records the requests it gets, lowercased.
+    struct Server {
+        url: String,
+        requests: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Server {
+        fn requests(&self) -> Vec<String> {
+            return self.requests.lock().unwrap().clone();
+        }
+    }
+
+    fn serve(responses: Vec<String>) -> Server {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/repo.yaml", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buffer).unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                let request = String::from_utf8_lossy(&request).to_lowercase();
+                recorded.lock().unwrap().push(request);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        return Server { url, requests };
+    }
+
+    fn response(status: &str, headers: &[&str], body: &str) -> String {
+        let headers: String = headers
+            .iter()
+            .map(|header| format!("{}\r\n", header))
+            .collect();
+        return format!(
+            "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            headers,
+            body.len(),
+            body
+        );
+    }
+
+    fn write_config(env: &TestEnv, config: &str) {
+        fs::write(env.home.path().join(".macondo"), config).unwrap();
+    }
+
+    /// Makes the cached copy look like it was fetched `seconds` ago
+    fn age(cache_file: &str, seconds: u64) {
+        let metadata = load_metadata(cache_file).unwrap();
+        let fetched_at = now() - seconds;
+        save_metadata(
+            cache_file,
+            &CacheMetadata {
+                fetched_at,
+                ..metadata
+            },
+        )
+        .unwrap();
+    }
+
+    fn contents(cache_file: &str) -> String {
+        return fs::read_to_string(cache_file).unwrap();
+    }
+
+    #[test]
+    fn revalidates_with_etag_and_last_modified() {
+        let env = test_env::isolate();
+        write_config(&env, "repositories: []\n");
+        let last_modified = "Wed, 21 Oct 2015 07:28:00 GMT";
+        let server = serve(vec![
+            response(
+                "200 OK",
+                &["ETag: \"v1\"", &format!("Last-Modified: {}", last_modified)],
+                "commands: []\n",
+            ),
+            response("304 Not Modified", &[], ""),
+        ]);
+        let repository = Repository::from(server.url.as_str());
+
+        let cache_file = get_from_cache(&repository, false).unwrap();
+        assert_eq!(cache_file, http_cache_file(&server.url));
+        assert_eq!(contents(&cache_file), "commands: []\n");
+        let metadata = load_metadata(&cache_file).unwrap();
+        assert_eq!(metadata.url, server.url);
+        assert_eq!(metadata.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(metadata.last_modified.as_deref(), Some(last_modified));
+        let hash = sha256_hex(b"commands: []\n");
+        assert_eq!(metadata.content_hash, format!("sha256:{}", hash));
+
+        // without a TTL, cached copies are used until invalidated
+        age(&cache_file, 3600);
+        assert_eq!(get_from_cache(&repository, false).unwrap(), cache_file);
+        assert_eq!(server.requests().len(), 1);
+
+        assert_eq!(get_from_cache(&repository, true).unwrap(), cache_file);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].contains("if-none-match: \"v1\"\r\n"));
+        let if_modified_since = format!("if-modified-since: {}\r\n", last_modified);
+        assert!(requests[1].contains(&if_modified_since.to_lowercase()));
+        assert_eq!(contents(&cache_file), "commands: []\n");
+        let metadata = load_metadata(&cache_file).unwrap();
+        assert!(metadata.fetched_at + 60 > now());
+        assert_eq!(metadata.etag.as_deref(), Some("\"v1\""));
+    }
+
+    #[test]
+    fn fetches_again_once_the_ttl_expires() {
+        let env = test_env::isolate();
+        write_config(&env, "cache_ttl: 1h\n");
+        let server = serve(vec![
+            response("200 OK", &[], "commands: []\n"),
+            response("200 OK", &[], "commands: [{}]\n"),
+        ]);
+        let repository = Repository::from(server.url.as_str());
+
+        let cache_file = get_from_cache(&repository, false).unwrap();
+        age(&cache_file, 1800);
+        get_from_cache(&repository, false).unwrap();
+        assert_eq!(server.requests().len(), 1);
+        assert_eq!(contents(&cache_file), "commands: []\n");
+
+        age(&cache_file, 3600);
+        get_from_cache(&repository, false).unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        // no validators were sent, as the server gave none
+        assert!(!requests[1].contains("if-none-match"));
+        assert!(!requests[1].contains("if-modified-since"));
+        assert_eq!(contents(&cache_file), "commands: [{}]\n");
+    }
+
+    #[test]
+    fn keeps_the_last_good_copy_when_a_refresh_fails() {
+        let env = test_env::isolate();
+        write_config(&env, "cache_ttl: 1h\n");
+        let server = serve(vec![
+            response("200 OK", &["ETag: \"v1\""], "commands: []\n"),
+            response("500 Internal Server Error", &[], "oops"),
+            response("500 Internal Server Error", &[], "oops"),
+        ]);
+        let repository = Repository::from(server.url.as_str());
+
+        let cache_file = get_from_cache(&repository, false).unwrap();
+        age(&cache_file, 7200);
+        // a refresh due to the TTL falls back to the cached copy
+        assert_eq!(get_from_cache(&repository, false).unwrap(), cache_file);
+        assert_eq!(contents(&cache_file), "commands: []\n");
+        // an explicit one fails, but doesn't lose the copy either
+        assert!(get_from_cache(&repository, true).is_err());
+        assert_eq!(server.requests().len(), 3);
+        assert_eq!(contents(&cache_file), "commands: []\n");
+        let metadata = load_metadata(&cache_file).unwrap();
+        assert_eq!(metadata.etag.as_deref(), Some("\"v1\""));
+        assert!(metadata.fetched_at + 7200 <= now());
+    }
+
+    #[test]
+    fn offline_mode_only_uses_cached_copies() {
+        let env = test_env::isolate();
+        write_config(&env, "cache_ttl: 1h\n");
+        let server = serve(vec![response("200 OK", &[], "commands: []\n")]);
+        let repository = Repository::from(server.url.as_str());
+
+        env::set_var(OFFLINE_ENV, "1");
+        assert!(get_from_cache(&repository, false).is_err());
+        assert!(server.requests().is_empty());
+
+        env::remove_var(OFFLINE_ENV);
+        let cache_file = get_from_cache(&repository, false).unwrap();
+        age(&cache_file, 7200);
+        env::set_var(OFFLINE_ENV, "1");
+        // stale or invalidated copies are used as they are
+        assert_eq!(get_from_cache(&repository, false).unwrap(), cache_file);
+        assert_eq!(get_from_cache(&repository, true).unwrap(), cache_file);
+        assert_eq!(server.requests().len(), 1);
+        assert_eq!(contents(&cache_file), "commands: []\n");
+    }
 }
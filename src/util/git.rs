@@ -0,0 +1,508 @@
+use crate::exec;
+use crate::util::cache::{cache_root, is_offline, save_metadata, CacheMetadata};
+use crate::util::hash::sha256_hex;
+use crate::util::time::now;
+use easy_error::{bail, Error, ResultExt};
+use std::fs;
+use std::path::{Component, Path};
+use std::process::Command;
+
+/// A command repository stored in a git repository. It is referenced as
+/// `git+URL[#[REF][:PATH]]`: a fragment can hold a reference (a branch, tag
+/// or commit) and a path (a manifest or directory within the repository),
+/// e.g. `git+https://github.com/org/commands.git#v1.2:manifest.yaml`, or
+/// `#:manifest.yaml` to use the default branch. The path only comes after
+/// `#`, as URLs like git@github.com:org/commands.git have colons too.
+#[derive(Debug, PartialEq)]
+pub struct GitSource {
+    pub url: String,
+    pub reference: Option<String>,
+    pub path: Option<String>,
+}
+
+impl GitSource {
+    /// The resource without its path, i.e. what identifies its checkout
+    pub fn checkout_url(&self) -> String {
+        return match &self.reference {
+            Some(reference) => format!("git+{}#{}", self.url, reference),
+            None => format!("git+{}", self.url),
+        };
+    }
+}
+
+/// Commit checked out before and after updating a git repository source
+pub struct GitUpdate {
+    // None when the repository was cloned for the first time
+    pub previous: Option<String>,
+    pub current: String,
+}
+
+pub fn is_git_resource(resource: &str) -> bool {
+    resource.starts_with("git+")
+}
+
+pub fn parse_git_resource(resource: &str) -> Result<GitSource, Error> {
+    let source = match resource.strip_prefix("git+") {
+        Some(source) => source,
+        None => bail!("{} is not a git repository. Expected git+URL", resource),
+    };
+    let (url, fragment) = match source.split_once('#') {
+        Some((url, fragment)) => (url, Some(fragment)),
+        None => (source, None),
+    };
+    if url.is_empty() {
+        bail!("Missing URL of git repository {}", resource);
+    }
+    // refs can't contain colons, so the first one separates the path
+    let (reference, path) = match fragment.map(|fragment| fragment.split_once(':')) {
+        Some(Some((reference, path))) => (reference, Some(path)),
+        Some(None) => (fragment.unwrap(), None),
+        None => ("", None),
+    };
+    if let Some(path) = path {
+        if Path::new(path)
+            .components()
+            .any(|c| c == Component::ParentDir || c == Component::RootDir)
+        {
+            bail!(
+                "Invalid path {} in {}: it must be relative to the root of the repository",
+                path,
+                resource
+            );
+        }
+    }
+    return Ok(GitSource {
+        url: String::from(url),
+        reference: Some(reference)
+            .filter(|reference| !reference.is_empty())
+            .map(String::from),
+        path: path.filter(|path| !path.is_empty()).map(String::from),
+    });
+}
+
+/// Returns the local path of a git repository source, cloning it into the
+/// cache the first time. Existing checkouts are used as-is; run
+/// `update_checkout` to fetch new commits.
+pub fn get_checkout(resource: &str) -> Result<String, Error> {
+    let source = parse_git_resource(resource)?;
+    let dir = checkout_dir(&source);
+    if !Path::new(&dir).exists() {
+        clone(&source, &dir)?;
+    }
+    return Ok(match &source.path {
+        Some(path) => format!("{}/{}", dir, path),
+        None => dir,
+    });
+}
+
+/// Whether a git repository source has been cloned already
+pub fn is_checked_out(resource: &str) -> Result<bool, Error> {
+    let source = parse_git_resource(resource)?;
+    let dir = checkout_dir(&source);
+    return Ok(Path::new(&dir).exists());
+}
+
+/// Fetches the new commits of a git repository source (cloning it if
+/// necessary) and checks its reference out again
+pub fn update_checkout(resource: &str) -> Result<GitUpdate, Error> {
+    let source = parse_git_resource(resource)?;
+    let dir = checkout_dir(&source);
+    if !Path::new(&dir).exists() {
+        return Ok(GitUpdate {
+            previous: None,
+            current: clone(&source, &dir)?,
+        });
+    }
+
+    let previous = git(&dir, vec!["rev-parse", "HEAD"])?;
+    exec_git(
+        &dir,
+        vec!["fetch", "--quiet", "--prune", "--tags", "--force", "origin"],
+        &format!("Fetching {}", source.url),
+    )?;
+    if source.reference.is_none() {
+        // follow changes of the default branch of the remote
+        exec_git(
+            &dir,
+            vec!["remote", "set-head", "origin", "--auto"],
+            &format!("Fetching {}", source.url),
+        )?;
+    }
+    let current = check_out_reference(&source, &dir)?;
+    record_checkout(&source, &dir, &current)?;
+    return Ok(GitUpdate {
+        previous: Some(previous),
+        current,
+    });
+}
+
+/// The commit a git repository source has checked out, cloning it if necessary
+pub fn current_commit(resource: &str) -> Result<String, Error> {
+    get_checkout(resource)?;
+    let dir = checkout_dir(&parse_git_resource(resource)?);
+    return git(&dir, vec!["rev-parse", "HEAD"]);
+}
+
+/// The same git repository source at a specific commit. Commits get their
+/// own checkout, so the checkout of the reference is left where it is.
+pub fn resource_at_commit(resource: &str, commit: &str) -> Result<String, Error> {
+    let source = parse_git_resource(resource)?;
+    return Ok(match &source.path {
+        Some(path) => format!("git+{}#{}:{}", source.url, commit, path),
+        None => format!("git+{}#{}", source.url, commit),
+    });
+}
+
+/// One-line summaries (abbreviated hash and subject) of the commits that are
+/// in `to` but not in `from`, newest first
+pub fn log_between(resource: &str, from: &str, to: &str) -> Result<Vec<String>, Error> {
+    let dir = checkout_dir(&parse_git_resource(resource)?);
+    let range = format!("{}..{}", from, to);
+    let log = git(&dir, vec!["log", "--format=%h %s", &range])?;
+    return Ok(log.lines().map(String::from).collect());
+}
+
+/// Abbreviated form of a commit hash, for display purposes
+pub fn short_commit(commit: &str) -> &str {
+    return commit.get(..7).unwrap_or(commit);
+}
+
+/// Each checkout lives in its own directory of the cache, keyed by URL and
+/// reference so that different refs of the same repository can coexist
+fn checkout_dir(source: &GitSource) -> String {
+    let key = format!(
+        "{}#{}",
+        source.url,
+        source.reference.as_deref().unwrap_or("")
+    );
+    return format!("{}/git/{}", cache_root(), sha256_hex(key.as_bytes()));
+}
+
+/// Clones the repository into `dir` and checks its reference out. Returns
+/// the commit checked out.
+fn clone(source: &GitSource, dir: &str) -> Result<String, Error> {
+    let parent = Path::new(dir).parent().unwrap();
+    fs::create_dir_all(parent).context(format!("Failed to create {}", parent.display()))?;
+    // cloned in a scratch directory first, so that an interrupted clone
+    // doesn't leave a broken checkout behind
+    let scratch = tempfile::Builder::new()
+        .prefix(".clone-")
+        .tempdir_in(parent)
+        .context(format!(
+            "Failed to create directory in {}",
+            parent.display()
+        ))?;
+    let scratch_dir = scratch.path().to_str().unwrap();
+    exec_git(
+        scratch_dir,
+        vec!["clone", "--quiet", "--no-checkout", &source.url, "."],
+        &format!("Cloning {}", source.url),
+    )?;
+    let commit = check_out_reference(source, scratch_dir)?;
+    // the scratch directory is gone after the rename, so dropping it is a no-op
+    fs::rename(scratch.path(), dir).context(format!("Failed to move checkout to {}", dir))?;
+    record_checkout(source, dir, &commit)?;
+    return Ok(commit);
+}
+
+/// Records what a checkout is, so that it shows up in `macondo cache list`
+fn record_checkout(source: &GitSource, dir: &str, commit: &str) -> Result<(), Error> {
+    return save_metadata(
+        dir,
+        &CacheMetadata {
+            url: source.checkout_url(),
+            fetched_at: now(),
+            content_hash: format!("git:{}", commit),
+            ..CacheMetadata::default()
+        },
+    );
+}
+
+/// Checks out the commit the reference of the source points to, detached
+/// from any local branch. Returns that commit.
+fn check_out_reference(source: &GitSource, dir: &str) -> Result<String, Error> {
+    let candidates = match &source.reference {
+        None => vec![String::from("refs/remotes/origin/HEAD")],
+        // branches are followed through their remote-tracking ref, which
+        // moves on every fetch
+        Some(reference) => vec![
+            format!("refs/remotes/origin/{}", reference),
+            format!("refs/tags/{}", reference),
+            reference.clone(),
+        ],
+    };
+    let commit = candidates.iter().find_map(|candidate| {
+        let candidate = format!("{}^{{commit}}", candidate);
+        return git(dir, vec!["rev-parse", "--verify", "--quiet", &candidate]).ok();
+    });
+    let commit = match commit {
+        Some(commit) => commit,
+        None => bail!(
+            "Could not find {} in {}",
+            source
+                .reference
+                .as_deref()
+                .map(|reference| format!("branch, tag or commit {}", reference))
+                .unwrap_or_else(|| String::from("the default branch")),
+            source.url
+        ),
+    };
+    git(
+        dir,
+        vec![
+            "-c",
+            "advice.detachedHead=false",
+            "checkout",
+            "--quiet",
+            "--force",
+            "--detach",
+            &commit,
+        ],
+    )?;
+    return Ok(commit);
+}
+
+/// Runs a quick, local git command and returns its trimmed output
+fn git(dir: &str, args: Vec<&str>) -> Result<String, Error> {
+    let output = Command::new("git")
+        .args(&args)
+        .current_dir(dir)
+        .output()
+        .context("Failed to run git. Make sure it is installed")?;
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    return Ok(String::from_utf8_lossy(&output.stdout).trim().to_string());
+}
+
+/// Runs a git command that may talk to the remote, showing a spinner
+fn exec_git(dir: &str, args: Vec<&str>, message: &str) -> Result<(), Error> {
+    if is_offline() {
+        bail!("{} is not possible in offline mode", message);
+    }
+    let output = exec::exec_and_capture_output(
+        "git",
+        args,
+        Vec::new(),
+        false,
+        Some(Path::new(dir)),
+        message,
+    )
+    .context("Failed to run git. Make sure it is installed")?;
+    if !output.status.success() {
+        bail!(
+            "{} failed: {}",
+            message,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_env;
+    use tempfile::TempDir;
+
+    fn source(url: &str, reference: Option<&str>, path: Option<&str>) -> GitSource {
+        return GitSource {
+            url: String::from(url),
+            reference: reference.map(String::from),
+            path: path.map(String::from),
+        };
+    }
+
+    #[test]
+    fn parses_references_and_paths() {
+        let url = "https://example.com/commands.git";
+        let cases = vec![
+            ("git+https://example.com/commands.git", None, None),
+            (
+                "git+https://example.com/commands.git#v1.2",
+                Some("v1.2"),
+                None,
+            ),
+            (
+                "git+https://example.com/commands.git#v1.2:dir/repo.yaml",
+                Some("v1.2"),
+                Some("dir/repo.yaml"),
+            ),
+            (
+                "git+https://example.com/commands.git#:repo.yaml",
+                None,
+                Some("repo.yaml"),
+            ),
+            ("git+https://example.com/commands.git#", None, None),
+        ];
+        for (resource, reference, path) in cases {
+            let parsed = parse_git_resource(resource).unwrap();
+            assert_eq!(parsed, source(url, reference, path), "{}", resource);
+        }
+    }
+
+    #[test]
+    fn keeps_colons_of_the_url() {
+        let parsed = parse_git_resource("git+git@example.com:org/commands.git").unwrap();
+        assert_eq!(
+            parsed,
+            source("git@example.com:org/commands.git", None, None)
+        );
+        let parsed = parse_git_resource("git+git@example.com:org/commands.git#main:cmds");
+        assert_eq!(
+            parsed.unwrap(),
+            source(
+                "git@example.com:org/commands.git",
+                Some("main"),
+                Some("cmds")
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_resources() {
+        for resource in &[
+            "https://example.com/commands.git",
+            "git+",
+            "git+#main",
+            "git+https://example.com/commands.git#main:../etc",
+            "git+https://example.com/commands.git#:/etc",
+        ] {
+            assert!(parse_git_resource(resource).is_err(), "{}", resource);
+        }
+    }
+
+    #[test]
+    fn checkout_url_drops_the_path() {
+        let parsed = parse_git_resource("git+/srv/commands.git#v1:repo.yaml").unwrap();
+        assert_eq!(parsed.checkout_url(), "git+/srv/commands.git#v1");
+        let parsed = parse_git_resource("git+/srv/commands.git#:repo.yaml").unwrap();
+        assert_eq!(parsed.checkout_url(), "git+/srv/commands.git");
+    }
+
+    /// A bare repository with a work tree to push commits to it from
+    struct Remote {
+        _dir: TempDir,
+        bare: String,
+        work: String,
+    }
+
+    impl Remote {
+        fn new() -> Remote {
+            let dir = TempDir::new().unwrap();
+            let bare = dir
+                .path()
+                .join("commands.git")
+                .to_string_lossy()
+                .to_string();
+            let work = dir.path().join("work").to_string_lossy().to_string();
+            let root = dir.path().to_str().unwrap();
+            git(root, vec!["init", "--quiet", "--bare", "-b", "main", &bare]).unwrap();
+            git(root, vec!["init", "--quiet", "-b", "main", &work]).unwrap();
+            git(&work, vec!["remote", "add", "origin", &bare]).unwrap();
+            return Remote {
+                _dir: dir,
+                bare,
+                work,
+            };
+        }
+
+        /// Commits a file and pushes it. Returns the commit.
+        fn commit(&self, file: &str, contents: &str) -> String {
+            fs::write(Path::new(&self.work).join(file), contents).unwrap();
+            git(&self.work, vec!["add", "."]).unwrap();
+            let identity = vec!["-c", "user.name=test", "-c", "user.email=test@example.com"];
+            let commit = vec!["commit", "--quiet", "-m", file];
+            git(&self.work, [identity, commit].concat()).unwrap();
+            git(
+                &self.work,
+                vec!["push", "--quiet", "--tags", "origin", "main"],
+            )
+            .unwrap();
+            return git(&self.work, vec!["rev-parse", "HEAD"]).unwrap();
+        }
+
+        fn resource(&self, fragment: &str) -> String {
+            return format!("git+{}{}", self.bare, fragment);
+        }
+    }
+
+    #[test]
+    fn checks_out_the_default_branch_and_paths() {
+        let env = test_env::isolate();
+        let remote = Remote::new();
+        let commit = remote.commit("repo.yaml", "commands: []");
+        assert!(cache_root().starts_with(env.home.path().to_str().unwrap()));
+
+        let checkout = get_checkout(&remote.resource("#:repo.yaml")).unwrap();
+        assert!(checkout.ends_with("/repo.yaml"));
+        assert_eq!(fs::read_to_string(&checkout).unwrap(), "commands: []");
+        assert!(checkout.starts_with(&format!("{}/git/", cache_root())));
+        assert_eq!(current_commit(&remote.resource("")).unwrap(), commit);
+        assert!(is_checked_out(&remote.resource("#:other.yaml")).unwrap());
+    }
+
+    #[test]
+    fn updates_follow_the_branch() {
+        let _env = test_env::isolate();
+        let remote = Remote::new();
+        let first = remote.commit("a.mcd", "first");
+        let resource = remote.resource("#main");
+        get_checkout(&resource).unwrap();
+
+        let second = remote.commit("b.mcd", "second");
+        // existing checkouts are used as they are until updated
+        assert_eq!(current_commit(&resource).unwrap(), first);
+        let update = update_checkout(&resource).unwrap();
+        assert_eq!(update.previous.as_deref(), Some(first.as_str()));
+        assert_eq!(update.current, second);
+        let log = log_between(&resource, &first, &second).unwrap();
+        assert_eq!(log, vec![format!("{} b.mcd", short_commit(&second))]);
+    }
+
+    #[test]
+    fn checks_out_tags_and_commits() {
+        let _env = test_env::isolate();
+        let remote = Remote::new();
+        let first = remote.commit("a.mcd", "first");
+        git(&remote.work, vec!["tag", "v1"]).unwrap();
+        remote.commit("a.mcd", "second");
+        git(&remote.work, vec!["push", "--quiet", "--tags", "origin"]).unwrap();
+
+        let tagged = remote.resource("#v1:a.mcd");
+        assert_eq!(
+            fs::read_to_string(get_checkout(&tagged).unwrap()).unwrap(),
+            "first"
+        );
+        let resource = remote.resource("");
+        let head = current_commit(&resource).unwrap();
+        let pinned = resource_at_commit(&remote.resource("#:a.mcd"), &first).unwrap();
+        assert_eq!(
+            fs::read_to_string(get_checkout(&pinned).unwrap()).unwrap(),
+            "first"
+        );
+        // the checkout of the default branch stays where it was
+        assert_eq!(current_commit(&resource).unwrap(), head);
+        assert_eq!(
+            fs::read_to_string(get_checkout(&remote.resource("#:a.mcd")).unwrap()).unwrap(),
+            "second"
+        );
+
+        let missing = remote.resource("#nope");
+        assert!(get_checkout(&missing).is_err());
+        assert!(!is_checked_out(&missing).unwrap());
+    }
+
+    #[test]
+    fn offline_mode_does_not_clone() {
+        let _env = test_env::isolate();
+        let remote = Remote::new();
+        remote.commit("a.mcd", "first");
+        std::env::set_var(crate::util::cache::OFFLINE_ENV, "1");
+        assert!(get_checkout(&remote.resource("")).is_err());
+    }
+}
@@ -0,0 +1,12 @@
+use sha2::{Digest, Sha256};
+
+/// SHA-256 of the provided data, as a 64 characters hex string.
+/// It is collision resistant, so it can vouch for contents, and stable across
+/// Rust releases, so it can be used for values that outlive the process (e.g.
+/// image labels).
+pub fn sha256_hex(data: &[u8]) -> String {
+    return Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+}
@@ -0,0 +1,553 @@
+use crate::config::{self, HttpSettings, Repository, RepositoryAuth};
+use crate::util::paths::expand_path;
+use colored::*;
+use easy_error::{bail, Error, ResultExt};
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::{Certificate, Identity, Proxy, Url};
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Set to a PEM file with root certificates to trust on top of the system
+/// ones, unless the config sets `ca_file`
+pub const CA_FILE_ENV: &str = "MACONDO_CA_FILE";
+
+/// Client used for the requests of every repository that doesn't need one of
+/// its own, so that connections are reused
+static SHARED_CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Proxy settings in effect, from the config or else from the environment
+pub struct ProxySettings {
+    pub http: Option<String>,
+    pub https: Option<String>,
+    pub no_proxy: Vec<String>,
+}
+
+/// Builds a GET request to `url` carrying the credentials of the
+/// repository, if any: its bearer token or basic auth credentials, or else
+/// those of ~/.netrc for the host, plus its client certificate.
+///
+/// Credentials are read when the request is built and never end up in
+/// errors, which only name where they were looked up.
+pub fn get(url: &str, repository: &Repository) -> Result<RequestBuilder, Error> {
+    let auth = repository.auth.clone().unwrap_or_default();
+    if repository.insecure {
+        eprintln!(
+            "{}: TLS certificates are not verified for {} (insecure in ~/.macondo). Anyone on the network path can tamper with its commands",
+            "WARNING".red().bold(),
+            repository.url.red()
+        );
+    }
+    let client = if auth.client_cert.is_some() || repository.insecure {
+        let identity = match &auth.client_cert {
+            Some(client_cert) => Some(load_identity(client_cert, &auth)?),
+            None => None,
+        };
+        build_client(identity, repository.insecure)?
+    } else {
+        shared_client()?
+    };
+    let request = client.get(url);
+
+    if let Some(token) = read_secret(&auth.token_env, &auth.token_file, "token")? {
+        return Ok(request.bearer_auth(token));
+    }
+    if let Some(username) = &auth.username {
+        return match read_secret(&auth.password_env, &auth.password_file, "password")? {
+            Some(password) => Ok(request.basic_auth(username, Some(password))),
+            None => bail!(
+                "No password for {} in the config of {}. Set password_env or password_file",
+                username,
+                repository.url
+            ),
+        };
+    }
+    if let Some((login, password)) = netrc_credentials(url)? {
+        return Ok(request.basic_auth(login, password));
+    }
+    return Ok(request);
+}
+
+/// Proxy settings of the config, falling back to HTTP_PROXY, HTTPS_PROXY and
+/// NO_PROXY (or their lowercase versions)
+pub fn proxy_settings() -> Result<ProxySettings, Error> {
+    let settings = config::load_global_config()?.http.unwrap_or_default();
+    let from_env = |names: &[&str]| {
+        names
+            .iter()
+            .find_map(|name| env::var(name).ok().filter(|value| !value.is_empty()))
+    };
+    let no_proxy = settings
+        .no_proxy
+        .or_else(|| from_env(&["NO_PROXY", "no_proxy"]))
+        .unwrap_or_default();
+    return Ok(ProxySettings {
+        http: settings
+            .proxy
+            .clone()
+            .or_else(|| from_env(&["HTTP_PROXY", "http_proxy"])),
+        https: settings
+            .proxy
+            .or_else(|| from_env(&["HTTPS_PROXY", "https_proxy"])),
+        no_proxy: no_proxy
+            .split(',')
+            .map(|host| host.trim().to_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect(),
+    });
+}
+
+/// The proxy settings as environment variables, in upper and lower case as
+/// tools disagree on which one to read
+pub fn proxy_env_vars() -> Result<Vec<(String, String)>, Error> {
+    let proxies = proxy_settings()?;
+    let no_proxy = Some(proxies.no_proxy.join(",")).filter(|hosts| !hosts.is_empty());
+    let mut env_vars = Vec::new();
+    for (name, value) in &[
+        ("HTTP_PROXY", proxies.http),
+        ("HTTPS_PROXY", proxies.https),
+        ("NO_PROXY", no_proxy),
+    ] {
+        if let Some(value) = value {
+            env_vars.push((name.to_string(), value.clone()));
+            env_vars.push((name.to_lowercase(), value.clone()));
+        }
+    }
+    return Ok(env_vars);
+}
+
+fn shared_client() -> Result<Client, Error> {
+    if let Some(client) = SHARED_CLIENT.get() {
+        return Ok(client.clone());
+    }
+    let client = build_client(None, false)?;
+    // clients share their connection pool when cloned
+    let _ = SHARED_CLIENT.set(client.clone());
+    return Ok(client);
+}
+
+/// Builds a client with the proxy and CA settings, plus a client certificate
+/// and the verification of TLS certificates disabled, if asked to
+fn build_client(identity: Option<Identity>, insecure: bool) -> Result<Client, Error> {
+    let settings = config::load_global_config()?.http.unwrap_or_default();
+    // the proxy is picked by macondo rather than reqwest, so that the config
+    // and the environment follow the same rules
+    let mut builder = Client::builder().no_proxy();
+    let proxies = proxy_settings()?;
+    if proxies.http.is_some() || proxies.https.is_some() {
+        let http = parse_proxy(&proxies.http)?;
+        let https = parse_proxy(&proxies.https)?;
+        let no_proxy = proxies.no_proxy;
+        builder = builder.proxy(Proxy::custom(move |url| {
+            if url
+                .host_str()
+                .map_or(false, |host| bypasses_proxy(host, &no_proxy))
+            {
+                return None;
+            }
+            return if url.scheme() == "https" {
+                https.clone()
+            } else {
+                http.clone()
+            };
+        }));
+    }
+    if let Some(ca_file) = ca_file(&settings) {
+        for certificate in load_certificates(&ca_file)? {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    if let Some(identity) = identity {
+        builder = builder.identity(identity);
+    }
+    if insecure {
+        builder = builder
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true);
+    }
+    return Ok(builder
+        .build()
+        .context("Failed to create the HTTP client")?);
+}
+
+/// Proxies without a scheme (e.g. proxy.example.com:3128) are HTTP proxies.
+/// The proxy is not part of errors, as it may embed credentials.
+fn parse_proxy(proxy: &Option<String>) -> Result<Option<Url>, Error> {
+    let proxy = match proxy {
+        Some(proxy) if proxy.contains("://") => proxy.clone(),
+        Some(proxy) => format!("http://{}", proxy),
+        None => return Ok(None),
+    };
+    match Url::parse(&proxy) {
+        Ok(proxy) => return Ok(Some(proxy)),
+        Err(e) => bail!("Invalid proxy in the config or environment: {}", e),
+    }
+}
+
+/// Whether a host is listed in NO_PROXY, either as is, as a subdomain of
+/// one of its domains or as an IP address of one of its CIDR ranges (e.g.
+/// 10.0.0.0/8). Ports are ignored.
+fn bypasses_proxy(host: &str, no_proxy: &[String]) -> bool {
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_lowercase();
+    let ip = host.parse::<IpAddr>().ok();
+    return no_proxy.iter().any(|entry| {
+        if entry == "*" {
+            return true;
+        }
+        if let Some((network, prefix)) = entry.split_once('/') {
+            return match (ip, network.parse::<IpAddr>(), prefix.parse::<u32>()) {
+                (Some(ip), Ok(network), Ok(prefix)) => is_in_network(ip, network, prefix),
+                _ => false,
+            };
+        }
+        let entry = match entry.rsplit_once(':') {
+            // IPv6 addresses have colons too, unless they are in brackets
+            Some((name, port))
+                if (!name.contains(':') || name.ends_with(']')) && port.parse::<u16>().is_ok() =>
+            {
+                name
+            }
+            _ => entry.as_str(),
+        };
+        let entry = entry.trim_start_matches('[').trim_end_matches(']');
+        let domain = entry.trim_start_matches('.');
+        return host == domain || host.ends_with(&format!(".{}", domain));
+    });
+}
+
+/// Whether an IP address is within `network/prefix`
+fn is_in_network(ip: IpAddr, network: IpAddr, prefix: u32) -> bool {
+    let (ip, network, bits) = match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            (u32::from(ip) as u128, u32::from(network) as u128, 32)
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => (u128::from(ip), u128::from(network), 128),
+        _ => return false,
+    };
+    if prefix > bits {
+        return false;
+    }
+    // a /0 network holds every address, and shifting by 128 bits overflows
+    let mask = u128::MAX.checked_shl(bits - prefix).unwrap_or(0);
+    return ip & mask == network & mask;
+}
+
+fn ca_file(settings: &HttpSettings) -> Option<String> {
+    return settings
+        .ca_file
+        .clone()
+        .or_else(|| env::var(CA_FILE_ENV).ok().filter(|file| !file.is_empty()))
+        .map(|file| expand_path(&file));
+}
+
+/// Loads every certificate of a PEM bundle
+fn load_certificates(ca_file: &str) -> Result<Vec<Certificate>, Error> {
+    let bundle =
+        fs::read_to_string(ca_file).context(format!("Failed to read CA file {}", ca_file))?;
+    let mut certificates = Vec::new();
+    const END: &str = "-----END CERTIFICATE-----";
+    for block in bundle
+        .split_inclusive(END)
+        .filter(|block| block.contains(END))
+    {
+        let certificate = Certificate::from_pem(block.trim().as_bytes())
+            .context(format!("Invalid certificate in CA file {}", ca_file))?;
+        certificates.push(certificate);
+    }
+    if certificates.is_empty() {
+        bail!("No PEM certificate found in CA file {}", ca_file);
+    }
+    return Ok(certificates);
+}
+
+/// Rejects URLs that embed a password, as repository URLs are stored in the
+/// config and end up in messages and cache metadata
+pub fn validate_url(url: &str) -> Result<(), Error> {
+    if let Ok(parsed) = Url::parse(url) {
+        if parsed.password().is_some() {
+            bail!("Repository URLs can't contain credentials. Configure them with --username and --password-env instead");
+        }
+    }
+    return Ok(());
+}
+
+/// Reads a secret from an environment variable or a file, whichever is
+/// configured. Trailing whitespace (e.g. the newline of a file) is dropped.
+fn read_secret(
+    env_var: &Option<String>,
+    file: &Option<String>,
+    what: &str,
+) -> Result<Option<String>, Error> {
+    if let Some(env_var) = env_var {
+        return match env::var(env_var) {
+            Ok(secret) if !secret.is_empty() => Ok(Some(secret.trim_end().to_string())),
+            _ => bail!(
+                "The {} is read from the environment variable {}, which is not set",
+                what,
+                env_var
+            ),
+        };
+    }
+    if let Some(file) = file {
+        let file = expand_path(file);
+        let secret = fs::read_to_string(&file)
+            .context(format!("Failed to read the {} from {}", what, file))?;
+        return Ok(Some(secret.trim_end().to_string()));
+    }
+    return Ok(None);
+}
+
+/// Loads a PKCS#12 bundle with the client certificate and its key
+fn load_identity(client_cert: &str, auth: &RepositoryAuth) -> Result<Identity, Error> {
+    let client_cert = expand_path(client_cert);
+    let bundle = fs::read(&client_cert).context(format!(
+        "Failed to read the client certificate {}",
+        client_cert
+    ))?;
+    let password = read_secret(
+        &auth.client_cert_password_env,
+        &None,
+        "certificate password",
+    )?;
+    return Ok(
+        Identity::from_pkcs12_der(&bundle, password.as_deref().unwrap_or("")).context(format!(
+            "Failed to load the client certificate {}. It must be a PKCS#12 bundle, and its password set in client_cert_password_env",
+            client_cert
+        ))?,
+    );
+}
+
+/// Login and password of the ~/.netrc entry (or $NETRC) for the host of the
+/// URL, falling back to its default entry
+fn netrc_credentials(url: &str) -> Result<Option<(String, Option<String>)>, Error> {
+    let host = match Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(String::from))
+    {
+        Some(host) => host,
+        None => return Ok(None),
+    };
+    let file = env::var("NETRC").unwrap_or_else(|_| expand_path("~/.netrc"));
+    if !Path::new(&file).exists() {
+        return Ok(None);
+    }
+    let doc = fs::read_to_string(&file).context(format!("Failed to read {}", file))?;
+    return Ok(netrc_entry(&doc, &host));
+}
+
+/// Login and password of the netrc entry for a host, falling back to the
+/// `default` entry
+fn netrc_entry(doc: &str, host: &str) -> Option<(String, Option<String>)> {
+    // entries are sequences of tokens that start with `machine HOST` or
+    // `default`
+    let mut entries: Vec<(Option<String>, Option<String>, Option<String>)> = Vec::new();
+    let mut tokens = netrc_tokens(doc).into_iter();
+    while let Some(token) = tokens.next() {
+        match token.as_str() {
+            "machine" => entries.push((tokens.next(), None, None)),
+            "default" => entries.push((None, None, None)),
+            "login" | "password" => {
+                let value = tokens.next();
+                if let Some(entry) = entries.last_mut() {
+                    if token == "login" {
+                        entry.1 = value;
+                    } else {
+                        entry.2 = value;
+                    }
+                }
+            }
+            // its value could be mistaken for a keyword otherwise
+            "account" => {
+                tokens.next();
+            }
+            _ => (),
+        }
+    }
+    let entry = entries
+        .iter()
+        .find(|entry| entry.0.as_deref() == Some(host))
+        .or_else(|| entries.iter().find(|entry| entry.0.is_none()));
+    return entry
+        .and_then(|(_, login, password)| login.clone().map(|login| (login, password.clone())));
+}
+
+/// Tokens of a netrc file: separated by whitespace, or between double quotes
+/// (with backslash escapes) to hold spaces. Macro definitions, from `macdef`
+/// to the next blank line, are skipped.
+fn netrc_tokens(doc: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut lines = doc.lines();
+    while let Some(line) = lines.next() {
+        let mut chars = line.chars().peekable();
+        loop {
+            while chars.peek().map_or(false, |c| c.is_whitespace()) {
+                chars.next();
+            }
+            let quoted = match chars.peek() {
+                Some('"') => {
+                    chars.next();
+                    true
+                }
+                Some(_) => false,
+                None => break,
+            };
+            let mut token = String::new();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' if quoted => break,
+                    '\\' if quoted => token.extend(chars.next()),
+                    c if c.is_whitespace() && !quoted => break,
+                    c => token.push(c),
+                }
+            }
+            if token == "macdef" && !quoted {
+                // the body goes on until a blank line
+                for line in lines.by_ref() {
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                }
+                break;
+            }
+            tokens.push(token);
+        }
+    }
+    return tokens;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_env;
+
+    fn credentials(login: &str, password: &str) -> Option<(String, Option<String>)> {
+        return Some((String::from(login), Some(String::from(password))));
+    }
+
+    #[test]
+    fn reads_netrc_entries_of_the_host() {
+        let doc = "machine example.com login alice password secret
+machine other.example.com
+  login bob
+  account acme
+  password hunter2
+default login anonymous password guest
+";
+        assert_eq!(
+            netrc_entry(doc, "example.com"),
+            credentials("alice", "secret")
+        );
+        assert_eq!(
+            netrc_entry(doc, "other.example.com"),
+            credentials("bob", "hunter2")
+        );
+        assert_eq!(
+            netrc_entry(doc, "unknown.example.com"),
+            credentials("anonymous", "guest")
+        );
+        assert_eq!(
+            netrc_entry("machine example.com login alice", "other.com"),
+            None
+        );
+        assert_eq!(
+            netrc_entry("machine example.com login alice", "example.com"),
+            Some((String::from("alice"), None))
+        );
+    }
+
+    #[test]
+    fn skips_netrc_macros() {
+        let doc = "machine ftp.example.com login alice password secret
+macdef init
+machine example.com login mallory password stolen
+cd /pub
+
+machine example.com login bob password hunter2
+";
+        assert_eq!(
+            netrc_entry(doc, "example.com"),
+            credentials("bob", "hunter2")
+        );
+        assert_eq!(
+            netrc_entry(doc, "ftp.example.com"),
+            credentials("alice", "secret")
+        );
+    }
+
+    #[test]
+    fn reads_quoted_netrc_tokens() {
+        let doc = r#"machine example.com login "alice smith" password "pass \"word\" \\ with spaces"
+machine other.example.com login bob password "macdef"
+"#;
+        assert_eq!(
+            netrc_entry(doc, "example.com"),
+            credentials("alice smith", r#"pass "word" \ with spaces"#)
+        );
+        assert_eq!(
+            netrc_entry(doc, "other.example.com"),
+            credentials("bob", "macdef")
+        );
+    }
+
+    #[test]
+    fn reads_netrc_from_the_environment() {
+        let env = test_env::isolate();
+        let url = "https://example.com/commands.yaml";
+        assert_eq!(netrc_credentials(url).unwrap(), None);
+        let netrc = env.home.path().join("netrc");
+        fs::write(&netrc, "machine example.com login alice password secret\n").unwrap();
+        env::set_var("NETRC", &netrc);
+        assert_eq!(
+            netrc_credentials(url).unwrap(),
+            credentials("alice", "secret")
+        );
+    }
+
+    #[test]
+    fn bypasses_the_proxy_for_no_proxy_hosts() {
+        let no_proxy: Vec<String> = vec![
+            "localhost",
+            ".corp.example.com",
+            "example.org",
+            "registry.example.net:5000",
+            "10.0.0.0/8",
+            "fd00::/8",
+            "[::1]:8080",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        for host in &[
+            "localhost",
+            "git.corp.example.com",
+            "corp.example.com",
+            "example.org",
+            "www.example.org",
+            "registry.example.net",
+            "10.1.2.3",
+            "[fd12::1]",
+            "[::1]",
+        ] {
+            assert!(bypasses_proxy(host, &no_proxy), "{}", host);
+        }
+        for host in &[
+            "example.com",
+            "notexample.org",
+            "example.net",
+            "11.0.0.1",
+            "[fe80::1]",
+            "localhost.example.com",
+        ] {
+            assert!(!bypasses_proxy(host, &no_proxy), "{}", host);
+        }
+        assert!(bypasses_proxy("example.com", &[String::from("*")]));
+        assert!(bypasses_proxy("192.168.1.1", &[String::from("0.0.0.0/0")]));
+        assert!(!bypasses_proxy("example.com", &[String::from("0.0.0.0/0")]));
+        assert!(!bypasses_proxy("10.0.0.1", &[String::from("10.0.0.0/33")]));
+    }
+}
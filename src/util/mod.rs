@@ -1,3 +1,12 @@
 pub mod cache;
+pub mod git;
+pub mod hash;
+pub mod http;
 pub mod paths;
-pub mod progress_bar;
\ No newline at end of file
+pub mod progress_bar;
+pub mod prompt;
+pub mod signature;
+pub mod tasks;
+#[cfg(test)]
+pub mod test_env;
+pub mod time;
@@ -1,8 +1,12 @@
 use easy_error::{bail, Error, ResultExt};
 use std::env;
 use std::env::current_dir;
-use std::fs::{create_dir_all, File};
+use std::fs;
+use std::fs::create_dir_all;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
+use tempfile::Builder;
 
 pub fn expand_path(path: &str) -> String {
     if path.starts_with("PWD") {
@@ -35,32 +39,76 @@ pub fn canonalize_path(path: &str) -> String {
     };
 }
 
-/// Creates a file in the provided path.
-/// It also creates all its directories if they do not yet exist
-pub fn ensure_file(filename: &str) -> Result<(), Error> {
-    let path = Path::new(&filename);
-    if path.is_dir() {
-        panic!("Expected {} to be a file, not a directory", path.display());
+/// Writes the provided contents to a file atomically: they are written to a
+/// temporary file in the same directory which then replaces the target file.
+/// Readers thus see either the old or the new contents, never a partial write.
+///
+/// The file keeps the permissions of the file it replaces. New files get the
+/// usual ones, i.e. 0666 minus the umask.
+pub fn write_atomically(filename: &str, contents: &[u8]) -> Result<(), Error> {
+    let path = Path::new(filename);
+    let parent = match path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+        Some(parent) => parent,
+        None => bail!("Could not figure out parent dir of {}", filename),
+    };
+    if !parent.exists() {
+        create_dir_all(parent).context(format!(
+            "Failed to create directory {} for {}",
+            parent.display(),
+            filename
+        ))?;
     }
 
-    let parent = path.parent();
-    if let Some(parent) = parent {
-        // if it does not exist, try to create it
-        if !parent.exists() {
-            create_dir_all(parent).context(format!(
-                "Failed to create directory {} for {}",
-                parent.display(),
-                filename
-            ))?;
-        }
-    } else {
-        bail!("Could not figure out parent dir of {}", filename);
+    // the umask applies to the mode temp files are created with
+    let mut file = Builder::new()
+        .permissions(fs::Permissions::from_mode(0o666))
+        .tempfile_in(parent)
+        .context(format!("Failed to create temp file for {}", filename))?;
+    if let Ok(metadata) = fs::metadata(path) {
+        file.as_file()
+            .set_permissions(metadata.permissions())
+            .context(format!("Failed to set the permissions of {}", filename))?;
+    }
+    file.write_all(contents)
+        .context(format!("Failed to write {}", filename))?;
+    file.persist(path)
+        .context(format!("Failed to write {}", filename))?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn mode(path: &Path) -> u32 {
+        return fs::metadata(path).unwrap().permissions().mode() & 0o777;
     }
 
-    if path.exists() {
-        return Ok(());
+    #[test]
+    fn writes_new_files_with_the_default_permissions() {
+        let dir = TempDir::new().unwrap();
+        // File::create applies the umask to 0666 too
+        let reference = dir.path().join("reference");
+        fs::File::create(&reference).unwrap();
+
+        let repo = dir.path().join("repo.yaml");
+        write_atomically(&repo.to_string_lossy(), b"commands: []").unwrap();
+        assert_eq!(fs::read(&repo).unwrap(), b"commands: []");
+        assert_eq!(mode(&repo), mode(&reference));
     }
 
-    File::create(filename).context(format!("Failed to create file {}", filename))?;
-    return Ok(());
+    #[test]
+    fn keeps_the_permissions_of_replaced_files() {
+        let dir = TempDir::new().unwrap();
+        let repo = dir.path().join("repo.yaml");
+        for permissions in &[0o644, 0o640, 0o600] {
+            fs::write(&repo, "old").unwrap();
+            fs::set_permissions(&repo, fs::Permissions::from_mode(*permissions)).unwrap();
+            write_atomically(&repo.to_string_lossy(), b"new").unwrap();
+            assert_eq!(fs::read_to_string(&repo).unwrap(), "new");
+            assert_eq!(mode(&repo), *permissions);
+        }
+    }
 }
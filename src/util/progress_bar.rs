@@ -15,3 +15,25 @@ pub fn get_progress_bar(length: u64, msg: &str) -> ProgressBar {
 
     progress_bar
 }
+
+/// Return a spinner labeled with the name of a task. Meant to be added to a
+/// `MultiProgress` when several tasks run concurrently.
+pub fn get_task_progress_bar(task_name: &str) -> ProgressBar {
+    let progressbar_style = ProgressStyle::default_spinner()
+        .tick_chars(TICK_SETTINGS.0)
+        .template(" {spinner} {prefix:<30} {msg}");
+
+    let progress_bar = ProgressBar::new_spinner();
+    progress_bar.set_style(progressbar_style);
+    progress_bar.enable_steady_tick(TICK_SETTINGS.1);
+    progress_bar.set_prefix(task_name);
+    progress_bar.set_message("waiting...");
+
+    progress_bar
+}
+
+/// Stops the spinner of a task progress bar, leaving the provided message
+pub fn finish_task_progress_bar(progress_bar: &ProgressBar, msg: &str) {
+    progress_bar.set_style(ProgressStyle::default_spinner().template("   {prefix:<30} {msg}"));
+    progress_bar.finish_with_message(msg);
+}
@@ -0,0 +1,38 @@
+use colored::*;
+use easy_error::{Error, ResultExt};
+use std::io::{self, IsTerminal, Write};
+
+/// Asks for a line of text, returning the default when it is left empty
+pub fn prompt(question: &str, default: &str) -> Result<String, Error> {
+    if default.is_empty() {
+        eprint!("{}: ", question.bold());
+    } else {
+        eprint!("{} [{}]: ", question.bold(), default);
+    }
+    io::stderr().flush().context("Failed to write to stderr")?;
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .context("Failed to read from stdin")?;
+    let answer = answer.trim();
+    return Ok(String::from(if answer.is_empty() {
+        default
+    } else {
+        answer
+    }));
+}
+
+/// Asks a yes/no question
+pub fn confirm(question: &str, default: bool) -> Result<bool, Error> {
+    let answer = prompt(question, if default { "Y/n" } else { "y/N" })?;
+    return Ok(match answer.to_lowercase().as_str() {
+        "y" | "yes" => true,
+        "n" | "no" => false,
+        _ => default,
+    });
+}
+
+/// Whether there is someone at the terminal to answer questions
+pub fn is_interactive() -> bool {
+    return io::stdin().is_terminal();
+}
@@ -0,0 +1,242 @@
+use crate::util::hash::sha256_hex;
+use easy_error::{bail, Error, ResultExt};
+use minisign_verify::{PublicKey, Signature};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use walkdir::{DirEntry, WalkDir};
+
+/// A file is signed by a minisign signature stored next to it, named after
+/// it plus this extension
+pub const SIGNATURE_EXTENSION: &str = "minisig";
+
+/// A signed directory lists the SHA-256 of each of its files in this file,
+/// in the format of `sha256sum`, which is what gets signed
+pub const DIRECTORY_SUMS: &str = "macondo.sums";
+
+pub fn signature_file(file: &str) -> String {
+    return format!("{}.{}", file, SIGNATURE_EXTENSION);
+}
+
+/// Reads a minisign public key, given either in its base64 form (RW...) or
+/// as the path of a .pub file. Returns the base64 form.
+pub fn parse_public_key(key: &str) -> Result<String, Error> {
+    let key = if Path::new(key).is_file() {
+        let doc = fs::read_to_string(key).context(format!("Failed to read {}", key))?;
+        // the key is the line that follows the untrusted comment
+        doc.lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with("untrusted comment:"))
+            .map(String::from)
+            .unwrap_or_default()
+    } else {
+        String::from(key.trim())
+    };
+    if PublicKey::from_base64(&key).is_err() {
+        bail!("{} is not a minisign public key", key);
+    }
+    return Ok(key);
+}
+
+/// Verifies that `signature`, the contents of a .minisig file, is a valid
+/// signature of `contents` made with the secret key of `key`
+pub fn verify(contents: &[u8], signature: &str, key: &str) -> Result<(), Error> {
+    let public_key = PublicKey::from_base64(key).context("Invalid minisign public key")?;
+    let signature = Signature::decode(signature).context("Invalid minisign signature")?;
+    public_key
+        .verify(contents, &signature, false)
+        .context("The signature does not match the trusted key")?;
+    return Ok(());
+}
+
+/// Verifies a file against the signature stored next to it
+pub fn verify_file(file: &str, key: &str) -> Result<(), Error> {
+    let signature_file = signature_file(file);
+    if !Path::new(&signature_file).exists() {
+        bail!("{} is not signed: {} does not exist", file, signature_file);
+    }
+    let contents = fs::read(file).context(format!("Failed to read {}", file))?;
+    let signature = fs::read_to_string(&signature_file)
+        .context(format!("Failed to read {}", signature_file))?;
+    return verify(&contents, &signature, key).context(format!("Untrusted {}", file));
+}
+
+/// Files of a directory of commands, i.e. everything but directories and
+/// what is inside .git directories, at any depth. Symbolic links are listed
+/// rather than followed. The loader and the verifier share this list, so
+/// that no command is loaded without being checked.
+pub fn directory_files(dir: &Path) -> Vec<DirEntry> {
+    return WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|entry| !(entry.file_type().is_dir() && entry.file_name() == ".git"))
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| !entry.file_type().is_dir())
+        .collect();
+}
+
+/// Verifies a directory of commands: its sums file must be signed, and
+/// every file of the directory must be listed there with its current hash.
+/// Symbolic links are rejected, as their target is not what was signed.
+pub fn verify_directory(dir: &Path, key: &str) -> Result<(), Error> {
+    let sums_file = dir.join(DIRECTORY_SUMS).to_string_lossy().to_string();
+    if !Path::new(&sums_file).exists() {
+        bail!(
+            "{} is not signed: {} does not exist",
+            dir.display(),
+            sums_file
+        );
+    }
+    verify_file(&sums_file, key)?;
+    let sums = fs::read_to_string(&sums_file).context(format!("Failed to read {}", sums_file))?;
+    let sums = parse_sums(&sums);
+
+    for file in directory_files(dir) {
+        let relative = file.path().strip_prefix(dir).unwrap_or(file.path());
+        let relative = relative.to_string_lossy().to_string();
+        if relative == DIRECTORY_SUMS || relative == signature_file(DIRECTORY_SUMS) {
+            continue;
+        }
+        if !file.file_type().is_file() {
+            bail!(
+                "Untrusted {}: symbolic links are not allowed in signed directories",
+                file.path().display()
+            );
+        }
+        let contents =
+            fs::read(file.path()).context(format!("Failed to read {}", file.path().display()))?;
+        match sums.get(relative.as_str()) {
+            Some(hash) if *hash == sha256_hex(&contents) => (),
+            Some(_) => bail!(
+                "Untrusted {}: it does not match its hash in the signed {}",
+                file.path().display(),
+                DIRECTORY_SUMS
+            ),
+            None => bail!(
+                "Untrusted {}: it is not listed in the signed {}",
+                file.path().display(),
+                DIRECTORY_SUMS
+            ),
+        }
+    }
+    return Ok(());
+}
+
+/// Parses `sha256sum` output into relative paths and hashes
+fn parse_sums(doc: &str) -> HashMap<&str, String> {
+    return doc
+        .lines()
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .map(|(hash, path)| {
+            // a leading * marks files hashed in binary mode
+            let path = path.trim_start().trim_start_matches('*');
+            (path.trim_start_matches("./"), hash.to_lowercase())
+        })
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    const KEY: &str = "RWRS+cqm5ZFMSX59YGWYb/S84/8c9hXX3DZ0NH4UXXMQIrPDHoJyVJQz";
+    const SUMS: &str =
+        "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03  hello.mcd\n";
+    const SUMS_SIGNATURE: &str = "untrusted comment: signature
+RURS+cqm5ZFMSUpkKE63olbctA6souBlbou4pSlvsKv1PIKespbDFM4IDthxMPQ3YfvfhcfjGCNDBGjnwE/waRAGtYnmQc0pPgM=
+trusted comment: timestamp:1 file:macondo.sums
+viKxuFnle2OtX2AB78Npk28ppLjjVm3bWKlrNeuSk+sz+m8Sr378vGclnjWY3BDPv+1yWHtuR1SSvk22AoTnAA==
+";
+
+    /// A directory signed with KEY that only contains hello.mcd
+    fn signed_dir() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("hello.mcd"), "hello\n").unwrap();
+        fs::write(dir.path().join(DIRECTORY_SUMS), SUMS).unwrap();
+        fs::write(
+            dir.path().join(signature_file(DIRECTORY_SUMS)),
+            SUMS_SIGNATURE,
+        )
+        .unwrap();
+        return dir;
+    }
+
+    fn listed(dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = directory_files(dir)
+            .iter()
+            .map(|file| file.path().strip_prefix(dir).unwrap().to_path_buf())
+            .collect();
+        files.sort();
+        return files;
+    }
+
+    #[test]
+    fn verifies_signed_directories() {
+        let dir = signed_dir();
+        verify_directory(dir.path(), KEY).unwrap();
+
+        fs::write(dir.path().join("hello.mcd"), "tampered\n").unwrap();
+        let err = verify_directory(dir.path(), KEY).unwrap_err().to_string();
+        assert!(err.contains("does not match its hash"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_unlisted_files() {
+        let dir = signed_dir();
+        fs::write(dir.path().join("evil.mcd"), "evil\n").unwrap();
+        let err = verify_directory(dir.path(), KEY).unwrap_err().to_string();
+        assert!(err.contains("is not listed"), "{}", err);
+    }
+
+    #[test]
+    fn skips_git_directories_at_any_depth() {
+        let dir = signed_dir();
+        for git_dir in &[".git", "sub/.git"] {
+            fs::create_dir_all(dir.path().join(git_dir)).unwrap();
+            fs::write(dir.path().join(git_dir).join("evil.mcd"), "evil\n").unwrap();
+        }
+        // neither verified nor listed for the loader
+        verify_directory(dir.path(), KEY).unwrap();
+        assert_eq!(
+            listed(dir.path()),
+            vec![
+                PathBuf::from("hello.mcd"),
+                PathBuf::from(DIRECTORY_SUMS),
+                PathBuf::from(signature_file(DIRECTORY_SUMS)),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symbolic_links() {
+        let outside = TempDir::new().unwrap();
+        fs::write(outside.path().join("evil.mcd"), "evil\n").unwrap();
+
+        let dir = signed_dir();
+        std::os::unix::fs::symlink(
+            outside.path().join("evil.mcd"),
+            dir.path().join("linked.mcd"),
+        )
+        .unwrap();
+        assert!(listed(dir.path()).contains(&PathBuf::from("linked.mcd")));
+        let err = verify_directory(dir.path(), KEY).unwrap_err().to_string();
+        assert!(err.contains("symbolic links are not allowed"), "{}", err);
+
+        // a link to a listed file is rejected too, even if its target matches
+        let dir = signed_dir();
+        fs::rename(
+            dir.path().join("hello.mcd"),
+            outside.path().join("hello.mcd"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink(
+            outside.path().join("hello.mcd"),
+            dir.path().join("hello.mcd"),
+        )
+        .unwrap();
+        let err = verify_directory(dir.path(), KEY).unwrap_err().to_string();
+        assert!(err.contains("symbolic links are not allowed"), "{}", err);
+    }
+}
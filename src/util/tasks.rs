@@ -0,0 +1,56 @@
+use crate::util::progress_bar;
+use indicatif::{MultiProgress, ProgressBar};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::thread;
+
+/// Runs `task` on every item, up to `jobs` of them concurrently. Every item
+/// (named by the first element of its tuple) gets its own progress bar, which
+/// is left with the message `summary` returns for its result once it is done.
+/// Results are returned in the same order as the items.
+pub fn run_concurrently<T, R, F, S>(
+    items: Vec<(String, T)>,
+    jobs: usize,
+    task: F,
+    summary: S,
+) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T, &ProgressBar) -> R + Sync,
+    S: Fn(&R) -> String + Sync,
+{
+    let total = items.len();
+    let multi_progress = MultiProgress::new();
+    let queue: VecDeque<(usize, T, ProgressBar)> = items
+        .into_iter()
+        .enumerate()
+        .map(|(idx, (name, item))| {
+            let progress = multi_progress.add(progress_bar::get_task_progress_bar(&name));
+            (idx, item, progress)
+        })
+        .collect();
+    let queue = Mutex::new(queue);
+    let results: Mutex<Vec<(usize, R)>> = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(total) {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap().pop_front();
+                let (idx, item, progress) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                let result = task(item, &progress);
+                progress_bar::finish_task_progress_bar(&progress, &summary(&result));
+                results.lock().unwrap().push((idx, result));
+            });
+        }
+        // draws the progress bars until all tasks are done
+        multi_progress.join().unwrap_or(());
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(idx, _)| *idx);
+    return results.into_iter().map(|(_, result)| result).collect();
+}
@@ -0,0 +1,39 @@
+//! Isolation for the tests that depend on the environment, like HOME, the
+//! cache store or offline mode, which is shared by the whole test binary
+use crate::util::cache::OFFLINE_ENV;
+use crate::util::http::CA_FILE_ENV;
+use std::env;
+use std::sync::{Mutex, MutexGuard};
+use tempfile::TempDir;
+
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Environment of a test: HOME and the cache store are fresh directories,
+/// and macondo is online and talks to servers directly. Tests holding one
+/// run one at a time.
+pub struct TestEnv {
+    pub home: TempDir,
+    _lock: MutexGuard<'static, ()>,
+}
+
+pub fn isolate() -> TestEnv {
+    // a failed test must not fail the ones that come after it
+    let lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let home = TempDir::new().unwrap();
+    env::set_var("HOME", home.path());
+    env::set_var("XDG_CACHE_HOME", home.path().join(".cache"));
+    for name in &[
+        OFFLINE_ENV,
+        CA_FILE_ENV,
+        "NETRC",
+        "HTTP_PROXY",
+        "http_proxy",
+        "HTTPS_PROXY",
+        "https_proxy",
+        "NO_PROXY",
+        "no_proxy",
+    ] {
+        env::remove_var(name);
+    }
+    return TestEnv { home, _lock: lock };
+}
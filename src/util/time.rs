@@ -0,0 +1,80 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Current unix timestamp (seconds)
+pub fn now() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+}
+
+/// Parses an age like 90s, 30m, 12h, 30d or 2w into seconds
+pub fn parse_age(age: &str) -> Result<u64, String> {
+    let error = || {
+        format!(
+            "invalid age {}. Expected a number followed by s, m, h, d or w",
+            age
+        )
+    };
+    // the unit is the last character, which may span several bytes
+    let (number, unit) = match age.char_indices().last() {
+        Some((idx, _)) if idx > 0 => age.split_at(idx),
+        _ => return Err(error()),
+    };
+    let number: u64 = number.parse().map_err(|_| error())?;
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 7 * 86400,
+        _ => return Err(error()),
+    };
+    return number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("invalid age {}. It is too long", age));
+}
+
+/// Formats the time elapsed since a unix timestamp, e.g. 5m ago or 3d ago
+pub fn format_age(timestamp: u64) -> String {
+    if timestamp == 0 {
+        return String::from("unknown");
+    }
+    let seconds = now().saturating_sub(timestamp);
+    let age = match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m", seconds / 60),
+        3600..=86399 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    };
+    return format!("{} ago", age);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ages() {
+        assert_eq!(parse_age("90s"), Ok(90));
+        assert_eq!(parse_age("30m"), Ok(1800));
+        assert_eq!(parse_age("12h"), Ok(43200));
+        assert_eq!(parse_age("1d"), Ok(86400));
+        assert_eq!(parse_age("2w"), Ok(1209600));
+        assert_eq!(parse_age("0s"), Ok(0));
+    }
+
+    #[test]
+    fn rejects_invalid_ages() {
+        for age in &["", "s", "5", "5x", "-5s", "5 s", "h5", "5µ", "µ", "5sµ"] {
+            assert!(parse_age(age).is_err(), "{}", age);
+        }
+    }
+
+    #[test]
+    fn rejects_ages_that_overflow() {
+        assert!(parse_age(&format!("{}w", u64::MAX / 60)).is_err());
+        assert!(parse_age(&format!("{}s", u64::MAX)).is_ok());
+        assert!(parse_age("99999999999999999999999s").is_err());
+    }
+}